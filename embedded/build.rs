@@ -1,9 +1,248 @@
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+/// Internal RAM the two frame buffers may take up, the rest is needed for the WIFI stack and the tasks
+const MAX_INTERNAL_FRAMEBUFFERS: u64 = 200 * 1024;
+/// PSRAM the two frame buffers may take up, the rest is needed for sprites and configs
+const MAX_PSRAM_FRAMEBUFFERS: u64 = 4 * 1024 * 1024;
+/// Highest data clock in MHz that works with the frame buffers in internal RAM and in PSRAM
+const MAX_INTERNAL_CLOCK_MHZ: i64 = 30;
+const MAX_PSRAM_CLOCK_MHZ: i64 = 3;
+
+const HUB75_PINS: [&str; 10] = [
+    "red1", "grn1", "blu1", "red2", "grn2", "blu2", "clock", "blank", "latch", "pwm",
+];
+
+/// GPIOs of the ESP32-S3 which can not be used for the panel.
+/// 22 to 25 don't exist, 26 to 32 connect the flash and 33 to 37 the octal PSRAM
+fn reserved_pin(pin: i64) -> Option<&'static str> {
+    match pin {
+        22..=25 => Some("does not exist"),
+        26..=32 => Some("is used by the SPI flash"),
+        33..=37 => Some("is used by the octal PSRAM"),
+        0..=48 => None,
+        _ => Some("does not exist"),
+    }
+}
+
 fn main() {
     linker_be_nice();
+    validate_config();
     // make sure linkall.x is the last linker script (otherwise might cause problems with flip-link)
     println!("cargo:rustc-link-arg=-Tlinkall.x");
 }
 
+/// Check the panel, network and MQTT settings in config.toml so mistakes fail the build instead of the display
+fn validate_config() {
+    let path = Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("config.toml");
+    println!("cargo:rerun-if-changed={}", path.display());
+    // A missing or broken config is reported by static_toml
+    let Ok(config) = std::fs::read_to_string(&path) else {
+        return;
+    };
+    let Ok(config) = config.parse::<toml::Table>() else {
+        return;
+    };
+    let section = |name: &str| config.get(name).and_then(|section| section.as_table());
+    if let Some(panel) = section("panel") {
+        report_errors("panel", validate_panel(panel));
+    }
+    if let Some(network) = section("network") {
+        report_errors("network", validate_network(network));
+    }
+    if let Some(mqtt) = section("mqtt") {
+        report_errors("mqtt", validate_mqtt(mqtt));
+    }
+}
+
+/// Fail the build if there are errors in the given section of config.toml
+fn report_errors(section: &str, errors: Vec<String>) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!();
+    eprintln!("💡 The [{section}] section of config.toml is invalid:");
+    for error in errors {
+        eprintln!("   {error}");
+    }
+    eprintln!();
+    std::process::exit(1);
+}
+
+fn validate_panel(panel: &toml::Table) -> Vec<String> {
+    let int = |key: &str| {
+        panel
+            .get(key)
+            .and_then(|value| value.as_integer())
+            .unwrap_or(0)
+    };
+    let mut errors = Vec::new();
+
+    let color_depth = int("color_depth");
+    let panel_width = int("panel_width");
+    let panel_height = int("panel_height");
+    let panels = int("num_panels_width") * int("num_panels_height");
+    if !(1..=8).contains(&color_depth) {
+        errors.push(format!(
+            "color_depth is {color_depth} but has to be between 1 and 8"
+        ));
+    }
+    if panel_width < 1 {
+        errors.push(format!(
+            "panel_width is {panel_width} but has to be at least 1"
+        ));
+    }
+    if !(2..=64).contains(&panel_height) || panel_height % 2 != 0 {
+        errors.push(format!(
+            "panel_height is {panel_height} but has to be an even number between 2 and 64"
+        ));
+    }
+    if panels < 1 {
+        errors.push(String::from("There has to be at least one panel"));
+    }
+
+    let in_psram = match panel.get("framebuffer").and_then(|value| value.as_str()) {
+        Some("internal") => false,
+        Some("psram") => true,
+        other => {
+            errors.push(format!(
+                "framebuffer is {other:?} but has to be \"internal\" or \"psram\""
+            ));
+            false
+        }
+    };
+    let clock_mhz = int("clock_mhz");
+    let max_clock_mhz = if in_psram {
+        MAX_PSRAM_CLOCK_MHZ
+    } else {
+        MAX_INTERNAL_CLOCK_MHZ
+    };
+    if !(1..=max_clock_mhz).contains(&clock_mhz) {
+        errors.push(format!(
+            "clock_mhz is {clock_mhz} but has to be between 1 and {max_clock_mhz} with the frame buffers in {}",
+            if in_psram { "PSRAM" } else { "internal RAM" }
+        ));
+    }
+
+    if errors.is_empty() {
+        // Each of the 2^depth - 1 frames holds half the rows, as two rows are shifted out at once.
+        // A row has one byte for each column of the chain and 4 bytes for the address latch
+        let frames = (1u64 << color_depth) - 1;
+        let row_size = (panel_width * panels) as u64 + 4;
+        let size = 2 * frames * (panel_height as u64 / 2) * row_size;
+        let max_size = if in_psram {
+            MAX_PSRAM_FRAMEBUFFERS
+        } else {
+            MAX_INTERNAL_FRAMEBUFFERS
+        };
+        if size > max_size {
+            errors.push(format!(
+                "The frame buffers need {} KB which is more than the {} KB available in {}. \
+                Lower the color_depth{}",
+                size / 1024,
+                max_size / 1024,
+                if in_psram { "PSRAM" } else { "internal RAM" },
+                if in_psram {
+                    ""
+                } else {
+                    " or set framebuffer to \"psram\""
+                },
+            ));
+        }
+    }
+
+    let pins = panel.get("pins").and_then(|pins| pins.as_table());
+    let mut used = BTreeMap::new();
+    for name in HUB75_PINS {
+        let Some(pin) = pins
+            .and_then(|pins| pins.get(name))
+            .and_then(|pin| pin.as_integer())
+        else {
+            errors.push(format!("pins.{name} is missing"));
+            continue;
+        };
+        if let Some(reason) = reserved_pin(pin) {
+            errors.push(format!("pins.{name} is GPIO{pin} which {reason}"));
+        }
+        if let Some(other) = used.insert(pin, name) {
+            errors.push(format!("pins.{name} and pins.{other} are both GPIO{pin}"));
+        }
+    }
+    errors
+}
+
+/// The static IP, gateway and DNS server are parsed when the display boots, so they have to be valid
+fn validate_network(network: &toml::Table) -> Vec<String> {
+    let string = |key: &str| {
+        network
+            .get(key)
+            .and_then(|value| value.as_str())
+            .unwrap_or("")
+    };
+    let mut errors = Vec::new();
+    let mut address = |key: &str| {
+        let value = string(key);
+        if value.is_empty() {
+            return None;
+        }
+        let parsed = value.parse::<Ipv4Addr>();
+        if parsed.is_err() {
+            errors.push(format!("{key} is \"{value}\" which is not an IPv4 address"));
+        }
+        parsed.ok()
+    };
+    let gateway = address("gateway");
+    address("dns_server");
+
+    let static_ip = string("static_ip");
+    if static_ip.is_empty() {
+        return errors;
+    }
+    let Some((ip, prefix_len)) = static_ip.split_once('/') else {
+        errors.push(format!(
+            "static_ip is \"{static_ip}\" but needs a prefix length, for example \"{static_ip}/24\""
+        ));
+        return errors;
+    };
+    let ip = ip.parse::<Ipv4Addr>();
+    if ip.is_err() {
+        errors.push(format!(
+            "static_ip is \"{static_ip}\" which does not start with an IPv4 address"
+        ));
+    }
+    let prefix_len = prefix_len.parse::<u8>().ok().filter(|len| *len <= 32);
+    if prefix_len.is_none() {
+        errors.push(format!(
+            "static_ip is \"{static_ip}\" but the prefix length has to be between 0 and 32"
+        ));
+    }
+    if let (Ok(ip), Some(prefix_len), Some(gateway)) = (ip, prefix_len, gateway) {
+        let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+        if u32::from(ip) & mask != u32::from(gateway) & mask {
+            errors.push(format!(
+                "gateway {gateway} is not in the network of static_ip {static_ip}"
+            ));
+        }
+    }
+    errors
+}
+
+/// The status interval is sent to the broker as keep alive, which is a 16 bit number of seconds
+fn validate_mqtt(mqtt: &toml::Table) -> Vec<String> {
+    let mut errors = Vec::new();
+    let interval = mqtt
+        .get("status_interval")
+        .and_then(|value| value.as_integer());
+    if let Some(interval) = interval.filter(|interval| !(1..=u16::MAX as i64).contains(interval)) {
+        errors.push(format!(
+            "status_interval is {interval} but has to be between 1 and {} seconds",
+            u16::MAX
+        ));
+    }
+    errors
+}
+
 fn linker_be_nice() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() > 1 {
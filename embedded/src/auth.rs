@@ -0,0 +1,76 @@
+use crate::CONFIG;
+use picoserve::{
+    io::Read,
+    request::RequestParts,
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::{Layer, Next},
+    ResponseSent,
+};
+
+/// Token clients have to send as `Authorization: Bearer <token>`.
+/// Authentication is disabled if it is empty.
+const API_TOKEN: &str = CONFIG.rest.api_token;
+
+/// Whether GET requests are allowed without a token
+const PUBLIC_READ_ONLY: bool = CONFIG.rest.public_read_only;
+
+/// Compare in constant time so the token can not be guessed byte by byte
+fn token_matches(token: &str) -> bool {
+    token.len() == API_TOKEN.len()
+        && token
+            .bytes()
+            .zip(API_TOKEN.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_authorized(request: &RequestParts<'_>) -> bool {
+    if API_TOKEN.is_empty() {
+        return true;
+    }
+    if request.path() == "/" || (PUBLIC_READ_ONLY && request.method() == "GET") {
+        return true;
+    }
+    let Some(value) = request.headers().get("Authorization") else {
+        return false;
+    };
+    value
+        .as_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(token_matches)
+}
+
+/// Rejects requests without a valid API token with 401 before they reach the handler
+pub struct AuthLayer;
+
+impl<State, PathParameters> Layer<State, PathParameters> for AuthLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if is_authorized(&request_parts) {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+        let connection = next.into_connection().await?;
+        (
+            StatusCode::UNAUTHORIZED,
+            ("WWW-Authenticate", "Bearer"),
+            "Missing or invalid API token",
+        )
+            .write_to(connection, response_writer)
+            .await
+    }
+}
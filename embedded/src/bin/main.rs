@@ -12,25 +12,37 @@
 use core::ptr::addr_of_mut;
 use core::sync::atomic::Ordering;
 use embassy_executor::{task, Spawner};
-use embassy_net::StackResources;
+use embassy_net::{Ipv4Cidr, StackResources, StaticConfigV4};
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
-use esp_hal::gpio::Pin;
 use esp_hal::psram::{FlashFreq, PsramConfig, SpiRamFreq, SpiTimingConfigCoreClock};
 use esp_hal::system::{CpuControl, Stack};
 use esp_hal::timer::AnyTimer;
 use esp_hal::{clock::CpuClock, timer::timg::TimerGroup};
 use esp_hal_embassy::Executor;
-use esp_hub75::Hub75Pins8;
-use headless_display::flash::{flash_init, flash_task};
-use headless_display::panel::init_led_panel;
+use headless_display::crash::{crash_task, take_crash_report};
+use headless_display::flash::{flash_init, flash_task, ota_confirm};
+use headless_display::logs::{init_logger, start_log_buffer, syslog_task};
+use headless_display::mdns::mdns_task;
+use headless_display::mqtt::mqtt_task;
+use headless_display::ota::{check_boot_state, REBOOT};
 use headless_display::panel::REFRESH_RATE;
+use headless_display::panel::{hub75_pins, init_led_panel};
+use headless_display::provisioning::{
+    captive_dns_task, dhcp_server_task, portal_task, PortalProps, AP_ADDRESS, PORTAL_TASK_POOL_SIZE,
+};
 use headless_display::rest::{web_task, AppProps, WEB_TASK_POOL_SIZE};
+use headless_display::schedule::schedule_task;
+use headless_display::sntp::sntp_task;
+use headless_display::sprite_cache::sprite_loader_task;
+use headless_display::stream::stream_task;
 use headless_display::ui::display_task;
 use headless_display::CONFIG;
 use headless_display::{
     panel::{hub75_task, FrameBufferExchange, Hub75Peripherals},
-    wifi::{connection, net_task, CurrentStateSignal, SystemState},
+    wifi::{
+        connected_network, connection, net_task, network_config, CurrentStateSignal, SystemState,
+    },
 };
 use log::info;
 use picoserve::AppBuilder;
@@ -52,7 +64,7 @@ async fn log_fps() {
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) {
-    esp_println::logger::init_logger_from_env();
+    init_logger();
 
     let psram_config = PsramConfig {
         flash_frequency: FlashFreq::FlashFreq120m,
@@ -65,7 +77,13 @@ async fn main(spawner: Spawner) {
         .with_psram(psram_config);
     let peripherals = esp_hal::init(config);
 
+    // Done before anything else, so an update which crashes during setup is still rolled back
+    let pending_confirm = check_boot_state();
+
     esp_alloc::psram_allocator!(peripherals.PSRAM, esp_hal::psram);
+    start_log_buffer();
+
+    let crash_report = take_crash_report();
 
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     let timer0: AnyTimer = timg0.timer0.into();
@@ -83,26 +101,15 @@ async fn main(spawner: Spawner) {
     let flash = &*flash;
 
     // LED Panel init
-    let pins = Hub75Pins8 {
-        red1: peripherals.GPIO42.degrade(),
-        grn1: peripherals.GPIO41.degrade(),
-        blu1: peripherals.GPIO40.degrade(),
-        red2: peripherals.GPIO38.degrade(),
-        grn2: peripherals.GPIO39.degrade(),
-        blu2: peripherals.GPIO12.degrade(),
-        clock: peripherals.GPIO2.degrade(),
-        blank: peripherals.GPIO14.degrade(),
-        latch: peripherals.GPIO47.degrade(),
-    };
-
+    let (pins, pwm_pin) = hub75_pins();
     let hub75_per: Hub75Peripherals<'_> = Hub75Peripherals {
         dma_channel: peripherals.DMA_CH0,
         lcd_cam: peripherals.LCD_CAM,
         pins,
-        pwm_pin: peripherals.GPIO45.degrade(),
+        pwm_pin,
         ledc: peripherals.LEDC,
     };
-    let (fb0, fb1, panel_freq) = init_led_panel::<false>();
+    let (fb0, fb1, panel_freq) = init_led_panel();
 
     info!("init framebuffer exchange");
     static TX: FrameBufferExchange = FrameBufferExchange::new();
@@ -130,8 +137,10 @@ async fn main(spawner: Spawner) {
         })
         .unwrap();
 
-    spawner.must_spawn(flash_task(flash, cpu_control));
-    spawner.must_spawn(display_task(&TX, &RX, fb0, &CURRENT_STATE, flash));
+    spawner.must_spawn(flash_task(flash, cpu_control, pending_confirm));
+    spawner.must_spawn(crash_task(crash_report));
+    spawner.must_spawn(sprite_loader_task(flash));
+    spawner.must_spawn(display_task(&TX, &RX, fb0, &CURRENT_STATE));
 
     let stats = esp_alloc::HEAP.stats();
     info!("After panel alloc: {stats}");
@@ -150,28 +159,58 @@ async fn main(spawner: Spawner) {
         .expect("Failed to initialize WIFI controller");
 
     let wifi_interface = interfaces.sta;
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let config = network_config();
     let seed = (rng.random() as u64) << 32 | rng.random() as u64;
 
     // Init network stack
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        make_static!(StackResources::<3>::new()),
+        // One additional socket each for DHCP, mDNS, DNS, SNTP, MQTT, DDP, E1.31 and syslog
+        make_static!(StackResources::<{ WEB_TASK_POOL_SIZE + 8 }>::new()),
+        seed,
+    );
+
+    // Network of the access point which is opened for provisioning the WIFI credentials
+    let ap_config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(AP_ADDRESS, 24),
+        gateway: Some(AP_ADDRESS),
+        dns_servers: Default::default(),
+    });
+    let (ap_stack, ap_runner) = embassy_net::new(
+        interfaces.ap,
+        ap_config,
+        make_static!(StackResources::<{ PORTAL_TASK_POOL_SIZE + 2 }>::new()),
         seed,
     );
 
     spawner.must_spawn(connection(controller, &CURRENT_STATE));
     spawner.must_spawn(net_task(runner));
+    spawner.must_spawn(net_task(ap_runner));
+    spawner.must_spawn(dhcp_server_task(ap_stack));
+    spawner.must_spawn(captive_dns_task(ap_stack));
 
     let stats = esp_alloc::HEAP.stats();
     info!("Total used heap: {stats}");
 
-    // TODO: handle system start properly. The wifi logo flashes briefly because the system is set to ready from 2 locations
-    CURRENT_STATE.signal(SystemState::WIFIConnecting);
+    let config = make_static!(picoserve::Config::new(picoserve::Timeouts {
+        start_read_request: Some(Duration::from_secs(5)),
+        persistent_start_read_request: Some(Duration::from_secs(1)),
+        read_request: Some(Duration::from_secs(1)),
+        write: Some(Duration::from_secs(1)),
+    })
+    .keep_connection_alive());
+
+    let portal = make_static!(PortalProps.build_app());
+    for id in 0..PORTAL_TASK_POOL_SIZE {
+        spawner.must_spawn(portal_task(id, ap_stack, portal, config));
+    }
+
     loop {
         if stack.is_link_up() {
-            CURRENT_STATE.signal(SystemState::WIFIWaitForIP);
+            CURRENT_STATE.signal(SystemState::WIFIWaitForIP(
+                connected_network().unwrap_or_default(),
+            ));
             break;
         }
         Timer::after(Duration::from_millis(500)).await;
@@ -186,24 +225,27 @@ async fn main(spawner: Spawner) {
         Timer::after(Duration::from_millis(500)).await;
     }
     CURRENT_STATE.signal(SystemState::Ready);
+    spawner.must_spawn(mdns_task(stack));
+    spawner.must_spawn(sntp_task(stack));
+    spawner.must_spawn(schedule_task());
+    spawner.must_spawn(mqtt_task(stack));
+    spawner.must_spawn(stream_task(stack));
+    spawner.must_spawn(syslog_task(stack));
 
     // Webserver
 
     let app = make_static!(AppProps.build_app());
 
-    let config = make_static!(picoserve::Config::new(picoserve::Timeouts {
-        start_read_request: Some(Duration::from_secs(5)),
-        persistent_start_read_request: Some(Duration::from_secs(1)),
-        read_request: Some(Duration::from_secs(1)),
-        write: Some(Duration::from_secs(1)),
-    })
-    .keep_connection_alive());
-
     for id in 0..WEB_TASK_POOL_SIZE {
         spawner.must_spawn(web_task(id, stack, app, config));
     }
 
-    loop {
-        Timer::after(Duration::from_secs(20)).await;
-    }
+    // Reaching this point means the firmware works well enough to receive further updates
+    ota_confirm().await;
+
+    REBOOT.wait().await;
+    info!("Rebooting...");
+    // Give the webserver some time to send out the response
+    Timer::after(Duration::from_secs(1)).await;
+    esp_hal::system::software_reset();
 }
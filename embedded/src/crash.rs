@@ -0,0 +1,236 @@
+use crate::flash::{self, crc32, FlashError, Namespace};
+use crate::sntp::unix_time;
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use ekv::ReadError;
+use embassy_time::{Duration, Instant, Ticker};
+use esp_hal::rtc_cntl::{reset_reason, SocResetReason};
+use esp_hal::system::Cpu;
+use esp_println::println;
+use interface::CrashReport;
+use log::{error, info, warn};
+
+const CRASHES_KEY: &str = "crashes";
+
+/// Number of crash reports kept in flash, older ones are dropped
+const MAX_REPORTS: usize = 8;
+
+const MESSAGE_SIZE: usize = 256;
+const MAX_BACKTRACE: usize = 16;
+
+/// How often the uptime is saved, so it is known after a reset by the watchdog
+const UPTIME_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Marks a valid [`CrashSlot`]. The RTC RAM holds random data after power on
+const SLOT_MAGIC: u32 = 0x4352_5348;
+
+/// State of the firmware kept in RTC RAM, which survives resets but not power loss
+#[repr(C)]
+struct CrashSlot {
+    magic: u32,
+    /// Whether the firmware panicked, otherwise only the uptime is valid
+    panicked: u32,
+    uptime_ms: u64,
+    /// Unix time of the crash, 0 if the clock was not synchronized
+    unix_time: u64,
+    message_len: u32,
+    message: [u8; MESSAGE_SIZE],
+    backtrace_len: u32,
+    backtrace: [u32; MAX_BACKTRACE],
+    /// Checksum over all fields before it
+    checksum: u32,
+}
+
+impl CrashSlot {
+    const EMPTY: Self = Self {
+        magic: 0,
+        panicked: 0,
+        uptime_ms: 0,
+        unix_time: 0,
+        message_len: 0,
+        message: [0; MESSAGE_SIZE],
+        backtrace_len: 0,
+        backtrace: [0; MAX_BACKTRACE],
+        checksum: 0,
+    };
+
+    fn compute_checksum(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                (self as *const Self).cast::<u8>(),
+                core::mem::offset_of!(Self, checksum),
+            )
+        };
+        crc32(bytes)
+    }
+
+    fn seal(&mut self) {
+        self.magic = SLOT_MAGIC;
+        self.checksum = self.compute_checksum();
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == SLOT_MAGIC && self.checksum == self.compute_checksum()
+    }
+}
+
+// SAFETY: The slot only holds integers and arrays of them, any bit pattern is valid
+unsafe impl esp_hal::Persistable for CrashSlot {}
+
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut CRASH_SLOT: CrashSlot = CrashSlot::EMPTY;
+
+/// Set when the display booted after a crash, until the hint was shown
+pub static RECOVERED: AtomicBool = AtomicBool::new(false);
+
+/// Writes a message into the fixed buffer of the slot, cutting it off when it is full
+struct MessageWriter<'a> {
+    buf: &'a mut [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let len = s.len().min(MESSAGE_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Whether the chip was reset on purpose, by power on, a software reset or the flash tool
+fn is_regular_reset(reason: SocResetReason) -> bool {
+    matches!(
+        reason,
+        SocResetReason::ChipPowerOn
+            | SocResetReason::CoreSw
+            | SocResetReason::CpuSw
+            | SocResetReason::CoreDeepSleep
+            | SocResetReason::CoreUsbUart
+            | SocResetReason::CoreUsbJtag
+    )
+}
+
+/// Collect the report of a crash before this boot from the RTC RAM and the reset reason.
+/// Has to be called once at startup, before anything panics again
+pub fn take_crash_report() -> Option<CrashReport> {
+    let slot = unsafe { &mut *addr_of_mut!(CRASH_SLOT) };
+    let reason = reset_reason(Cpu::ProCpu);
+    let valid = slot.is_valid();
+    let panicked = valid && slot.panicked != 0;
+    let crashed = panicked || reason.is_some_and(|reason| !is_regular_reset(reason));
+    let report = crashed.then(|| CrashReport {
+        message: if panicked {
+            let len = (slot.message_len as usize).min(MESSAGE_SIZE);
+            String::from_utf8_lossy(&slot.message[..len]).into_owned()
+        } else {
+            String::new()
+        },
+        backtrace: if panicked {
+            slot.backtrace[..(slot.backtrace_len as usize).min(MAX_BACKTRACE)].to_vec()
+        } else {
+            Vec::new()
+        },
+        reset_reason: match reason {
+            Some(reason) => format!("{reason:?}"),
+            None => String::from("Unknown"),
+        },
+        uptime_ms: if valid { slot.uptime_ms } else { 0 },
+        unix_time: (panicked && slot.unix_time != 0).then_some(slot.unix_time),
+    });
+    *slot = CrashSlot::EMPTY;
+    if report.is_some() {
+        RECOVERED.store(true, Ordering::Relaxed);
+    }
+    report
+}
+
+/// Save the uptime, so a reset by the watchdog can tell how long the firmware was running
+fn save_uptime() {
+    let slot = unsafe { &mut *addr_of_mut!(CRASH_SLOT) };
+    slot.uptime_ms = Instant::now().as_millis();
+    slot.seal();
+}
+
+/// Stored crash reports, the newest last
+pub async fn crash_reports() -> Result<Vec<CrashReport>, FlashError> {
+    match flash::read(Namespace::Settings, CRASHES_KEY.into()).await {
+        Ok(data) => Ok(postcard::from_bytes(&data).unwrap_or_else(|e| {
+            error!("Stored crash reports are corrupt: {e}");
+            Vec::new()
+        })),
+        Err(FlashError::ReadErr(ReadError::KeyNotFound)) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn store_crash_report(report: CrashReport) -> Result<(), FlashError> {
+    let mut reports = crash_reports().await?;
+    reports.push(report);
+    let excess = reports.len().saturating_sub(MAX_REPORTS);
+    reports.drain(..excess);
+    let data = postcard::to_allocvec(&reports).expect("Failed to serialize crash reports");
+    flash::store(Namespace::Settings, CRASHES_KEY.into(), data).await
+}
+
+/// Store the report of the crash before this boot and keep the uptime in RTC RAM up to date
+#[embassy_executor::task]
+pub async fn crash_task(report: Option<CrashReport>) {
+    if let Some(report) = report {
+        warn!(
+            "Recovered from a crash after {} ms ({}): {}",
+            report.uptime_ms, report.reset_reason, report.message
+        );
+        match store_crash_report(report).await {
+            Ok(_) => info!("Stored crash report"),
+            Err(e) => error!("Failed to store crash report: {e:?}"),
+        }
+    }
+    let mut ticker = Ticker::every(UPTIME_INTERVAL);
+    loop {
+        save_uptime();
+        ticker.next().await;
+    }
+}
+
+/// Print the panic like `esp_backtrace`, keep it in RTC RAM and reset, so the display
+/// comes back up and reports the crash
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let backtrace = esp_backtrace::Backtrace::capture();
+    println!();
+    println!("====================== PANIC ======================");
+    println!("{info}");
+    println!();
+    println!("Backtrace:");
+    println!();
+    for frame in backtrace.frames() {
+        println!("0x{:x}", frame.program_counter());
+    }
+
+    let slot = unsafe { &mut *addr_of_mut!(CRASH_SLOT) };
+    slot.panicked = 1;
+    slot.uptime_ms = Instant::now().as_millis();
+    slot.unix_time = unix_time().unwrap_or_default();
+    let mut writer = MessageWriter {
+        buf: &mut slot.message,
+        len: 0,
+    };
+    write!(writer, "{}", info.message()).ok();
+    if let Some(location) = info.location() {
+        write!(writer, " at {location}").ok();
+    }
+    slot.message_len = writer.len as u32;
+    let frames = backtrace.frames();
+    let count = frames.len().min(MAX_BACKTRACE);
+    for (address, frame) in slot.backtrace.iter_mut().zip(&frames[..count]) {
+        *address = frame.program_counter() as u32;
+    }
+    slot.backtrace_len = count as u32;
+    slot.seal();
+
+    esp_hal::system::software_reset()
+}
@@ -1,5 +1,8 @@
+use crate::ota::{self, OtaError, OtaUpdate};
 use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use ekv::flash::{self, PageID};
 use ekv::{config, Database, ReadError};
@@ -7,48 +10,689 @@ use embassy_executor::task;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+use embedded_graphics::prelude::OriginDimensions;
 use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
 use esp_backtrace as _;
 use esp_bootloader_esp_idf::partitions::{self, FlashRegion};
 use esp_hal::system::{Cpu, CpuControl};
 use esp_storage::FlashStorage;
-use log::info;
+use interface::{Resource, ResourceInfo, StorageInfo, StoredItem};
+use log::{error, info};
 use static_cell::make_static;
+use tinyqoi::Qoi;
 
 pub type FlashType =
     Database<PersistentStorage<FlashRegion<'static, FlashStorage>>, CriticalSectionRawMutex>;
 
+/// Signal the flash task answers a single operation on
+pub type Reply<T> = Arc<Signal<CriticalSectionRawMutex, FlashResult<T>>>;
+
+/// Kind of data a value holds. Every kind is stored under its own key prefix,
+/// so the same name can be used in several namespaces without clashing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Namespace {
+    Sprite,
+    Settings,
+}
+
+impl Namespace {
+    const ALL: [Namespace; 2] = [Namespace::Sprite, Namespace::Settings];
+
+    fn prefix(self) -> &'static str {
+        match self {
+            Namespace::Sprite => "sprite/",
+            Namespace::Settings => "settings/",
+        }
+    }
+
+    /// Type stored in the header of each value
+    fn type_id(self) -> u8 {
+        match self {
+            Namespace::Sprite => 1,
+            Namespace::Settings => 2,
+        }
+    }
+
+    /// Version of the format values are serialized with. Bumped when the format changes,
+    /// so old values are rejected instead of being misinterpreted
+    fn version(self) -> u8 {
+        1
+    }
+
+    fn from_type_id(type_id: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|namespace| namespace.type_id() == type_id)
+    }
+}
+
 pub enum FlashOperation {
-    Store(String, Vec<u8>),
-    Delete(String),
-    Exists(String),
-    Format,
+    Store(Namespace, String, Vec<u8>, Reply<()>),
+    /// Write one chunk of a streamed upload. The offset has to be a multiple of [`CHUNK_SIZE`]
+    StoreChunk(Namespace, String, u32, Vec<u8>, Reply<()>),
+    /// Validate a streamed upload of the given total size and make it visible
+    StoreFinish(Namespace, String, u32, Reply<()>),
+    /// Number of bytes an unfinished upload can be resumed at
+    UploadOffset(Namespace, String, Reply<u32>),
+    Delete(Namespace, String, Reply<()>),
+    Exists(Namespace, String, Reply<bool>),
+    Read(Namespace, String, Reply<Vec<u8>>),
+    List(Namespace, Reply<StorageInfo>),
+    /// Delete all values of a namespace
+    Clear(Namespace, Reply<()>),
+    OtaBegin(u32, Reply<()>),
+    OtaWrite(Vec<u8>, Reply<()>),
+    OtaFinish(Reply<()>),
+    /// Sent once the system is up and running to confirm a firmware update. This has no result.
+    OtaConfirm,
 }
 
-pub type FlashOperationChannel = Channel<CriticalSectionRawMutex, FlashOperation, 3>;
-pub static FLASH_OPERATION: FlashOperationChannel = Channel::new();
+/// Number of operations that can wait for the flash task
+const QUEUE_SIZE: usize = 3;
+
+/// How long an operation may wait for a free place in the queue
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an operation may take from being queued until its result arrives
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A firmware update without a write for this long was abandoned and may be replaced
+const OTA_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type FlashOperationChannel = Channel<CriticalSectionRawMutex, FlashOperation, QUEUE_SIZE>;
+static FLASH_OPERATION: FlashOperationChannel = Channel::new();
 
 #[derive(Debug)]
-pub enum FlashOperationResult {
+pub enum FlashError {
     WriteErr(ekv::WriteError<partitions::Error>),
     CommitErr(ekv::CommitError<partitions::Error>),
-    FormatErr(ekv::FormatError<partitions::Error>),
     ReadErr(ekv::ReadError<partitions::Error>),
+    CursorErr(ekv::CursorError<partitions::Error>),
+    OtaErr(OtaError),
+    UploadErr(UploadError),
+    ValueErr(ValueError),
     Error(ekv::Error<partitions::Error>),
-    // Ugly hack because I'm too lazy to make a proper type for this now
-    ExistsResult(bool),
+    /// The queue stayed full for [`QUEUE_TIMEOUT`]
+    Busy,
+    /// The result did not arrive within [`REQUEST_TIMEOUT`]. The operation may still be done later
+    Timeout,
+}
+
+pub type FlashResult<T> = Result<T, FlashError>;
+
+/// Queue an operation for the flash task and wait for its result
+async fn request<T>(operation: impl FnOnce(Reply<T>) -> FlashOperation) -> FlashResult<T> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let reply = Arc::new(Signal::new());
+    with_timeout(
+        QUEUE_TIMEOUT,
+        FLASH_OPERATION.send(operation(reply.clone())),
+    )
+    .await
+    .map_err(|_| FlashError::Busy)?;
+    with_deadline(deadline, reply.wait())
+        .await
+        .map_err(|_| FlashError::Timeout)?
+}
+
+/// Store a value, replacing an existing one
+pub async fn store(namespace: Namespace, key: String, value: Vec<u8>) -> FlashResult<()> {
+    request(|reply| FlashOperation::Store(namespace, key, value, reply)).await
 }
 
-pub type FlashOperationResultSignal =
-    Signal<CriticalSectionRawMutex, Result<(), FlashOperationResult>>;
-pub static FLASH_OPERATION_RESULT: FlashOperationResultSignal = Signal::new();
+pub async fn store_chunk(
+    namespace: Namespace,
+    key: String,
+    offset: u32,
+    data: Vec<u8>,
+) -> FlashResult<()> {
+    request(|reply| FlashOperation::StoreChunk(namespace, key, offset, data, reply)).await
+}
+
+pub async fn store_finish(namespace: Namespace, key: String, size: u32) -> FlashResult<()> {
+    request(|reply| FlashOperation::StoreFinish(namespace, key, size, reply)).await
+}
+
+pub async fn upload_offset(namespace: Namespace, key: String) -> FlashResult<u32> {
+    request(|reply| FlashOperation::UploadOffset(namespace, key, reply)).await
+}
+
+pub async fn delete(namespace: Namespace, key: String) -> FlashResult<()> {
+    request(|reply| FlashOperation::Delete(namespace, key, reply)).await
+}
+
+pub async fn exists(namespace: Namespace, key: String) -> FlashResult<bool> {
+    request(|reply| FlashOperation::Exists(namespace, key, reply)).await
+}
+
+/// Read a value. Fails with [`ReadError::KeyNotFound`] if it does not exist
+pub async fn read(namespace: Namespace, key: String) -> FlashResult<Vec<u8>> {
+    request(|reply| FlashOperation::Read(namespace, key, reply)).await
+}
+
+pub async fn list(namespace: Namespace) -> FlashResult<StorageInfo> {
+    request(|reply| FlashOperation::List(namespace, reply)).await
+}
+
+pub async fn clear(namespace: Namespace) -> FlashResult<()> {
+    request(|reply| FlashOperation::Clear(namespace, reply)).await
+}
+
+pub async fn ota_begin(size: u32) -> FlashResult<()> {
+    request(|reply| FlashOperation::OtaBegin(size, reply)).await
+}
+
+pub async fn ota_write(data: Vec<u8>) -> FlashResult<()> {
+    request(|reply| FlashOperation::OtaWrite(data, reply)).await
+}
+
+pub async fn ota_finish() -> FlashResult<()> {
+    request(FlashOperation::OtaFinish).await
+}
+
+/// Confirm that the running firmware works
+pub async fn ota_confirm() {
+    FLASH_OPERATION.send(FlashOperation::OtaConfirm).await;
+}
 
-/// Make a zeroed out buffer in heap
+/// Size of the records values are split into. Set with `EKV_MAX_VALUE_SIZE`
+pub const CHUNK_SIZE: usize = config::MAX_VALUE_SIZE;
+
+/// Maximum number of chunks of a single value
+const MAX_CHUNKS: usize = 64;
+
+/// Largest value that can be stored. Sprites are kept in RAM while displayed,
+/// so this is limited well below the size of the flash partition.
+pub const MAX_STORED_SIZE: usize = CHUNK_SIZE * MAX_CHUNKS;
+
+/// Starts the header record of a value, the data is kept in separate chunk records
+const HEADER_MAGIC: [u8; 4] = *b"HLW1";
+const HEADER_SIZE: usize = 14;
+
+/// Setting which marks that the sprites of older firmware were moved into their namespace
+const MIGRATED_KEY: &str = "migrated";
+
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("Value of {0} bytes exceeds the limit of {} bytes", MAX_STORED_SIZE)]
+    TooLarge(usize),
+    #[error(
+        "Offset {0} is not a multiple of the chunk size of {} bytes",
+        CHUNK_SIZE
+    )]
+    Unaligned(u32),
+    #[error("Can not resume at offset {0}, the previous chunks are missing")]
+    CannotResume(u32),
+    #[error("Upload is incomplete")]
+    Incomplete,
+    #[error("Not a valid sprite: {0}")]
+    InvalidResource(postcard::Error),
+}
+
+/// Reasons a stored value is rejected when it is read
+#[derive(Debug, thiserror::Error)]
+pub enum ValueError {
+    #[error("Value has no valid header")]
+    MissingHeader,
+    #[error("Value holds a {0:?} instead of a {1:?}")]
+    WrongType(Option<Namespace>, Namespace),
+    #[error("Value has format version {0} but version {1} is supported")]
+    UnsupportedVersion(u8, u8),
+    #[error("Checksum of the value does not match")]
+    ChecksumMismatch,
+}
+
+/// Header record of a value describing the data in its chunks
+struct ValueHeader {
+    type_id: u8,
+    version: u8,
+    size: u32,
+    checksum: u32,
+}
+
+impl ValueHeader {
+    fn new(namespace: Namespace, value: &[u8]) -> Self {
+        Self {
+            type_id: namespace.type_id(),
+            version: namespace.version(),
+            size: value.len() as u32,
+            checksum: crc32(value),
+        }
+    }
+
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(&HEADER_MAGIC);
+        header[4] = self.type_id;
+        header[5] = self.version;
+        header[6..10].copy_from_slice(&self.size.to_le_bytes());
+        header[10..].copy_from_slice(&self.checksum.to_le_bytes());
+        header
+    }
+
+    fn decode(record: &[u8]) -> Option<Self> {
+        let header: &[u8; HEADER_SIZE] = record.try_into().ok()?;
+        if header[..4] != HEADER_MAGIC {
+            return None;
+        }
+        Some(Self {
+            type_id: header[4],
+            version: header[5],
+            size: u32::from_le_bytes(header[6..10].try_into().unwrap()),
+            checksum: u32::from_le_bytes(header[10..].try_into().unwrap()),
+        })
+    }
+
+    /// Check that the header belongs to a value of the namespace in a supported version
+    fn validate(&self, namespace: Namespace) -> Result<(), ValueError> {
+        if self.type_id != namespace.type_id() {
+            return Err(ValueError::WrongType(
+                Namespace::from_type_id(self.type_id),
+                namespace,
+            ));
+        }
+        if self.version != namespace.version() {
+            return Err(ValueError::UnsupportedVersion(
+                self.version,
+                namespace.version(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// CRC-32 (IEEE) of the data
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(u32::MAX, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Make a zeroed out buffer in heap which can hold any single record
 pub fn make_buf() -> Box<[u8]> {
-    let buf = Box::new_zeroed_slice(10240);
+    let buf = Box::new_zeroed_slice(CHUNK_SIZE);
     unsafe { buf.assume_init() }
 }
 
+/// Key of the header record of a value
+fn storage_key(namespace: Namespace, key: &str) -> String {
+    format!("{}{key}", namespace.prefix())
+}
+
+/// Key of a chunk record. Chunks are sorted directly after the header record
+fn chunk_key(key: &str, index: usize) -> Vec<u8> {
+    let mut chunk_key = Vec::from(key.as_bytes());
+    chunk_key.push(0);
+    chunk_key.extend_from_slice(&(index as u16).to_be_bytes());
+    chunk_key
+}
+
+fn chunk_count(size: usize) -> usize {
+    size.div_ceil(CHUNK_SIZE)
+}
+
+type ReadResult<T> = Result<T, ReadError<partitions::Error>>;
+
+async fn read_chunks(flash: &FlashType, key: &str, size: usize) -> ReadResult<Vec<u8>> {
+    let rtx = flash.read_transaction().await;
+    let mut value = alloc::vec![0u8; size];
+    for (index, chunk) in value.chunks_mut(CHUNK_SIZE).enumerate() {
+        let len = rtx.read(&chunk_key(key, index), chunk).await?;
+        if len != chunk.len() {
+            return Err(ReadError::Corrupted);
+        }
+    }
+    Ok(value)
+}
+
+async fn read_header(flash: &FlashType, key: &str) -> FlashResult<ValueHeader> {
+    let mut buf = [0u8; HEADER_SIZE];
+    let len = flash
+        .read_transaction()
+        .await
+        .read(key.as_bytes(), &mut buf)
+        .await
+        .map_err(|e| match e {
+            ReadError::BufferTooSmall => FlashError::ValueErr(ValueError::MissingHeader),
+            e => FlashError::ReadErr(e),
+        })?;
+    ValueHeader::decode(&buf[..len]).ok_or(FlashError::ValueErr(ValueError::MissingHeader))
+}
+
+/// Read a value of the namespace and check its type, version and checksum
+pub async fn read_value(
+    flash: &FlashType,
+    namespace: Namespace,
+    key: &str,
+) -> FlashResult<Vec<u8>> {
+    let key = storage_key(namespace, key);
+    let header = read_header(flash, &key).await?;
+    header.validate(namespace).map_err(FlashError::ValueErr)?;
+    let value = read_chunks(flash, &key, header.size as usize)
+        .await
+        .map_err(FlashError::ReadErr)?;
+    if crc32(&value) != header.checksum {
+        return Err(FlashError::ValueErr(ValueError::ChecksumMismatch));
+    }
+    Ok(value)
+}
+
+/// Number of chunk records of a value, 0 if it does not exist
+async fn stored_chunks(flash: &FlashType, key: &str) -> usize {
+    read_header(flash, key)
+        .await
+        .map_or(0, |header| chunk_count(header.size as usize))
+}
+
+async fn write_value(
+    flash: &FlashType,
+    namespace: Namespace,
+    key: &str,
+    value: &[u8],
+) -> FlashResult<()> {
+    if value.len() > MAX_STORED_SIZE {
+        return Err(FlashError::UploadErr(UploadError::TooLarge(value.len())));
+    }
+    let key = storage_key(namespace, key);
+    let old_chunks = stored_chunks(flash, &key).await;
+    let chunks = chunk_count(value.len());
+    // Keys have to be written in ascending order: header first, then the chunks
+    let mut wtx = flash.write_transaction().await;
+    wtx.write(key.as_bytes(), &ValueHeader::new(namespace, value).encode())
+        .await
+        .map_err(FlashError::WriteErr)?;
+    for (index, chunk) in value.chunks(CHUNK_SIZE).enumerate() {
+        wtx.write(&chunk_key(&key, index), chunk)
+            .await
+            .map_err(FlashError::WriteErr)?;
+    }
+    for index in chunks..old_chunks {
+        wtx.delete(&chunk_key(&key, index))
+            .await
+            .map_err(FlashError::WriteErr)?;
+    }
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+/// Delete the header record with the given storage key and the chunks that follow it
+async fn delete_record(flash: &FlashType, key: &str, chunks: usize) -> FlashResult<()> {
+    let mut wtx = flash.write_transaction().await;
+    wtx.delete(key.as_bytes())
+        .await
+        .map_err(FlashError::WriteErr)?;
+    for index in 0..chunks {
+        wtx.delete(&chunk_key(key, index))
+            .await
+            .map_err(FlashError::WriteErr)?;
+    }
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+async fn delete_value(flash: &FlashType, namespace: Namespace, key: &str) -> FlashResult<()> {
+    let key = storage_key(namespace, key);
+    let chunks = stored_chunks(flash, &key).await;
+    delete_record(flash, &key, chunks).await
+}
+
+/// Delete the header record with the given storage key and every chunk found under it.
+/// Unlike [`delete_value`] this also removes the chunks of an upload that never finished
+async fn delete_upload(flash: &FlashType, key: &str) -> FlashResult<()> {
+    let keys = keys_with_prefix(flash, key.as_bytes()).await?;
+    // Other values may start with the same name, chunks follow the key with a 0 byte
+    let keys = keys.into_iter().filter(|record| {
+        record
+            .get(key.len())
+            .is_none_or(|separator| *separator == 0)
+    });
+    let mut wtx = flash.write_transaction().await;
+    for record in keys {
+        wtx.delete(&record).await.map_err(FlashError::WriteErr)?;
+    }
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+/// Number of bytes of an unfinished upload which are stored in complete chunks.
+/// An upload which was finished can not be resumed and starts over at 0
+async fn resumable_offset(flash: &FlashType, namespace: Namespace, key: &str) -> u32 {
+    let key = storage_key(namespace, key);
+    if read_header(flash, &key).await.is_ok() {
+        return 0;
+    }
+    let rtx = flash.read_transaction().await;
+    let mut buf = make_buf();
+    let mut chunks = 0;
+    while chunks < MAX_CHUNKS {
+        match rtx.read(&chunk_key(&key, chunks), &mut buf).await {
+            Ok(len) if len == CHUNK_SIZE => chunks += 1,
+            _ => break,
+        }
+    }
+    (chunks * CHUNK_SIZE) as u32
+}
+
+async fn write_chunk(
+    flash: &FlashType,
+    namespace: Namespace,
+    key: &str,
+    offset: u32,
+    data: &[u8],
+) -> FlashResult<()> {
+    let offset_bytes = offset as usize;
+    if offset_bytes % CHUNK_SIZE != 0 {
+        return Err(FlashError::UploadErr(UploadError::Unaligned(offset)));
+    }
+    if offset_bytes + data.len() > MAX_STORED_SIZE {
+        return Err(FlashError::UploadErr(UploadError::TooLarge(
+            offset_bytes + data.len(),
+        )));
+    }
+    let index = offset_bytes / CHUNK_SIZE;
+    let key = storage_key(namespace, key);
+    if index == 0 {
+        // Remove the old value so it can not be read while the new one is incomplete,
+        // together with the chunks a previous upload may have left behind
+        delete_upload(flash, &key).await?;
+    }
+    if index > 0 {
+        let mut buf = make_buf();
+        let rtx = flash.read_transaction().await;
+        if rtx
+            .read(&chunk_key(&key, index - 1), &mut buf)
+            .await
+            .is_err()
+        {
+            return Err(FlashError::UploadErr(UploadError::CannotResume(offset)));
+        }
+    }
+    let mut wtx = flash.write_transaction().await;
+    wtx.write(&chunk_key(&key, index), data)
+        .await
+        .map_err(FlashError::WriteErr)?;
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+/// Check that all chunks of a streamed upload are there and, for sprites, form a valid
+/// [`Resource`] before writing the header which makes the value visible
+async fn finish_upload(
+    flash: &FlashType,
+    namespace: Namespace,
+    key: &str,
+    size: u32,
+) -> FlashResult<()> {
+    let key = storage_key(namespace, key);
+    let Ok(value) = read_chunks(flash, &key, size as usize).await else {
+        delete_upload(flash, &key).await?;
+        return Err(FlashError::UploadErr(UploadError::Incomplete));
+    };
+    if namespace == Namespace::Sprite {
+        if let Err(e) = postcard::from_bytes::<Resource>(&value) {
+            delete_upload(flash, &key).await?;
+            return Err(FlashError::UploadErr(UploadError::InvalidResource(e)));
+        }
+    }
+    let mut wtx = flash.write_transaction().await;
+    wtx.write(
+        key.as_bytes(),
+        &ValueHeader::new(namespace, &value).encode(),
+    )
+    .await
+    .map_err(FlashError::WriteErr)?;
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+/// Try to interpret a stored value as [`Resource`] and summarize it
+fn resource_info(value: &[u8]) -> Option<ResourceInfo> {
+    let res = postcard::from_bytes::<Resource>(value).ok()?;
+    let size = res
+        .frames
+        .first()
+        .and_then(|frame| Qoi::new(frame).ok())
+        .map(|img| img.size())
+        .unwrap_or_default();
+    Some(ResourceInfo {
+        frame_count: res.frames.len() as u32,
+        frame_time_ms: res.frame_time_ms,
+        width: size.width,
+        height: size.height,
+    })
+}
+
+/// An entry of the listing whose chunks are still being collected
+struct ListedItem {
+    key: String,
+    size: u32,
+    data: Vec<u8>,
+}
+
+impl ListedItem {
+    fn finish(self, namespace: Namespace) -> StoredItem {
+        StoredItem {
+            resource: (namespace == Namespace::Sprite)
+                .then(|| resource_info(&self.data))
+                .flatten(),
+            key: self.key,
+            size: self.size,
+        }
+    }
+}
+
+/// Iterate over all entries in the database and collect information about the values
+/// of the namespace
+async fn list_items(flash: &FlashType, namespace: Namespace) -> FlashResult<StorageInfo> {
+    let rtx = flash.read_transaction().await;
+    let mut cursor = rtx.read_all().await.map_err(FlashError::Error)?;
+    let mut key_buf = [0u8; config::MAX_KEY_SIZE];
+    let mut val_buf = make_buf();
+    let mut items = Vec::new();
+    let mut current: Option<ListedItem> = None;
+    let mut used_bytes = 0;
+    let prefix = namespace.prefix().as_bytes();
+    while let Some((key_len, value_len)) = cursor
+        .next(&mut key_buf, &mut val_buf)
+        .await
+        .map_err(FlashError::CursorErr)?
+    {
+        used_bytes += (key_len + value_len) as u32;
+        let Some(key) = key_buf[..key_len].strip_prefix(prefix) else {
+            continue;
+        };
+        let record = &val_buf[..value_len];
+        match key.iter().position(|b| *b == 0) {
+            // Chunks directly follow the header of their value
+            Some(end) => {
+                if let Some(item) = current
+                    .as_mut()
+                    .filter(|item| item.key.as_bytes() == &key[..end])
+                {
+                    item.data.extend_from_slice(record);
+                }
+            }
+            None => {
+                items.extend(current.take().map(|item| item.finish(namespace)));
+                let header =
+                    ValueHeader::decode(record).filter(|header| header.validate(namespace).is_ok());
+                current = header.map(|header| ListedItem {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    size: header.size,
+                    data: Vec::with_capacity(header.size as usize),
+                });
+            }
+        }
+    }
+    items.extend(current.take().map(|item| item.finish(namespace)));
+    Ok(StorageInfo {
+        items,
+        used_bytes,
+        total_bytes: (config::MAX_PAGE_COUNT * config::PAGE_SIZE) as u32,
+    })
+}
+
+/// Collect the keys of all records starting with the prefix
+async fn keys_with_prefix(flash: &FlashType, prefix: &[u8]) -> FlashResult<Vec<Vec<u8>>> {
+    let rtx = flash.read_transaction().await;
+    let mut cursor = rtx.read_all().await.map_err(FlashError::Error)?;
+    let mut key_buf = [0u8; config::MAX_KEY_SIZE];
+    let mut keys = Vec::new();
+    // Only the keys are needed, values which don't fit are skipped by the cursor
+    let mut val_buf = make_buf();
+    while let Some((key_len, _)) = cursor
+        .next(&mut key_buf, &mut val_buf)
+        .await
+        .map_err(FlashError::CursorErr)?
+    {
+        if key_buf[..key_len].starts_with(prefix) {
+            keys.push(key_buf[..key_len].to_vec());
+        }
+    }
+    Ok(keys)
+}
+
+/// Delete all values of the namespace, leaving the other namespaces untouched
+async fn clear_namespace(flash: &FlashType, namespace: Namespace) -> FlashResult<()> {
+    let keys = keys_with_prefix(flash, namespace.prefix().as_bytes()).await?;
+    // The cursor returns the keys in ascending order as required by the transaction
+    let mut wtx = flash.write_transaction().await;
+    for key in keys {
+        wtx.delete(&key).await.map_err(FlashError::WriteErr)?;
+    }
+    wtx.commit().await.map_err(FlashError::CommitErr)
+}
+
+/// Move the sprites of older firmware into the sprite namespace. Those were stored
+/// as a single record under their plain name. Runs once, as it reads every key
+async fn migrate_legacy_sprites(flash: &FlashType) -> FlashResult<()> {
+    let marker = storage_key(Namespace::Settings, MIGRATED_KEY);
+    match read_header(flash, &marker).await {
+        Ok(_) => return Ok(()),
+        Err(FlashError::ReadErr(ReadError::KeyNotFound)) => {}
+        Err(e) => return Err(e),
+    }
+    let keys = keys_with_prefix(flash, b"").await?;
+    let legacy_keys = keys.into_iter().filter(|key| {
+        !key.contains(&0)
+            && !Namespace::ALL
+                .iter()
+                .any(|namespace| key.starts_with(namespace.prefix().as_bytes()))
+    });
+    for key in legacy_keys {
+        let key = String::from_utf8_lossy(&key).into_owned();
+        let mut buf = make_buf();
+        let len = flash
+            .read_transaction()
+            .await
+            .read(key.as_bytes(), &mut buf)
+            .await
+            .map_err(FlashError::ReadErr)?;
+        info!("Moving sprite {key} into the sprite namespace");
+        write_value(flash, Namespace::Sprite, &key, &buf[..len]).await?;
+        delete_record(flash, &key, 0).await?;
+    }
+    write_value(flash, Namespace::Settings, MIGRATED_KEY, &[]).await
+}
+
 // Workaround for alignment requirements.
 #[repr(C, align(4))]
 struct AlignedBuf<const N: usize>([u8; N]);
@@ -123,7 +767,11 @@ pub fn flash_init() -> FlashType {
 }
 
 #[task]
-pub async fn flash_task(flash: &'static FlashType, mut cpu_control: CpuControl<'static>) {
+pub async fn flash_task(
+    flash: &'static FlashType,
+    mut cpu_control: CpuControl<'static>,
+    pending_confirm: bool,
+) {
     if flash.mount().await.is_err() {
         info!("Flash mount failed. Formatting...");
         unsafe {
@@ -132,65 +780,154 @@ pub async fn flash_task(flash: &'static FlashType, mut cpu_control: CpuControl<'
         flash.format().await.unwrap();
         cpu_control.unpark_core(Cpu::AppCpu);
     }
+    let mut confirm_deadline = pending_confirm.then(|| Instant::now() + ota::CONFIRM_TIMEOUT);
+    // The update in progress together with the time of its last write
+    let mut ota_update: Option<(OtaUpdate, Instant)> = None;
+
+    unsafe {
+        cpu_control.park_core(Cpu::AppCpu);
+    }
+    if let Err(e) = migrate_legacy_sprites(flash).await {
+        error!("Failed to move stored sprites into their namespace: {e:?}");
+    }
+    cpu_control.unpark_core(Cpu::AppCpu);
+
     info!("Flash task is starting");
     loop {
-        let operation = FLASH_OPERATION.receive().await;
+        let operation = match confirm_deadline {
+            Some(deadline) => match with_deadline(deadline, FLASH_OPERATION.receive()).await {
+                Ok(operation) => operation,
+                Err(_) => {
+                    error!("Firmware update was not confirmed in time");
+                    unsafe {
+                        cpu_control.park_core(Cpu::AppCpu);
+                    }
+                    if let Err(e) = ota::rollback() {
+                        error!("Failed to roll back firmware: {e}");
+                    }
+                    esp_hal::system::software_reset();
+                }
+            },
+            None => FLASH_OPERATION.receive().await,
+        };
         match operation {
-            FlashOperation::Format => {
-                info!("Formatting flash...");
+            FlashOperation::Clear(namespace, reply) => {
+                info!("Deleting all values of {namespace:?}...");
                 unsafe {
                     cpu_control.park_core(Cpu::AppCpu);
                 }
-                FLASH_OPERATION_RESULT.signal(
-                    flash
-                        .format()
-                        .await
-                        .map_err(FlashOperationResult::FormatErr),
-                );
+                reply.signal(clear_namespace(flash, namespace).await);
                 cpu_control.unpark_core(Cpu::AppCpu);
             }
-            FlashOperation::Delete(ref key) => {
-                info!("Deleting {key}...");
+            FlashOperation::Delete(namespace, ref key, reply) => {
+                info!("Deleting {key} of {namespace:?}...");
                 unsafe {
                     cpu_control.park_core(Cpu::AppCpu);
                 }
-                let mut wtx = flash.write_transaction().await;
-                if let Err(e) = wtx.delete(key.as_bytes()).await {
-                    FLASH_OPERATION_RESULT.signal(Err(FlashOperationResult::WriteErr(e)));
-                } else {
-                    FLASH_OPERATION_RESULT
-                        .signal(wtx.commit().await.map_err(FlashOperationResult::CommitErr));
-                }
+                reply.signal(delete_value(flash, namespace, key).await);
                 cpu_control.unpark_core(Cpu::AppCpu);
             }
-            FlashOperation::Store(ref key, ref value) => {
-                info!("Saving {key} to flash...");
+            FlashOperation::Store(namespace, ref key, ref value, reply) => {
+                info!("Saving {key} of {namespace:?} to flash...");
                 unsafe {
                     cpu_control.park_core(Cpu::AppCpu);
                 }
-                let mut wtx = flash.write_transaction().await;
-                if let Err(e) = wtx.write(key.as_bytes(), value.as_slice()).await {
-                    FLASH_OPERATION_RESULT.signal(Err(FlashOperationResult::WriteErr(e)));
-                } else {
-                    FLASH_OPERATION_RESULT
-                        .signal(wtx.commit().await.map_err(FlashOperationResult::CommitErr));
+                let result = write_value(flash, namespace, key, value).await;
+                if result.is_ok() {
                     info!("Done");
                 }
+                reply.signal(result);
+                cpu_control.unpark_core(Cpu::AppCpu);
+            }
+            FlashOperation::StoreChunk(namespace, ref key, offset, ref data, reply) => {
+                info!("Saving {} bytes of {key} at offset {offset}...", data.len());
+                unsafe {
+                    cpu_control.park_core(Cpu::AppCpu);
+                }
+                reply.signal(write_chunk(flash, namespace, key, offset, data).await);
+                cpu_control.unpark_core(Cpu::AppCpu);
+            }
+            FlashOperation::StoreFinish(namespace, ref key, size, reply) => {
+                info!("Finishing upload of {key}...");
+                unsafe {
+                    cpu_control.park_core(Cpu::AppCpu);
+                }
+                reply.signal(finish_upload(flash, namespace, key, size).await);
                 cpu_control.unpark_core(Cpu::AppCpu);
             }
-            FlashOperation::Exists(ref key) => {
-                info!("Checking if {key} exists...");
-                let wtx = flash.read_transaction().await;
-                let mut val_buf = make_buf();
-                match wtx.read(key.as_bytes(), &mut val_buf).await {
-                    Ok(_) => {
-                        FLASH_OPERATION_RESULT.signal(Err(FlashOperationResult::ExistsResult(true)))
+            FlashOperation::UploadOffset(namespace, ref key, reply) => {
+                info!("Checking how much of {key} was uploaded...");
+                reply.signal(Ok(resumable_offset(flash, namespace, key).await));
+            }
+            FlashOperation::Exists(namespace, ref key, reply) => {
+                info!("Checking if {key} of {namespace:?} exists...");
+                let key = storage_key(namespace, key);
+                reply.signal(match read_header(flash, &key).await {
+                    Ok(header) => Ok(header.validate(namespace).is_ok()),
+                    Err(FlashError::ReadErr(ReadError::KeyNotFound)) => Ok(false),
+                    Err(FlashError::ValueErr(_)) => Ok(false),
+                    Err(e) => Err(e),
+                });
+            }
+            FlashOperation::Read(namespace, ref key, reply) => {
+                info!("Reading {key} of {namespace:?}...");
+                reply.signal(read_value(flash, namespace, key).await);
+            }
+            FlashOperation::List(namespace, reply) => {
+                info!("Listing {namespace:?} values...");
+                reply.signal(list_items(flash, namespace).await);
+            }
+            FlashOperation::OtaBegin(size, reply) => {
+                if ota_update
+                    .as_ref()
+                    .is_some_and(|(_, last_write)| last_write.elapsed() < OTA_IDLE_TIMEOUT)
+                {
+                    reply.signal(Err(FlashError::OtaErr(OtaError::InProgress)));
+                    continue;
+                }
+                let result = OtaUpdate::begin(size).map(|update| {
+                    ota_update = Some((update, Instant::now()));
+                });
+                reply.signal(result.map_err(FlashError::OtaErr));
+            }
+            FlashOperation::OtaWrite(ref data, reply) => {
+                unsafe {
+                    cpu_control.park_core(Cpu::AppCpu);
+                }
+                let result = match ota_update {
+                    Some((ref mut update, ref mut last_write)) => {
+                        *last_write = Instant::now();
+                        update.write(data)
+                    }
+                    None => Err(OtaError::NotStarted),
+                };
+                if result.is_err() {
+                    ota_update = None;
+                }
+                reply.signal(result.map_err(FlashError::OtaErr));
+                cpu_control.unpark_core(Cpu::AppCpu);
+            }
+            FlashOperation::OtaFinish(reply) => {
+                info!("Finishing firmware update...");
+                unsafe {
+                    cpu_control.park_core(Cpu::AppCpu);
+                }
+                let result = match ota_update.take() {
+                    Some((update, _)) => update.finish(),
+                    None => Err(OtaError::NotStarted),
+                };
+                reply.signal(result.map_err(FlashError::OtaErr));
+                cpu_control.unpark_core(Cpu::AppCpu);
+            }
+            FlashOperation::OtaConfirm => {
+                if confirm_deadline.take().is_some() {
+                    unsafe {
+                        cpu_control.park_core(Cpu::AppCpu);
+                    }
+                    if let Err(e) = ota::confirm() {
+                        error!("Failed to confirm firmware update: {e}");
                     }
-                    Err(e) => match e {
-                        ReadError::KeyNotFound => FLASH_OPERATION_RESULT
-                            .signal(Err(FlashOperationResult::ExistsResult(false))),
-                        e => FLASH_OPERATION_RESULT.signal(Err(FlashOperationResult::ReadErr(e))),
-                    },
+                    cpu_control.unpark_core(Cpu::AppCpu);
                 }
             }
         }
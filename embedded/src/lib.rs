@@ -7,10 +7,23 @@
 
 extern crate alloc;
 
+pub mod auth;
+pub mod crash;
 pub mod flash;
+pub mod logs;
+pub mod mdns;
+pub mod mqtt;
+pub mod ota;
 pub mod panel;
+pub mod provisioning;
 pub mod resources;
 pub mod rest;
+pub mod schedule;
+pub mod screenshot;
+pub mod sntp;
+pub mod sprite_cache;
+pub mod stream;
+pub mod topology;
 pub mod ui;
 pub mod wifi;
 
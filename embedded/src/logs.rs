@@ -0,0 +1,354 @@
+use crate::sntp::unix_time;
+use crate::wifi::HOSTNAME;
+use crate::CONFIG;
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use interface::{LogLevel, LogRecord};
+use log::{error, info, Level, LevelFilter, Log, Metadata, Record};
+
+/// Number of bytes of log records kept in RAM. The oldest records are dropped first
+const BUFFER_SIZE: usize = CONFIG.logs.buffer_size as usize;
+
+/// Host log records are forwarded to with syslog. Forwarding is disabled if it is empty
+const SYSLOG_HOST: &str = CONFIG.logs.syslog_host;
+const SYSLOG_PORT: u16 = CONFIG.logs.syslog_port as u16;
+const SYSLOG_LOCAL_PORT: u16 = 51400;
+/// Most verbose level forwarded to syslog
+const SYSLOG_LEVEL: LogLevel = match CONFIG.logs.syslog_level.as_bytes() {
+    b"error" => LogLevel::Error,
+    b"warn" => LogLevel::Warn,
+    b"info" => LogLevel::Info,
+    b"debug" => LogLevel::Debug,
+    b"trace" => LogLevel::Trace,
+    _ => panic!("logs.syslog_level has to be error, warn, info, debug or trace"),
+};
+/// Syslog facility local0
+const SYSLOG_FACILITY: u8 = 16;
+const APP_NAME: &str = "headless-display";
+
+/// Longest time a follow request waits for new records. It keeps one of the
+/// `max_concurrent_connections` busy meanwhile, so this is kept short
+const FOLLOW_TIMEOUT: Duration = Duration::from_secs(10);
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+struct LogBuffer {
+    records: VecDeque<LogRecord>,
+    /// Approximate number of bytes used by the records
+    size: usize,
+    next_id: u32,
+}
+
+impl LogBuffer {
+    fn push(&mut self, record: LogRecord) {
+        self.size += record_size(&record);
+        self.records.push_back(record);
+        while self.size > BUFFER_SIZE {
+            match self.records.pop_front() {
+                Some(dropped) => self.size -= record_size(&dropped),
+                None => break,
+            }
+        }
+    }
+}
+
+fn record_size(record: &LogRecord) -> usize {
+    size_of::<LogRecord>() + record.target.len() + record.message.len()
+}
+
+static BUFFER: Mutex<CriticalSectionRawMutex, RefCell<LogBuffer>> =
+    Mutex::new(RefCell::new(LogBuffer {
+        records: VecDeque::new(),
+        size: 0,
+        next_id: 1,
+    }));
+
+/// Records are only kept once the heap is set up, as they are allocated
+static BUFFERING: AtomicBool = AtomicBool::new(false);
+
+/// Signaled whenever a record was added, so the syslog task forwards it
+static NEW_RECORD: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+fn log_level(level: Level) -> LogLevel {
+    match level {
+        Level::Error => LogLevel::Error,
+        Level::Warn => LogLevel::Warn,
+        Level::Info => LogLevel::Info,
+        Level::Debug => LogLevel::Debug,
+        Level::Trace => LogLevel::Trace,
+    }
+}
+
+/// Parse a level name like "warn", case insensitive
+pub fn parse_level(name: &str) -> Option<LogLevel> {
+    // "off" would never return anything, so it is not accepted
+    name.parse::<LevelFilter>().ok()?.to_level().map(log_level)
+}
+
+/// Logger which prints to the serial port like `esp_println` and keeps the records
+/// in a ring buffer in RAM
+struct RingLogger;
+
+/// Filter set with the `ESP_LOG` environment variable at build time, like `info,esp_wifi=warn`.
+/// It holds a default level and levels for targets and the modules below them
+const LOG_FILTER: &str = match option_env!("ESP_LOG") {
+    Some(filter) => filter,
+    None => "info",
+};
+
+/// Level and target of every directive in [`LOG_FILTER`]. Directives without a target
+/// set the default level, invalid ones are returned as error
+fn log_directives(
+) -> impl Iterator<Item = Result<(Option<&'static str>, LevelFilter), &'static str>> {
+    LOG_FILTER
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .map(|directive| match directive.split_once('=') {
+            Some((target, level)) => level
+                .trim()
+                .parse()
+                .map(|level| (Some(target.trim()), level))
+                .map_err(|_| directive),
+            None => directive
+                .parse()
+                .map(|level| (None, level))
+                .map_err(|_| directive),
+        })
+}
+
+/// Level of the last directive without a target, info if there is none
+fn default_level() -> LevelFilter {
+    log_directives()
+        .flatten()
+        .filter_map(|(target, level)| target.is_none().then_some(level))
+        .last()
+        .unwrap_or(LevelFilter::Info)
+}
+
+/// Level of the most specific directive matching the target, the default level otherwise
+fn target_level(target: &str) -> LevelFilter {
+    let mut matched: Option<(&str, LevelFilter)> = None;
+    for (directive_target, level) in log_directives().flatten() {
+        let Some(directive_target) = directive_target else {
+            continue;
+        };
+        let matches = target
+            .strip_prefix(directive_target)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"));
+        if matches && matched.is_none_or(|(longest, _)| directive_target.len() >= longest.len()) {
+            matched = Some((directive_target, level));
+        }
+    }
+    matched.map_or_else(default_level, |(_, level)| level)
+}
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= target_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        const RESET: &str = "\u{1B}[0m";
+        let color = match record.level() {
+            Level::Error => "\u{1B}[31m",
+            Level::Warn => "\u{1B}[33m",
+            Level::Info => "\u{1B}[32m",
+            Level::Debug => "\u{1B}[0m",
+            Level::Trace => "\u{1B}[35m",
+        };
+        println!("{}{} - {}{}", color, record.level(), record.args(), RESET);
+        if !BUFFERING.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut message = String::new();
+        write!(message, "{}", record.args()).ok();
+        let mut record = LogRecord {
+            id: 0,
+            uptime_ms: Instant::now().as_millis(),
+            level: log_level(record.level()),
+            target: String::from(record.target()),
+            message,
+        };
+        BUFFER.lock(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            record.id = buffer.next_id;
+            buffer.next_id = buffer.next_id.wrapping_add(1);
+            buffer.push(record);
+        });
+        NEW_RECORD.signal(());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: RingLogger = RingLogger;
+
+/// Install the logger. The levels are set with the `ESP_LOG` environment variable at build time
+/// and default to info
+pub fn init_logger() {
+    // The most verbose level of any target, the others are filtered in `enabled`
+    let level = log_directives()
+        .flatten()
+        .map(|(_, level)| level)
+        .fold(default_level(), Ord::max);
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    log::set_max_level(level);
+    for directive in log_directives().filter_map(Result::err) {
+        log::warn!("Ignoring invalid ESP_LOG directive '{directive}'");
+    }
+}
+
+/// Start keeping records in RAM. Has to be called once the heap is set up,
+/// records logged before are only printed to the serial port
+pub fn start_log_buffer() {
+    BUFFERING.store(true, Ordering::Relaxed);
+}
+
+/// Records newer than the given id which are at most as verbose as the given level
+pub fn records_since(since: u32, level: LogLevel) -> Vec<LogRecord> {
+    BUFFER.lock(|buffer| {
+        buffer
+            .borrow()
+            .records
+            .iter()
+            .filter(|record| record.id > since && record.level <= level)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Like [`records_since`], but if there are no matching records and `follow` is set,
+/// wait up to [`FOLLOW_TIMEOUT`] for new ones
+pub async fn wait_for_records(since: u32, level: LogLevel, follow: bool) -> Vec<LogRecord> {
+    let deadline = Instant::now() + FOLLOW_TIMEOUT;
+    loop {
+        let records = records_since(since, level);
+        if !records.is_empty() || !follow || Instant::now() >= deadline {
+            return records;
+        }
+        Timer::after(FOLLOW_POLL_INTERVAL).await;
+    }
+}
+
+/// Convert days since the Unix epoch into year, month and day
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Shift the epoch to 0000-03-01 so leap days are at the end of the year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// RFC 3339 time the record was logged at, or the nil value if the time is not known yet
+fn syslog_timestamp(record: &LogRecord) -> String {
+    let Some(now) = unix_time() else {
+        return String::from("-");
+    };
+    let age_ms = Instant::now().as_millis().saturating_sub(record.uptime_ms);
+    let time_ms = (now * 1000).saturating_sub(age_ms);
+    let seconds = time_ms / 1000;
+    let (year, month, day) = civil_from_days(seconds / 86_400);
+    let time_of_day = seconds % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time_of_day / 3600,
+        time_of_day / 60 % 60,
+        time_of_day % 60,
+        time_ms % 1000
+    )
+}
+
+/// Format a record as RFC 5424 syslog message
+fn syslog_message(record: &LogRecord) -> String {
+    let severity = match record.level {
+        LogLevel::Error => 3,
+        LogLevel::Warn => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug | LogLevel::Trace => 7,
+    };
+    format!(
+        "<{}>1 {} {HOSTNAME} {APP_NAME} - {} - {}",
+        SYSLOG_FACILITY * 8 + severity,
+        syslog_timestamp(record),
+        record.target,
+        record.message
+    )
+}
+
+async fn syslog_address(stack: embassy_net::Stack<'static>) -> Option<IpAddress> {
+    if let Ok(address) = SYSLOG_HOST.parse::<Ipv4Addr>() {
+        return Some(IpAddress::Ipv4(address));
+    }
+    stack
+        .dns_query(SYSLOG_HOST, DnsQueryType::A)
+        .await
+        .ok()?
+        .first()
+        .copied()
+}
+
+/// Forward log records to the configured syslog host over UDP
+#[embassy_executor::task]
+pub async fn syslog_task(stack: embassy_net::Stack<'static>) {
+    if SYSLOG_HOST.is_empty() {
+        return;
+    }
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_meta = [PacketMetadata::EMPTY; 8];
+    let mut rx_buf = [0u8; 64];
+    let mut tx_buf = [0u8; 2048];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    if socket.bind(SYSLOG_LOCAL_PORT).is_err() {
+        error!("Failed to bind the syslog socket");
+        return;
+    }
+    // Failures are not logged, as that would produce another record to forward
+    let endpoint = loop {
+        stack.wait_config_up().await;
+        match syslog_address(stack).await {
+            Some(address) => break IpEndpoint::new(address, SYSLOG_PORT),
+            None => Timer::after(RETRY_INTERVAL).await,
+        }
+    };
+    info!("Forwarding logs to {SYSLOG_HOST}:{SYSLOG_PORT}");
+
+    let mut last_id = 0;
+    loop {
+        for record in records_since(last_id, SYSLOG_LEVEL) {
+            last_id = record.id;
+            socket
+                .send_to(syslog_message(&record).as_bytes(), endpoint)
+                .await
+                .ok();
+        }
+        NEW_RECORD.wait().await;
+    }
+}
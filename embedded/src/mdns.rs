@@ -0,0 +1,85 @@
+use crate::wifi::HOSTNAME;
+use core::net::{Ipv4Addr, Ipv6Addr};
+use edge_mdns::buf::VecBufAccess;
+use edge_mdns::domain::base::Ttl;
+use edge_mdns::host::{Host, Service, ServiceAnswers};
+use edge_mdns::io::{self, Mdns, IPV4_DEFAULT_SOCKET};
+use edge_mdns::HostAnswersMdnsHandler;
+use edge_nal::UdpSplit;
+use edge_nal_embassy::{Udp, UdpBuffers};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use esp_hal::peripherals::RNG;
+use esp_hal::rng::Rng;
+use log::{error, info};
+
+/// DNS-SD service type under which the display announces its REST API
+const SERVICE_TYPE: &str = "_headless-led-wall";
+
+fn fill_random(buf: &mut [u8]) {
+    // The RNG has no state, the peripheral is only needed to guarantee it was initialized
+    Rng::new(unsafe { RNG::steal() }).read(buf);
+}
+
+/// Answer mDNS queries for `<hostname>.local` and announce the REST API
+/// as `_headless-led-wall._tcp` service
+#[embassy_executor::task]
+pub async fn mdns_task(stack: embassy_net::Stack<'static>) {
+    let buffers = UdpBuffers::<1, 1500, 1500, 2>::new();
+    let udp = Udp::new(stack, &buffers);
+    let recv_buf = VecBufAccess::<NoopRawMutex, 1500>::new();
+    let send_buf = VecBufAccess::<NoopRawMutex, 1500>::new();
+    let signal = Signal::<NoopRawMutex, ()>::new();
+    loop {
+        stack.wait_config_up().await;
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+        let host = Host {
+            hostname: HOSTNAME,
+            ipv4: config.address.address(),
+            ipv6: Ipv6Addr::UNSPECIFIED,
+            ttl: Ttl::from_secs(60),
+        };
+        let service = Service {
+            name: HOSTNAME,
+            priority: 1,
+            weight: 5,
+            service: SERVICE_TYPE,
+            protocol: "_tcp",
+            port: 80,
+            service_subtypes: &[],
+            txt_kvs: &[],
+        };
+        info!(
+            "Announcing {HOSTNAME}.local at {} via mDNS",
+            config.address.address()
+        );
+        let result =
+            match io::bind(&udp, IPV4_DEFAULT_SOCKET, Some(Ipv4Addr::UNSPECIFIED), None).await {
+                Ok(mut socket) => {
+                    let (recv, send) = socket.split();
+                    let mdns = Mdns::<NoopRawMutex, _, _, _, _>::new(
+                        Some(Ipv4Addr::UNSPECIFIED),
+                        None,
+                        recv,
+                        send,
+                        &recv_buf,
+                        &send_buf,
+                        fill_random,
+                        &signal,
+                    );
+                    mdns.run(HostAnswersMdnsHandler::new(ServiceAnswers::new(
+                        &host, &service,
+                    )))
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+        if let Err(e) = result {
+            error!("mDNS responder failed: {e:?}");
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,284 @@
+use crate::panel::{BRIGHTNESS, PANEL_ON, REFRESH_RATE};
+use crate::rest::DISPLAY_CONFIG_SIGNAL;
+use crate::sprite_cache::cache_stats;
+use crate::ui::TOAST_SIGNAL;
+use crate::wifi::{HOSTNAME, RSSI};
+use crate::CONFIG;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+};
+use core::net::Ipv4Addr;
+use core::sync::atomic::Ordering;
+use embassy_futures::select::{select, Either};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::{self, TcpReader, TcpSocket, TcpWriter};
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use interface::mqtt::{Command, ConfigPayload, Topics};
+use interface::{embedded::CheckedScreenConfig, Configuration};
+use log::{error, info, warn};
+use rust_mqtt::client::client::MqttClient;
+use rust_mqtt::client::client_config::{ClientConfig, MqttVersion};
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use rust_mqtt::utils::rng_generator::CountingRng;
+use serde_json::json;
+
+/// Broker to connect to. The MQTT client is disabled if it is empty
+const BROKER: &str = CONFIG.mqtt.broker;
+const PORT: u16 = CONFIG.mqtt.port as u16;
+const USERNAME: &str = CONFIG.mqtt.username;
+const PASSWORD: &str = CONFIG.mqtt.password;
+/// Prefix of all topics of the display, the hostname is used if empty
+const TOPIC_PREFIX: &str = CONFIG.mqtt.topic_prefix;
+/// Prefix Home Assistant listens on for discovery messages. Discovery is disabled if it is empty
+const DISCOVERY_PREFIX: &str = CONFIG.mqtt.discovery_prefix;
+const STATUS_INTERVAL: Duration = Duration::from_secs(CONFIG.mqtt.status_interval as u64);
+/// The status messages are the only packets an idle display sends, so the broker is told
+/// to expect one every status interval. It drops clients silent for 1.5 times this long
+const KEEP_ALIVE_SECS: u16 = CONFIG.mqtt.status_interval as u16;
+
+/// How often the panel state is checked for changes to publish
+const STATE_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+const SEND_BUFFER_SIZE: usize = 1024;
+/// Has to fit the largest config which is sent to the display. The broker is told this
+/// maximum packet size on connect and drops larger messages instead of delivering them,
+/// so configs of more than about 8 KB have to be sent over the REST API
+const RECEIVE_BUFFER_SIZE: usize = 8192;
+const MAX_PROPERTIES: usize = 5;
+
+/// Socket handed to the MQTT client. The reader is shared with [`run_client`], so it can wait
+/// for incoming data without starting to read a packet
+struct Connection<'a> {
+    reader: &'a Mutex<NoopRawMutex, TcpReader<'a>>,
+    writer: TcpWriter<'a>,
+}
+
+impl embedded_io_async::ErrorType for Connection<'_> {
+    type Error = tcp::Error;
+}
+
+impl embedded_io_async::Read for Connection<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.reader.lock().await.read(buf).await
+    }
+}
+
+impl embedded_io_async::Write for Connection<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        embedded_io_async::Write::write(&mut self.writer, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        embedded_io_async::Write::flush(&mut self.writer).await
+    }
+}
+
+fn decode_config(payload: ConfigPayload) -> Result<Configuration, String> {
+    match payload {
+        ConfigPayload::Json(json) => serde_json::from_slice(json).map_err(|e| format!("{e}")),
+        ConfigPayload::Postcard(data) => postcard::from_bytes(data).map_err(|e| format!("{e}")),
+    }
+}
+
+fn handle_message(topics: &Topics, topic: &str, payload: &[u8]) {
+    match topics.parse(topic, payload) {
+        Ok(Command::Config(payload)) => {
+            info!("Validating config update from MQTT");
+            match decode_config(payload)
+                .and_then(|config| CheckedScreenConfig::new(config).map_err(|e| format!("{e}")))
+            {
+                Ok(config) => DISPLAY_CONFIG_SIGNAL.signal(Some(config)),
+                Err(e) => error!("Rejected config from MQTT: {e}"),
+            }
+        }
+        Ok(Command::Power(on)) => PANEL_ON.store(on, Ordering::Relaxed),
+        Ok(Command::Brightness(brightness)) => BRIGHTNESS.store(brightness, Ordering::Relaxed),
+        Ok(Command::Toast(text)) => TOAST_SIGNAL.signal(String::from(text)),
+        Err(e) => warn!("Ignoring message on {topic}: {e}"),
+    }
+}
+
+async fn broker_address(stack: embassy_net::Stack<'static>) -> Result<IpAddress, &'static str> {
+    if let Ok(address) = BROKER.parse::<Ipv4Addr>() {
+        return Ok(IpAddress::Ipv4(address));
+    }
+    stack
+        .dns_query(BROKER, DnsQueryType::A)
+        .await
+        .map_err(|_| "DNS lookup failed")?
+        .first()
+        .copied()
+        .ok_or("Broker has no address")
+}
+
+/// Connect to the broker and serve it until the connection fails
+async fn run_client(stack: embassy_net::Stack<'static>, topics: &Topics) -> Result<(), String> {
+    let address = broker_address(stack).await?;
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(90)));
+    socket
+        .connect((address, PORT))
+        .await
+        .map_err(|e| format!("Failed to connect to {BROKER}: {e:?}"))?;
+
+    let mut config = ClientConfig::<MAX_PROPERTIES, _>::new(
+        MqttVersion::MQTTv5,
+        CountingRng(Instant::now().as_ticks()),
+    );
+    config.add_max_subscribe_qos(QualityOfService::QoS0);
+    config.add_client_id(HOSTNAME);
+    if !USERNAME.is_empty() {
+        config.add_username(USERNAME);
+        config.add_password(PASSWORD);
+    }
+    config.add_will(&topics.availability, b"offline", true);
+    config.max_packet_size = RECEIVE_BUFFER_SIZE as u32;
+    config.keep_alive = KEEP_ALIVE_SECS;
+
+    let (reader, writer) = socket.split();
+    let reader = Mutex::<NoopRawMutex, _>::new(reader);
+    let connection = Connection {
+        reader: &reader,
+        writer,
+    };
+    let mut send_buffer = vec![0; SEND_BUFFER_SIZE];
+    let mut receive_buffer = vec![0; RECEIVE_BUFFER_SIZE];
+    let mut client = MqttClient::new(
+        connection,
+        &mut send_buffer,
+        SEND_BUFFER_SIZE,
+        &mut receive_buffer,
+        RECEIVE_BUFFER_SIZE,
+        config,
+    );
+    let mqtt_error = |e: ReasonCode| format!("MQTT error: {e}");
+
+    client.connect_to_broker().await.map_err(mqtt_error)?;
+    info!("Connected to MQTT broker {BROKER}");
+    let subscriptions: heapless::Vec<&str, 4> = heapless::Vec::from_slice(&[
+        topics.config.as_str(),
+        topics.power_set.as_str(),
+        topics.brightness_set.as_str(),
+        topics.toast.as_str(),
+    ])
+    .unwrap();
+    client
+        .subscribe_to_topics(&subscriptions)
+        .await
+        .map_err(mqtt_error)?;
+    if !DISCOVERY_PREFIX.is_empty() {
+        client
+            .send_message(
+                &topics.discovery,
+                serde_json::to_string(&topics.discovery_message(env!("CARGO_PKG_VERSION")))
+                    .expect("Failed to serialize discovery message")
+                    .as_bytes(),
+                QualityOfService::QoS0,
+                true,
+            )
+            .await
+            .map_err(mqtt_error)?;
+    }
+    client
+        .send_message(
+            &topics.availability,
+            b"online",
+            QualityOfService::QoS0,
+            true,
+        )
+        .await
+        .map_err(mqtt_error)?;
+
+    let mut ticker = Ticker::every(STATE_INTERVAL);
+    let mut published_state = None;
+    let mut last_status = None;
+    loop {
+        // The ticker only interrupts waiting for data. Once data arrived the packet is read
+        // completely, as a receive which is cancelled halfway leaves the rest of the packet
+        // in the stream and the client would read garbage from then on
+        let readable = async { reader.lock().await.wait_read_ready().await };
+        if let Either::First(()) = select(readable, ticker.next()).await {
+            let (topic, payload) = client.receive_message().await.map_err(mqtt_error)?;
+            handle_message(topics, topic, payload);
+        }
+
+        let state = (
+            PANEL_ON.load(Ordering::Relaxed),
+            BRIGHTNESS.load(Ordering::Relaxed),
+        );
+        if published_state != Some(state) {
+            let (on, brightness) = state;
+            let power = if on { "ON" } else { "OFF" };
+            client
+                .send_message(
+                    &topics.power_state,
+                    power.as_bytes(),
+                    QualityOfService::QoS0,
+                    true,
+                )
+                .await
+                .map_err(mqtt_error)?;
+            client
+                .send_message(
+                    &topics.brightness_state,
+                    format!("{brightness}").as_bytes(),
+                    QualityOfService::QoS0,
+                    true,
+                )
+                .await
+                .map_err(mqtt_error)?;
+            published_state = Some(state);
+        }
+
+        // Publishing the status regularly also keeps the connection alive
+        let now = Instant::now();
+        if last_status.is_none_or(|last| now.duration_since(last) >= STATUS_INTERVAL) {
+            let status = json!({
+                "fps": REFRESH_RATE.load(Ordering::Relaxed),
+                "heap_used": esp_alloc::HEAP.used(),
+                "heap_free": esp_alloc::HEAP.free(),
+                "rssi": RSSI.load(Ordering::Relaxed),
+                "uptime": now.as_secs(),
+                "sprite_cache": cache_stats(),
+            })
+            .to_string();
+            client
+                .send_message(
+                    &topics.status,
+                    status.as_bytes(),
+                    QualityOfService::QoS0,
+                    false,
+                )
+                .await
+                .map_err(mqtt_error)?;
+            last_status = Some(now);
+        }
+    }
+}
+
+/// Keep a connection to the configured MQTT broker to receive configs, power state,
+/// brightness and toast messages and to publish the state and telemetry of the display
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: embassy_net::Stack<'static>) {
+    if BROKER.is_empty() {
+        info!("No MQTT broker configured");
+        return;
+    }
+    let topics = Topics::new(TOPIC_PREFIX, DISCOVERY_PREFIX, HOSTNAME);
+    loop {
+        stack.wait_config_up().await;
+        if let Err(e) = run_client(stack, &topics).await {
+            error!("MQTT connection to {BROKER} failed: {e}");
+        }
+        Timer::after(RETRY_INTERVAL).await;
+    }
+}
@@ -0,0 +1,366 @@
+use alloc::vec::Vec;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::Duration;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_bootloader_esp_idf::ota::{Ota, OtaImageState, Slot};
+use esp_bootloader_esp_idf::partitions::{
+    self, AppPartitionSubType, DataPartitionSubType, PartitionType,
+};
+use esp_storage::{FlashStorage, FlashStorageError};
+use log::{error, info, warn};
+
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+const ESP_IMAGE_HEADER_LEN: usize = 24;
+const ESP_SEGMENT_HEADER_LEN: usize = 8;
+const ESP_CHECKSUM_SEED: u8 = 0xEF;
+const ESP_CHIP_ID_ESP32S3: u16 = 9;
+const ESP_APP_DESC_MAGIC_WORD: u32 = 0xABCD5432;
+const SECTOR_SIZE: u32 = FlashStorage::SECTOR_SIZE;
+const WORD_SIZE: usize = FlashStorage::WORD_SIZE as usize;
+
+/// Time a freshly updated firmware has to get up and running before it is rolled back
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub type RebootSignal = Signal<CriticalSectionRawMutex, ()>;
+/// Signaled once a firmware update is written and the device should reboot into it
+pub static REBOOT: RebootSignal = Signal::new();
+
+#[derive(Debug, thiserror::Error)]
+pub enum OtaError {
+    #[error("No firmware update is in progress")]
+    NotStarted,
+    #[error("Another firmware update is in progress")]
+    InProgress,
+    #[error("Could not find the {0:?} partition")]
+    MissingPartition(PartitionType),
+    #[error("Firmware image of {0} bytes does not fit into the OTA slot of {1} bytes")]
+    TooLarge(u32, u32),
+    #[error("Received more data than the announced {0} bytes")]
+    TooMuchData(u32),
+    #[error("Firmware image is incomplete. Got {0} of {1} bytes")]
+    Incomplete(u32, u32),
+    #[error("Not a valid firmware image: {0}")]
+    InvalidImage(&'static str),
+    #[error("Firmware image checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Partition error: {0}")]
+    Partition(#[from] partitions::Error),
+    #[error("Flash error: {0:?}")]
+    Flash(FlashStorageError),
+}
+
+enum ImageState {
+    Header,
+    SegmentHeader,
+    SegmentData(u32),
+    Checksum,
+    Done,
+}
+
+/// Parses an ESP app image while it is streamed and validates its header,
+/// app descriptor and checksum.
+struct ImageVerifier {
+    state: ImageState,
+    position: usize,
+    buf: [u8; ESP_IMAGE_HEADER_LEN],
+    buf_len: usize,
+    segments_left: u8,
+    segment_offset: usize,
+    magic_word: u32,
+    found_app_desc: bool,
+    checksum: u8,
+    checksum_ok: bool,
+}
+
+impl ImageVerifier {
+    fn new() -> Self {
+        Self {
+            state: ImageState::Header,
+            position: 0,
+            buf: [0; ESP_IMAGE_HEADER_LEN],
+            buf_len: 0,
+            segments_left: 0,
+            segment_offset: 0,
+            magic_word: 0,
+            found_app_desc: false,
+            checksum: ESP_CHECKSUM_SEED,
+            checksum_ok: false,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        for &byte in data {
+            match self.state {
+                ImageState::Header => {
+                    self.buf[self.buf_len] = byte;
+                    self.buf_len += 1;
+                    if self.buf_len == ESP_IMAGE_HEADER_LEN {
+                        if self.buf[0] != ESP_IMAGE_MAGIC {
+                            return Err(OtaError::InvalidImage("wrong magic byte"));
+                        }
+                        let chip_id = u16::from_le_bytes([self.buf[12], self.buf[13]]);
+                        if chip_id != ESP_CHIP_ID_ESP32S3 {
+                            return Err(OtaError::InvalidImage(
+                                "image is not built for the esp32s3",
+                            ));
+                        }
+                        self.segments_left = self.buf[1];
+                        self.buf_len = 0;
+                        self.state = ImageState::SegmentHeader;
+                    }
+                }
+                ImageState::SegmentHeader => {
+                    self.buf[self.buf_len] = byte;
+                    self.buf_len += 1;
+                    if self.buf_len == ESP_SEGMENT_HEADER_LEN {
+                        let len = u32::from_le_bytes([
+                            self.buf[4],
+                            self.buf[5],
+                            self.buf[6],
+                            self.buf[7],
+                        ]);
+                        self.buf_len = 0;
+                        self.segment_offset = 0;
+                        self.magic_word = 0;
+                        self.state = if len > 0 {
+                            ImageState::SegmentData(len)
+                        } else {
+                            self.next_segment()
+                        };
+                    }
+                }
+                ImageState::SegmentData(remaining) => {
+                    self.checksum ^= byte;
+                    // The app descriptor is placed at the very start of a segment
+                    if self.segment_offset < 4 {
+                        self.magic_word |= (byte as u32) << (8 * self.segment_offset);
+                        if self.segment_offset == 3 {
+                            self.found_app_desc |= self.magic_word == ESP_APP_DESC_MAGIC_WORD;
+                        }
+                    }
+                    self.segment_offset += 1;
+                    self.state = if remaining > 1 {
+                        ImageState::SegmentData(remaining - 1)
+                    } else {
+                        self.next_segment()
+                    };
+                }
+                ImageState::Checksum => {
+                    // The checksum is the last byte of the padding to a 16 byte boundary
+                    if (self.position + 1) % 16 == 0 {
+                        self.checksum_ok = byte == self.checksum;
+                        self.state = ImageState::Done;
+                    }
+                }
+                // Anything after the checksum is an optional hash which is not checked
+                ImageState::Done => {}
+            }
+            self.position += 1;
+        }
+        Ok(())
+    }
+
+    fn next_segment(&mut self) -> ImageState {
+        self.segments_left = self.segments_left.saturating_sub(1);
+        if self.segments_left == 0 {
+            ImageState::Checksum
+        } else {
+            ImageState::SegmentHeader
+        }
+    }
+
+    fn finish(&self) -> Result<(), OtaError> {
+        if !matches!(self.state, ImageState::Done) {
+            return Err(OtaError::InvalidImage("image ended before the checksum"));
+        }
+        if !self.found_app_desc {
+            return Err(OtaError::InvalidImage("no app descriptor found"));
+        }
+        if !self.checksum_ok {
+            return Err(OtaError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// A firmware update which is currently being written into the inactive OTA slot
+pub struct OtaUpdate {
+    storage: FlashStorage,
+    slot: Slot,
+    offset: u32,
+    size: u32,
+    written: u32,
+    erased: u32,
+    pending: Vec<u8>,
+    verifier: ImageVerifier,
+}
+
+fn slot_partition_type(slot: Slot) -> PartitionType {
+    match slot {
+        Slot::Slot1 => PartitionType::App(AppPartitionSubType::Ota1),
+        _ => PartitionType::App(AppPartitionSubType::Ota0),
+    }
+}
+
+/// Look up offset and size of the given partition
+fn find_partition(storage: &mut FlashStorage, kind: PartitionType) -> Result<(u32, u32), OtaError> {
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(storage, &mut pt_mem)?;
+    let partition = pt
+        .find_partition(kind)?
+        .ok_or(OtaError::MissingPartition(kind))?;
+    Ok((partition.offset(), partition.len()))
+}
+
+/// Run an operation on the otadata partition
+fn with_ota<R>(
+    f: impl FnOnce(&mut Ota<'_, FlashStorage>) -> Result<R, partitions::Error>,
+) -> Result<R, OtaError> {
+    let mut storage = FlashStorage::new();
+    let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
+    let pt = partitions::read_partition_table(&mut storage, &mut pt_mem)?;
+    let kind = PartitionType::Data(DataPartitionSubType::Ota);
+    let otadata = pt
+        .find_partition(kind)?
+        .ok_or(OtaError::MissingPartition(kind))?;
+    let mut region = otadata.as_embedded_storage(&mut storage);
+    let mut ota = Ota::new(&mut region)?;
+    Ok(f(&mut ota)?)
+}
+
+impl OtaUpdate {
+    /// Prepare writing a firmware image of the given size into the inactive OTA slot
+    pub fn begin(size: u32) -> Result<Self, OtaError> {
+        let slot = with_ota(|ota| ota.current_slot())?.next();
+        let mut storage = FlashStorage::new();
+        let (offset, capacity) = find_partition(&mut storage, slot_partition_type(slot))?;
+        if size > capacity {
+            return Err(OtaError::TooLarge(size, capacity));
+        }
+        info!("Writing {size} bytes of firmware into {slot:?} at {offset:#x}");
+        Ok(Self {
+            storage,
+            slot,
+            offset,
+            size,
+            written: 0,
+            erased: 0,
+            pending: Vec::with_capacity(SECTOR_SIZE as usize),
+            verifier: ImageVerifier::new(),
+        })
+    }
+
+    /// Write the next chunk of the firmware image
+    pub fn write(&mut self, data: &[u8]) -> Result<(), OtaError> {
+        if self.written + (self.pending.len() + data.len()) as u32 > self.size {
+            return Err(OtaError::TooMuchData(self.size));
+        }
+        self.verifier.update(data)?;
+        self.pending.extend_from_slice(data);
+        // Flash writes have to be word aligned, keep the rest around for the next chunk
+        let aligned = self.pending.len() - self.pending.len() % WORD_SIZE;
+        self.flush(aligned)
+    }
+
+    fn flush(&mut self, len: usize) -> Result<(), OtaError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let start = self.offset + self.written;
+        let end = start + len as u32;
+        while self.offset + self.erased < end {
+            let sector = self.offset + self.erased;
+            self.storage
+                .erase(sector, sector + SECTOR_SIZE)
+                .map_err(OtaError::Flash)?;
+            self.erased += SECTOR_SIZE;
+        }
+        self.storage
+            .write(start, &self.pending[..len])
+            .map_err(OtaError::Flash)?;
+        self.pending.drain(..len);
+        self.written += len as u32;
+        Ok(())
+    }
+
+    /// Verify the written image and mark it to be booted next
+    pub fn finish(mut self) -> Result<(), OtaError> {
+        let received = self.written + self.pending.len() as u32;
+        if received != self.size {
+            return Err(OtaError::Incomplete(received, self.size));
+        }
+        self.verifier.finish()?;
+        // pad the last word with the erased flash value
+        while self.pending.len() % WORD_SIZE != 0 {
+            self.pending.push(0xFF);
+        }
+        self.flush(self.pending.len())?;
+        let slot = self.slot;
+        with_ota(|ota| {
+            ota.set_current_slot(slot)?;
+            ota.set_current_ota_state(OtaImageState::New)
+        })?;
+        info!("Firmware update written to {slot:?}. It will be booted next");
+        Ok(())
+    }
+}
+
+/// Check if the given OTA slot contains something which looks like a firmware image
+fn slot_has_image(slot: Slot) -> Result<bool, OtaError> {
+    let mut storage = FlashStorage::new();
+    let (offset, _) = find_partition(&mut storage, slot_partition_type(slot))?;
+    let mut magic = [0u8; WORD_SIZE];
+    storage.read(offset, &mut magic).map_err(OtaError::Flash)?;
+    Ok(magic[0] == ESP_IMAGE_MAGIC)
+}
+
+/// Mark the running firmware as broken and select the previous one for the next boot
+pub fn rollback() -> Result<(), OtaError> {
+    let current = with_ota(|ota| {
+        ota.set_current_ota_state(OtaImageState::Invalid)?;
+        ota.current_slot()
+    })?;
+    let previous = current.next();
+    // If there never was a second OTA image the previous firmware is the factory app
+    let target = if slot_has_image(previous)? {
+        previous
+    } else {
+        Slot::None
+    };
+    warn!("Rolling back firmware from {current:?} to {target:?}");
+    with_ota(|ota| ota.set_current_slot(target))
+}
+
+/// Mark the running firmware as working so it is not rolled back anymore
+pub fn confirm() -> Result<(), OtaError> {
+    info!("Confirming firmware update");
+    with_ota(|ota| ota.set_current_ota_state(OtaImageState::Valid))
+}
+
+/// Check the state of the running firmware after boot. Called first thing in `main`,
+/// before the second core runs, so the flash can be accessed without parking it.
+///
+/// Returns true if this is the first boot after an update which still needs to be confirmed.
+/// If the firmware was already booted once without being confirmed it is rolled back.
+pub fn check_boot_state() -> bool {
+    match with_ota(|ota| ota.current_ota_state()) {
+        Ok(OtaImageState::New) => {
+            info!("First boot after firmware update. Waiting for confirmation");
+            if let Err(e) = with_ota(|ota| ota.set_current_ota_state(OtaImageState::PendingVerify))
+            {
+                error!("Failed to update OTA state: {e}");
+            }
+            true
+        }
+        Ok(OtaImageState::PendingVerify) => {
+            error!("Updated firmware did not confirm itself on the previous boot");
+            if let Err(e) = rollback() {
+                error!("Failed to roll back firmware: {e}");
+                return false;
+            }
+            esp_hal::system::software_reset()
+        }
+        // Either no OTA update was ever done or the state is final
+        _ => false,
+    }
+}
@@ -12,14 +12,19 @@ use esp_hal::peripherals::LCD_CAM;
 use esp_hal::time::Rate;
 use esp_hub75::framebuffer::{compute_frame_count, compute_rows, latched::DmaFrameBuffer};
 use esp_hub75::{Hub75, Hub75Pins8};
-use hub75_framebuffer::tiling::{compute_tiled_cols, ChainTopRightDown, TiledFrameBuffer};
+use hub75_framebuffer::tiling::compute_tiled_cols;
 use log::{error, info};
 use static_cell::make_static;
 
-// Constants to tune for best panel performance
+// Constants to tune for best panel performance, checked by build.rs
 const BITS: u8 = CONFIG.panel.color_depth as u8;
-const PANEL_FREQ_WITH_PSRAM: Rate = Rate::from_mhz(2); // Upper limit is about 3Mhz in the best cases when using PSRAM.
-const PANEL_FREQ_STATIC: Rate = Rate::from_mhz(20); // caps out at 30Mhz
+const PANEL_FREQ: Rate = Rate::from_mhz(CONFIG.panel.clock_mhz as u32);
+/// Whether the framebuffers are allocated in PSRAM because they don't fit into internal RAM
+const FRAMEBUFFER_IN_PSRAM: bool = match CONFIG.panel.framebuffer.as_bytes() {
+    b"internal" => false,
+    b"psram" => true,
+    _ => panic!("panel.framebuffer has to be internal or psram"),
+};
 
 const TILED_COLS: usize = CONFIG.panel.num_panels_width as usize;
 const TILED_ROWS: usize = CONFIG.panel.num_panels_height as usize;
@@ -33,21 +38,15 @@ pub static REFRESH_RATE: AtomicU32 = AtomicU32::new(0);
 pub static PANEL_ON: AtomicBool = AtomicBool::new(true);
 pub static SYSTEM_IS_UP: AtomicBool = AtomicBool::new(false);
 pub static BRIGHTNESS: AtomicU8 = AtomicU8::new(CONFIG.panel.initial_brightness as u8);
+/// Set while outdated data is shown and the display should be dimmed
+pub static DIMMED: AtomicBool = AtomicBool::new(false);
 
-type FBType = DmaFrameBuffer<ROWS, FB_COLS, NROWS, BITS, FRAME_COUNT>;
-pub type TiledFBType = TiledFrameBuffer<
-    FBType,
-    ChainTopRightDown<ROWS, PANEL_COLS, TILED_ROWS, TILED_COLS>,
-    ROWS,
-    PANEL_COLS,
-    NROWS,
-    BITS,
-    FRAME_COUNT,
-    TILED_ROWS,
-    TILED_COLS,
-    FB_COLS,
->;
-pub type FrameBufferExchange = Signal<CriticalSectionRawMutex, &'static mut TiledFBType>;
+/// Brightness is divided by this while [`DIMMED`] is set
+const DIM_DIVISOR: u8 = 4;
+
+/// All panels of the chain as one long row. Use [`crate::topology::PanelTarget`] to draw on it
+pub type FBType = DmaFrameBuffer<ROWS, FB_COLS, NROWS, BITS, FRAME_COUNT>;
+pub type FrameBufferExchange = Signal<CriticalSectionRawMutex, &'static mut FBType>;
 
 pub struct Hub75Peripherals<'d> {
     pub lcd_cam: LCD_CAM<'d>,
@@ -57,7 +56,7 @@ pub struct Hub75Peripherals<'d> {
     pub ledc: esp_hal::peripherals::LEDC<'d>,
 }
 
-fn init_fbs_heap() -> (&'static mut TiledFBType, &'static mut TiledFBType) {
+fn init_fbs_heap() -> (&'static mut FBType, &'static mut FBType) {
     // If the framebuffer is too large to fit in ram, we can allocate it on the
     // heap in PSRAM instead.
     // Allocate the framebuffer to PSRAM without ever putting it on the stack first
@@ -65,14 +64,14 @@ fn init_fbs_heap() -> (&'static mut TiledFBType, &'static mut TiledFBType) {
     use alloc::boxed::Box;
     use core::alloc::Layout;
 
-    let layout = Layout::new::<TiledFBType>();
+    let layout = Layout::new::<FBType>();
 
     let fb0 = unsafe {
-        let ptr = alloc(layout) as *mut TiledFBType;
+        let ptr = alloc(layout) as *mut FBType;
         Box::from_raw(ptr)
     };
     let fb1 = unsafe {
-        let ptr = alloc(layout) as *mut TiledFBType;
+        let ptr = alloc(layout) as *mut FBType;
         Box::from_raw(ptr)
     };
 
@@ -81,28 +80,43 @@ fn init_fbs_heap() -> (&'static mut TiledFBType, &'static mut TiledFBType) {
     (fb0, fb1)
 }
 
-fn init_fbs_stack() -> (&'static mut TiledFBType, &'static mut TiledFBType) {
+fn init_fbs_stack() -> (&'static mut FBType, &'static mut FBType) {
     // // Allocate the framebuffers in static memory. This assumes that they fit into ram.
-    let fb0 = make_static!(TiledFrameBuffer::new());
-    let fb1 = make_static!(TiledFrameBuffer::new());
+    let fb0 = make_static!(DmaFrameBuffer::new());
+    let fb1 = make_static!(DmaFrameBuffer::new());
     (fb0, fb1)
 }
 
-pub fn init_led_panel<const USE_HEAP: bool>(
-) -> (&'static mut TiledFBType, &'static mut TiledFBType, Rate) {
-    let (fb0, fb1) = if USE_HEAP {
+pub fn init_led_panel() -> (&'static mut FBType, &'static mut FBType, Rate) {
+    let (fb0, fb1) = if FRAMEBUFFER_IN_PSRAM {
         init_fbs_heap()
     } else {
         init_fbs_stack()
     };
+    (fb0, fb1, PANEL_FREQ)
+}
 
-    let panel_freq = if USE_HEAP {
-        PANEL_FREQ_WITH_PSRAM
-    } else {
-        PANEL_FREQ_STATIC
-    };
+/// GPIO with the given number
+fn config_pin(number: i64) -> AnyPin<'static> {
+    // build.rs makes sure every pin exists and is only used once
+    unsafe { AnyPin::steal(number as u8) }
+}
 
-    (fb0, fb1, panel_freq)
+/// Pins of the HUB75 connector and the PWM pin for the brightness as set in the config
+pub fn hub75_pins() -> (Hub75Pins8<'static>, AnyPin<'static>) {
+    let pins = &CONFIG.panel.pins;
+    let hub75_pins = Hub75Pins8 {
+        red1: config_pin(pins.red1),
+        grn1: config_pin(pins.grn1),
+        blu1: config_pin(pins.blu1),
+        red2: config_pin(pins.red2),
+        grn2: config_pin(pins.grn2),
+        blu2: config_pin(pins.blu2),
+        clock: config_pin(pins.clock),
+        blank: config_pin(pins.blank),
+        latch: config_pin(pins.latch),
+    };
+    (hub75_pins, config_pin(pins.pwm))
 }
 
 #[task]
@@ -110,7 +124,7 @@ pub async fn hub75_task(
     peripherals: Hub75Peripherals<'static>,
     rx: &'static FrameBufferExchange,
     tx: &'static FrameBufferExchange,
-    fb: &'static mut TiledFBType,
+    fb: &'static mut FBType,
     panel_freq: Rate,
     target_frame_rate: u32,
 ) {
@@ -165,6 +179,9 @@ pub async fn hub75_task(
     loop {
         let curr_on_state = PANEL_ON.load(Ordering::Relaxed);
         brightness = BRIGHTNESS.load(Ordering::Relaxed);
+        if DIMMED.load(Ordering::Relaxed) {
+            brightness /= DIM_DIVISOR;
+        }
         if curr_on_state != panel_is_on {
             if curr_on_state {
                 let res = channel0.start_duty_fade(prev_state, brightness, 300);
@@ -176,6 +193,10 @@ pub async fn hub75_task(
                 prev_state = 0;
             }
             panel_is_on = curr_on_state;
+        } else if panel_is_on && brightness != prev_state && !channel0.is_duty_fade_running() {
+            let res = channel0.start_duty_fade(prev_state, brightness, 300);
+            info!("Panel fade result: {res:?}");
+            prev_state = brightness;
         }
 
         // Render something to the display if:
@@ -0,0 +1,167 @@
+use crate::wifi::{add_network, validate_network};
+use crate::CONFIG;
+use alloc::{format, string::String};
+use core::net::{Ipv4Addr, SocketAddr};
+use edge_dhcp::io::server::run as run_dhcp_server;
+use edge_dhcp::server::{Server, ServerOptions};
+use edge_nal::UdpBind;
+use edge_nal_embassy::{Udp, UdpBuffers};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use interface::WifiNetwork;
+use log::{error, info};
+use picoserve::{
+    extract::Form,
+    response::{self, File, Redirect},
+    routing::{get, get_service},
+    AppBuilder, AppRouter,
+};
+
+/// Name of the access point which is opened when the display has no working credentials
+pub const AP_SSID: &str = CONFIG.wifi.access_point_ssid;
+/// Address of the display in the provisioning network
+pub const AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+
+pub const PORTAL_TASK_POOL_SIZE: usize = 2;
+
+const PORTAL_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>LED wall setup</title>
+</head>
+<body>
+<h1>LED wall setup</h1>
+<form method="post" action="/">
+<p><label>Network name (SSID)<br><input name="ssid" maxlength="32" required></label></p>
+<p><label>Password<br><input name="password" type="password" maxlength="64"></label></p>
+<p><label>Priority (higher is preferred)<br><input name="priority" type="number" min="0" max="255" value="0"></label></p>
+<p><button type="submit">Connect</button></p>
+</form>
+</body>
+</html>
+"#;
+
+pub type ProvisionedSignal = Signal<CriticalSectionRawMutex, ()>;
+
+/// Signaled by the captive portal once a new network has been stored
+pub static PROVISIONED: ProvisionedSignal = Signal::new();
+
+pub struct PortalProps;
+
+impl AppBuilder for PortalProps {
+    type PathRouter = impl picoserve::routing::PathRouter;
+
+    fn build_app(self) -> picoserve::Router<Self::PathRouter> {
+        picoserve::Router::new()
+            .route(
+                "/",
+                get_service(File::html(PORTAL_PAGE)).post(credentials_handler),
+            )
+            // Connectivity checks of the common operating systems. Redirecting them
+            // makes the device show the "sign in to network" prompt.
+            .route("/generate_204", get(|| Redirect::to("/")))
+            .route("/hotspot-detect.html", get(|| Redirect::to("/")))
+            .route("/connecttest.txt", get(|| Redirect::to("/")))
+            .route("/ncsi.txt", get(|| Redirect::to("/")))
+    }
+}
+
+async fn credentials_handler(Form(network): Form<WifiNetwork>) -> (response::StatusCode, String) {
+    if let Err(e) = validate_network(&network) {
+        return (response::StatusCode::BAD_REQUEST, String::from(e));
+    }
+    let ssid = network.ssid.clone();
+    match add_network(network).await {
+        Ok(_) => {
+            PROVISIONED.signal(());
+            (
+                response::StatusCode::OK,
+                format!("Network saved. The display is now connecting to '{ssid}'."),
+            )
+        }
+        Err(e) => (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store network: {e:?}"),
+        ),
+    }
+}
+
+#[embassy_executor::task(pool_size = PORTAL_TASK_POOL_SIZE)]
+pub async fn portal_task(
+    id: usize,
+    stack: embassy_net::Stack<'static>,
+    app: &'static AppRouter<PortalProps>,
+    config: &'static picoserve::Config<Duration>,
+) -> ! {
+    let port = 80;
+    let mut tcp_rx_buffer = [0; 1024];
+    let mut tcp_tx_buffer = [0; 1024];
+    let mut http_buffer = [0; 2048];
+
+    picoserve::listen_and_serve(
+        id,
+        app,
+        config,
+        stack,
+        port,
+        &mut tcp_rx_buffer,
+        &mut tcp_tx_buffer,
+        &mut http_buffer,
+    )
+    .await
+}
+
+/// Hands out addresses to the clients joining the provisioning access point
+#[embassy_executor::task]
+pub async fn dhcp_server_task(stack: embassy_net::Stack<'static>) {
+    let buffers = UdpBuffers::<1, 1024, 1024, 2>::new();
+    let udp = Udp::new(stack, &buffers);
+    let mut gateway = [AP_ADDRESS];
+    // Point clients to our own DNS server so every lookup ends up at the portal
+    let dns = [AP_ADDRESS];
+    let mut options = ServerOptions::new(AP_ADDRESS, Some(&mut gateway));
+    options.dns = &dns;
+    let mut server = Server::<_, 8>::new(|| Instant::now().as_secs(), AP_ADDRESS);
+    let mut buf = [0u8; 1024];
+    loop {
+        let result = match udp
+            .bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 67))
+            .await
+        {
+            Ok(mut socket) => run_dhcp_server(&mut server, &options, &mut socket, &mut buf)
+                .await
+                .map_err(|e| format!("{e:?}")),
+            Err(e) => Err(format!("{e:?}")),
+        };
+        if let Err(e) = result {
+            error!("DHCP server failed: {e}");
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Answers every DNS query with the address of the portal
+#[embassy_executor::task]
+pub async fn captive_dns_task(stack: embassy_net::Stack<'static>) {
+    let buffers = UdpBuffers::<1, 512, 512, 2>::new();
+    let udp = Udp::new(stack, &buffers);
+    let mut tx_buf = [0u8; 512];
+    let mut rx_buf = [0u8; 512];
+    info!("Starting captive portal DNS server");
+    loop {
+        if let Err(e) = edge_captive::io::run(
+            &udp,
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 53),
+            &mut tx_buf,
+            &mut rx_buf,
+            AP_ADDRESS,
+            core::time::Duration::from_secs(60),
+        )
+        .await
+        {
+            error!("Captive portal DNS server failed: {e:?}");
+        }
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
@@ -1,27 +1,37 @@
 use core::sync::atomic::Ordering;
 
 use crate::{
+    auth::AuthLayer,
+    crash::crash_reports,
+    logs::{parse_level, wait_for_records},
+    ota::REBOOT,
     panel::{BRIGHTNESS, PANEL_ON},
+    schedule::{schedule, set_schedule},
+    screenshot::{QoiImage, SCREENSHOT_LOCK, SCREENSHOT_REQUEST, SCREENSHOT_RESULT},
+    sprite_cache::{cache_stats, SPRITE_CACHE},
+    wifi::{add_network, remove_network, validate_network, wifi_status},
     CONFIG,
 };
 use alloc::{format, string::String, vec::Vec};
+use ekv::ReadError;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::Duration;
+use embassy_time::{with_timeout, Duration};
 use interface::{
     embedded::{CheckedScreenConfig, ScreenBuildError},
-    Configuration, Resource,
+    Configuration, CrashReport, LogLevel, LogRecord, Resource, Schedule, SpriteCacheStats,
+    StorageInfo, UploadProgress, WifiNetwork, WifiStatus,
 };
 use log::{error, info};
 use picoserve::{
-    extract::{FromRequest, Query},
+    extract::{FromRequest, FromRequestParts, Query},
     io::Read,
-    response::{self, ErrorWithStatusCode},
+    response::{self, Content, ErrorWithStatusCode, Json},
     routing::{get, post},
     AppBuilder, AppRouter,
 };
 use postcard::from_bytes;
 
-use crate::flash::{FlashOperation, FlashOperationResult, FLASH_OPERATION, FLASH_OPERATION_RESULT};
+use crate::flash::{self, FlashError, Namespace, UploadError, CHUNK_SIZE, MAX_STORED_SIZE};
 
 pub const WEB_TASK_POOL_SIZE: usize = CONFIG.rest.max_concurrent_connections as usize;
 
@@ -30,6 +40,11 @@ pub type DisplayConfigSignal = Signal<CriticalSectionRawMutex, Option<CheckedScr
 
 pub static DISPLAY_CONFIG_SIGNAL: DisplayConfigSignal = Signal::new();
 
+pub type HeartbeatSignal = Signal<CriticalSectionRawMutex, ()>;
+
+/// Signaled when the server confirms the current config is still up to date
+pub static HEARTBEAT_SIGNAL: HeartbeatSignal = Signal::new();
+
 pub struct AppProps;
 
 impl AppBuilder for AppProps {
@@ -45,77 +60,341 @@ impl AppBuilder for AppProps {
             )
             .route("/api/state", post(on_off_handler))
             .route("/api/config", post(config_handler))
+            .route("/api/heartbeat", post(heartbeat_handler))
             .route("/api/settings", post(settings_handler))
+            .route(
+                "/api/schedule",
+                get(schedule_handler).post(schedule_update_handler),
+            )
+            .route("/api/screenshot", get(screenshot_handler))
+            .route("/api/logs", get(logs_handler))
+            .route("/api/crashes", get(crashes_handler))
             .route("/api/storage/format", post(format_handler))
-            .route("/api/storage/upload", post(upload_handler))
+            .route(
+                "/api/storage/upload",
+                get(upload_offset_handler).post(upload_handler),
+            )
             .route("/api/storage/exists", post(exists_handler))
             .route("/api/storage/delete", post(delete_handler))
+            .route("/api/storage/list", get(list_handler))
+            .route("/api/storage/download", get(download_handler))
+            .route("/api/storage/cache", get(cache_handler))
+            .route("/api/ota", post(ota_handler))
+            .route("/api/wifi", get(wifi_handler))
+            .route("/api/wifi/add", post(wifi_add_handler))
+            .route("/api/wifi/delete", post(wifi_delete_handler))
+            .layer(AuthLayer)
     }
 }
 
-pub struct Postcard<T>(pub T);
+/// Encoding of a request or response body
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Postcard,
+    Json,
+}
+
+impl Encoding {
+    /// Postcard is used unless the header asks for JSON
+    fn from_header(request_parts: &picoserve::request::RequestParts<'_>, name: &str) -> Self {
+        let is_json = request_parts
+            .headers()
+            .get(name)
+            .and_then(|value| value.as_str().ok())
+            .is_some_and(|value| value.contains("application/json"));
+        if is_json {
+            Encoding::Json
+        } else {
+            Encoding::Postcard
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Postcard => "application/octet-stream",
+            Encoding::Json => "application/json",
+        }
+    }
+}
+
+/// Encoding the client wants the response in as given by the `Accept` header
+pub struct Accept(pub Encoding);
+
+impl<'r, State> FromRequestParts<'r, State> for Accept {
+    type Rejection = core::convert::Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &picoserve::request::RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Accept(Encoding::from_header(request_parts, "Accept")))
+    }
+}
+
+/// Response body which is already encoded in the given encoding
+pub struct Encoded(pub Encoding, pub Vec<u8>);
+
+impl Content for Encoded {
+    fn content_type(&self) -> &'static str {
+        self.0.content_type()
+    }
+
+    fn content_length(&self) -> usize {
+        self.1.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.1.as_slice().write_content(writer).await
+    }
+}
+
+/// Frames are arrays of numbers in JSON, which take up to 4 characters for each byte
+const MAX_JSON_SIZE: usize = 4 * MAX_STORED_SIZE;
+
+/// Read the whole request body, which may be larger than the HTTP buffer
+async fn read_body<R: Read>(
+    request_body: picoserve::request::RequestBody<'_, R>,
+) -> Result<Vec<u8>, R::Error> {
+    let mut reader = request_body.reader();
+    let mut data = Vec::with_capacity(reader.content_length());
+    loop {
+        let mut buf = [0u8; 1024];
+        let read_size = reader.read(&mut buf).await?;
+        if read_size == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..read_size]);
+    }
+}
+
+/// Request body which is decoded as JSON if the `Content-Type` is `application/json`
+/// and as postcard otherwise
+pub struct Payload<T>(pub T);
 
 #[derive(Debug, thiserror::Error, ErrorWithStatusCode)]
 #[status_code(BAD_REQUEST)]
-pub enum BadPostcardRequest {
+pub enum BadPayloadRequest {
     #[error("Read Error")]
     #[status_code(INTERNAL_SERVER_ERROR)]
     ReadError,
+    #[error("Body of {0} bytes exceeds the limit of {} bytes", MAX_STORED_SIZE)]
+    #[status_code(PAYLOAD_TOO_LARGE)]
+    TooLarge(usize),
     #[error("Postcard deserialize failed: {0}")]
     DeserializationError(#[from] postcard::Error),
+    #[error("JSON deserialize failed: {0}")]
+    JsonError(serde_json::Error),
 }
 
-impl<'r, State, T: serde::Deserialize<'r>> FromRequest<'r, State> for Postcard<T> {
-    type Rejection = BadPostcardRequest;
+impl<'r, State, T: serde::de::DeserializeOwned> FromRequest<'r, State> for Payload<T> {
+    type Rejection = BadPayloadRequest;
 
-    async fn from_request<R: picoserve::io::Read>(
+    async fn from_request<R: Read>(
         _state: &'r State,
-        _request_parts: picoserve::request::RequestParts<'r>,
+        request_parts: picoserve::request::RequestParts<'r>,
+        request_body: picoserve::request::RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let encoding = Encoding::from_header(&request_parts, "Content-Type");
+        if request_body.content_length() > MAX_STORED_SIZE {
+            return Err(BadPayloadRequest::TooLarge(request_body.content_length()));
+        }
+        let data = read_body(request_body)
+            .await
+            .map_err(|_| BadPayloadRequest::ReadError)?;
+        match encoding {
+            Encoding::Postcard => Ok(Payload(from_bytes(&data)?)),
+            Encoding::Json => Ok(Payload(
+                serde_json::from_slice(&data).map_err(BadPayloadRequest::JsonError)?,
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UploadQuery {
+    key: String,
+    /// Number of bytes already stored by an interrupted upload which is resumed
+    #[serde(default)]
+    offset: u32,
+}
+
+/// Streams an uploaded sprite into flash in chunks of [`CHUNK_SIZE`] bytes.
+/// JSON uploads are converted to postcard and therefore have to be read completely first.
+/// The offset of a resumed JSON upload counts the bytes of the converted sprite,
+/// so only the part which is not stored yet is written.
+pub struct SpriteUpload {
+    pub key: String,
+    pub size: u32,
+}
+
+#[derive(Debug, thiserror::Error, ErrorWithStatusCode)]
+#[status_code(BAD_REQUEST)]
+pub enum BadSpriteUpload {
+    #[error("Read Error")]
+    #[status_code(INTERNAL_SERVER_ERROR)]
+    ReadError,
+    #[error("Missing key or invalid offset")]
+    BadQuery,
+    #[error("Sprite of {0} bytes exceeds the limit of {} bytes", MAX_STORED_SIZE)]
+    #[status_code(PAYLOAD_TOO_LARGE)]
+    TooLarge(usize),
+    #[error(
+        "JSON sprite of {0} bytes exceeds the limit of {} bytes",
+        MAX_JSON_SIZE
+    )]
+    #[status_code(PAYLOAD_TOO_LARGE)]
+    JsonTooLarge(usize),
+    #[error("JSON deserialize failed: {0}")]
+    JsonError(serde_json::Error),
+    #[error("Offset {0} is past the end of the sprite of {1} bytes")]
+    OffsetPastEnd(u32, usize),
+    #[error("Connection closed after {0} bytes were stored")]
+    Interrupted(u32),
+    #[error("{0}")]
+    Rejected(UploadError),
+    #[error("Failed to store sprite after {0} bytes: {1:?}")]
+    #[status_code(INTERNAL_SERVER_ERROR)]
+    StoreFailed(u32, FlashError),
+}
+
+fn store_failed(stored: u32, error: FlashError) -> BadSpriteUpload {
+    match error {
+        FlashError::UploadErr(e) => BadSpriteUpload::Rejected(e),
+        e => BadSpriteUpload::StoreFailed(stored, e),
+    }
+}
+
+impl<'r, State> FromRequest<'r, State> for SpriteUpload {
+    type Rejection = BadSpriteUpload;
+
+    async fn from_request<R: Read>(
+        state: &'r State,
+        request_parts: picoserve::request::RequestParts<'r>,
         request_body: picoserve::request::RequestBody<'r, R>,
     ) -> Result<Self, Self::Rejection> {
-        Ok(Postcard(from_bytes(
-            request_body
-                .read_all()
+        let Query(UploadQuery { key, offset }) =
+            Query::<UploadQuery>::from_request_parts(state, &request_parts)
                 .await
-                .map_err(|_| BadPostcardRequest::ReadError)?,
-        )?))
+                .map_err(|_| BadSpriteUpload::BadQuery)?;
+        if offset as usize % CHUNK_SIZE != 0 {
+            return Err(BadSpriteUpload::Rejected(UploadError::Unaligned(offset)));
+        }
+
+        let mut position = offset;
+        match Encoding::from_header(&request_parts, "Content-Type") {
+            Encoding::Postcard => {
+                let mut reader = request_body.reader();
+                let total_size = offset as usize + reader.content_length();
+                if total_size > MAX_STORED_SIZE {
+                    return Err(BadSpriteUpload::TooLarge(total_size));
+                }
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                loop {
+                    let mut buf = [0u8; 1024];
+                    let free = CHUNK_SIZE - chunk.len();
+                    let read_size = reader
+                        .read(&mut buf[..free.min(1024)])
+                        .await
+                        .map_err(|_| BadSpriteUpload::ReadError)?;
+                    chunk.extend_from_slice(&buf[..read_size]);
+                    let done = read_size == 0;
+                    if done && position as usize + chunk.len() < total_size {
+                        // The complete chunks stay in flash, so the upload can be resumed
+                        return Err(BadSpriteUpload::Interrupted(position));
+                    }
+                    if chunk.len() == CHUNK_SIZE || (done && !chunk.is_empty()) {
+                        let data = core::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+                        let len = data.len() as u32;
+                        flash::store_chunk(Namespace::Sprite, key.clone(), position, data)
+                            .await
+                            .map_err(|e| store_failed(position, e))?;
+                        position += len;
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            }
+            Encoding::Json => {
+                if request_body.content_length() > MAX_JSON_SIZE {
+                    return Err(BadSpriteUpload::JsonTooLarge(request_body.content_length()));
+                }
+                let json = read_body(request_body)
+                    .await
+                    .map_err(|_| BadSpriteUpload::ReadError)?;
+                let resource: Resource =
+                    serde_json::from_slice(&json).map_err(BadSpriteUpload::JsonError)?;
+                drop(json);
+                let data = postcard::to_allocvec(&resource).expect("Failed to serialize resource");
+                if data.len() > MAX_STORED_SIZE {
+                    return Err(BadSpriteUpload::TooLarge(data.len()));
+                }
+                let Some(remaining) = data.get(offset as usize..) else {
+                    return Err(BadSpriteUpload::OffsetPastEnd(offset, data.len()));
+                };
+                for chunk in remaining.chunks(CHUNK_SIZE) {
+                    flash::store_chunk(Namespace::Sprite, key.clone(), position, chunk.to_vec())
+                        .await
+                        .map_err(|e| store_failed(position, e))?;
+                    position += chunk.len() as u32;
+                }
+            }
+        }
+
+        flash::store_finish(Namespace::Sprite, key.clone(), position)
+            .await
+            .map_err(|e| store_failed(position, e))?;
+        Ok(SpriteUpload {
+            key,
+            size: position,
+        })
     }
 }
 
-pub struct RawData(pub Vec<u8>);
+/// Streams the request body into the inactive OTA slot while it is received
+pub struct FirmwareUpload(pub usize);
 
 #[derive(Debug, thiserror::Error, ErrorWithStatusCode)]
 #[status_code(BAD_REQUEST)]
-pub enum BadRawDataRequest {
+pub enum BadFirmwareUpload {
     #[error("Read Error")]
     #[status_code(INTERNAL_SERVER_ERROR)]
     ReadError,
+    #[error("Firmware update failed: {0:?}")]
+    UpdateFailed(FlashError),
 }
 
-impl<'r, State> FromRequest<'r, State> for RawData {
-    type Rejection = BadRawDataRequest;
+impl<'r, State> FromRequest<'r, State> for FirmwareUpload {
+    type Rejection = BadFirmwareUpload;
 
-    async fn from_request<R: picoserve::io::Read>(
+    async fn from_request<R: Read>(
         _state: &'r State,
         _request_parts: picoserve::request::RequestParts<'r>,
         request_body: picoserve::request::RequestBody<'r, R>,
     ) -> Result<Self, Self::Rejection> {
         let mut reader = request_body.reader();
         let total_size = reader.content_length();
-        let mut data = Vec::with_capacity(total_size);
+        flash::ota_begin(total_size as u32)
+            .await
+            .map_err(BadFirmwareUpload::UpdateFailed)?;
         loop {
-            let mut buf = [0u8; 1024];
+            let mut buf = [0u8; 4096];
             let read_size = reader
                 .read(&mut buf)
                 .await
-                .map_err(|_| BadRawDataRequest::ReadError)?;
-            data.extend_from_slice(&buf[..read_size]);
+                .map_err(|_| BadFirmwareUpload::ReadError)?;
             if read_size == 0 {
                 break;
             }
+            flash::ota_write(buf[..read_size].to_vec())
+                .await
+                .map_err(BadFirmwareUpload::UpdateFailed)?;
         }
+        flash::ota_finish()
+            .await
+            .map_err(BadFirmwareUpload::UpdateFailed)?;
 
-        Ok(RawData(data))
+        Ok(FirmwareUpload(total_size))
     }
 }
 
@@ -139,18 +418,95 @@ async fn settings_handler(settings: Query<SettingsQuery>) -> (response::StatusCo
     (response::StatusCode::OK, "Settings updated")
 }
 
+async fn schedule_handler() -> Json<Schedule> {
+    Json(schedule())
+}
+
+async fn schedule_update_handler(schedule: Payload<Schedule>) -> (response::StatusCode, String) {
+    let schedule = schedule.0;
+    if let Err(e) = schedule.validate() {
+        return (response::StatusCode::BAD_REQUEST, String::from(e));
+    }
+    match set_schedule(schedule).await {
+        Ok(_) => (response::StatusCode::OK, String::from("Schedule updated")),
+        Err(e) => (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store schedule: {e:?}"),
+        ),
+    }
+}
+
+async fn screenshot_handler() -> Result<QoiImage, (response::StatusCode, &'static str)> {
+    let _screenshot = SCREENSHOT_LOCK.lock().await;
+    SCREENSHOT_RESULT.reset();
+    SCREENSHOT_REQUEST.signal(());
+    // The display task only renders while the panel is on, so don't wait forever
+    with_timeout(Duration::from_secs(3), SCREENSHOT_RESULT.wait())
+        .await
+        .map_err(|_| {
+            (
+                response::StatusCode::SERVICE_UNAVAILABLE,
+                "Timed out waiting for the display to render. Is the panel turned on?",
+            )
+        })
+}
+
+#[derive(serde::Deserialize)]
+struct LogsQuery {
+    /// Most verbose level to return. All levels if empty
+    #[serde(default)]
+    level: String,
+    /// Only return records with a higher id
+    #[serde(default)]
+    since: u32,
+    /// Wait for new records if there are none yet
+    #[serde(default)]
+    follow: bool,
+}
+
+async fn logs_handler(
+    query: Query<LogsQuery>,
+) -> Result<Json<Vec<LogRecord>>, (response::StatusCode, &'static str)> {
+    let query = query.0;
+    let level = if query.level.is_empty() {
+        LogLevel::Trace
+    } else {
+        parse_level(&query.level).ok_or((
+            response::StatusCode::BAD_REQUEST,
+            "Level has to be error, warn, info, debug or trace",
+        ))?
+    };
+    Ok(Json(
+        wait_for_records(query.since, level, query.follow).await,
+    ))
+}
+
+async fn cache_handler() -> Json<SpriteCacheStats> {
+    Json(cache_stats())
+}
+
+async fn crashes_handler() -> Result<Json<Vec<CrashReport>>, (response::StatusCode, String)> {
+    crash_reports().await.map(Json).map_err(|e| {
+        (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read crash reports: {e:?}"),
+        )
+    })
+}
+
 async fn format_handler() -> (response::StatusCode, String) {
     DISPLAY_CONFIG_SIGNAL.signal(None);
 
-    FLASH_OPERATION.send(FlashOperation::Format).await;
-    match FLASH_OPERATION_RESULT.wait().await {
+    let result = flash::clear(Namespace::Sprite).await;
+    SPRITE_CACHE.lock().await.invalidate(None);
+    match result {
         Ok(_) => (
             response::StatusCode::OK,
-            String::from("Flash formated and config cleared"),
+            String::from("Sprites deleted and config cleared"),
         ),
         Err(e) => (
             response::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to format flash: {e:?}"),
+            format!("Failed to delete sprites: {e:?}"),
         ),
     }
 }
@@ -160,55 +516,47 @@ struct FlashKey {
     key: String,
 }
 
-async fn upload_handler(key: Query<FlashKey>, data: RawData) -> (response::StatusCode, String) {
-    // info!("Got data: {:?}", data.0);
-    let result = postcard::from_bytes::<Resource>(&data.0);
-    if let Err(e) = result {
-        return (
-            response::StatusCode::BAD_REQUEST,
-            format!("Failed to deserialize postcard: {e}",),
-        );
-    }
-    FLASH_OPERATION
-        .send(FlashOperation::Store(key.0.key, data.0))
-        .await;
-    match FLASH_OPERATION_RESULT.wait().await {
-        Ok(_) => (response::StatusCode::OK, String::from("Item stored")),
-        Err(e) => (
-            response::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to store item: {e:?}"),
-        ),
-    }
+async fn upload_handler(upload: SpriteUpload) -> (response::StatusCode, String) {
+    info!("Stored {} with {} bytes", upload.key, upload.size);
+    SPRITE_CACHE.lock().await.invalidate(Some(&upload.key));
+    (response::StatusCode::OK, String::from("Item stored"))
+}
+
+async fn upload_offset_handler(
+    key: Query<FlashKey>,
+) -> Result<Json<UploadProgress>, (response::StatusCode, String)> {
+    flash::upload_offset(Namespace::Sprite, key.0.key)
+        .await
+        .map(|offset| Json(UploadProgress { offset }))
+        .map_err(|e| {
+            (
+                response::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to check the upload: {e:?}"),
+            )
+        })
 }
 
 async fn exists_handler(key: Query<FlashKey>) -> (response::StatusCode, String) {
-    FLASH_OPERATION
-        .send(FlashOperation::Exists(key.0.key))
-        .await;
-    match FLASH_OPERATION_RESULT.wait().await {
-        Err(FlashOperationResult::ExistsResult(exists)) => {
-            if exists {
-                (response::StatusCode::OK, String::from("Item exists"))
-            } else {
-                (
-                    response::StatusCode::OK,
-                    String::from("Item does not exist"),
-                )
-            }
-        }
-        other => (
+    match flash::exists(Namespace::Sprite, key.0.key).await {
+        Ok(true) => (response::StatusCode::OK, String::from("Item exists")),
+        Ok(false) => (
+            response::StatusCode::OK,
+            String::from("Item does not exist"),
+        ),
+        Err(e) => (
             response::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to check if item exists: {other:?}"),
+            format!("Failed to check if item exists: {e:?}"),
         ),
     }
 }
 
 async fn delete_handler(key: Query<FlashKey>) -> (response::StatusCode, String) {
-    FLASH_OPERATION
-        .send(FlashOperation::Delete(key.0.key))
-        .await;
-    match FLASH_OPERATION_RESULT.wait().await {
-        Ok(_) => (response::StatusCode::OK, String::from("Item was deleted")),
+    let key = key.0.key;
+    match flash::delete(Namespace::Sprite, key.clone()).await {
+        Ok(_) => {
+            SPRITE_CACHE.lock().await.invalidate(Some(&key));
+            (response::StatusCode::OK, String::from("Item was deleted"))
+        }
         Err(e) => {
             error!("Failed to delete item: {e:?}");
             (
@@ -219,10 +567,107 @@ async fn delete_handler(key: Query<FlashKey>) -> (response::StatusCode, String)
     }
 }
 
+async fn list_handler() -> Result<Json<StorageInfo>, (response::StatusCode, String)> {
+    flash::list(Namespace::Sprite).await.map(Json).map_err(|e| {
+        (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to list items: {e:?}"),
+        )
+    })
+}
+
+async fn download_handler(
+    key: Query<FlashKey>,
+    accept: Accept,
+) -> Result<Encoded, (response::StatusCode, String)> {
+    let data = match flash::read(Namespace::Sprite, key.0.key).await {
+        Ok(data) => data,
+        Err(FlashError::ReadErr(ReadError::KeyNotFound)) => {
+            return Err((
+                response::StatusCode::NOT_FOUND,
+                String::from("Item does not exist"),
+            ))
+        }
+        Err(e) => {
+            return Err((
+                response::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read item: {e:?}"),
+            ))
+        }
+    };
+    match accept.0 {
+        Encoding::Postcard => Ok(Encoded(Encoding::Postcard, data)),
+        Encoding::Json => postcard::from_bytes::<Resource>(&data)
+            .ok()
+            .and_then(|resource| serde_json::to_vec(&resource).ok())
+            .map(|json| Encoded(Encoding::Json, json))
+            .ok_or((
+                response::StatusCode::INTERNAL_SERVER_ERROR,
+                String::from("Stored item is not a valid resource"),
+            )),
+    }
+}
+
+async fn ota_handler(firmware: FirmwareUpload) -> (response::StatusCode, String) {
+    REBOOT.signal(());
+    (
+        response::StatusCode::OK,
+        format!(
+            "Firmware update of {} bytes written. Rebooting...",
+            firmware.0
+        ),
+    )
+}
+
+async fn wifi_handler() -> Json<WifiStatus> {
+    Json(wifi_status())
+}
+
+async fn wifi_add_handler(network: Payload<WifiNetwork>) -> (response::StatusCode, String) {
+    let network = network.0;
+    if let Err(e) = validate_network(&network) {
+        return (response::StatusCode::BAD_REQUEST, String::from(e));
+    }
+    match add_network(network).await {
+        Ok(_) => (response::StatusCode::OK, String::from("Network stored")),
+        Err(e) => (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to store network: {e:?}"),
+        ),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SsidQuery {
+    ssid: String,
+}
+
+async fn wifi_delete_handler(query: Query<SsidQuery>) -> (response::StatusCode, String) {
+    match remove_network(&query.0.ssid).await {
+        Ok(true) => (
+            response::StatusCode::OK,
+            String::from("Network was deleted"),
+        ),
+        Ok(false) => (
+            response::StatusCode::NOT_FOUND,
+            String::from("Network is not known"),
+        ),
+        Err(e) => (
+            response::StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete network: {e:?}"),
+        ),
+    }
+}
+
+async fn heartbeat_handler() -> (response::StatusCode, &'static str) {
+    HEARTBEAT_SIGNAL.signal(());
+    (response::StatusCode::OK, "Heartbeat received")
+}
+
 // TODO: Implement checks that all styles used are also defined
 // Check that all used sprites are also in flash
 async fn config_handler(
-    config: Postcard<Configuration>,
+    config: Payload<Configuration>,
 ) -> Result<(response::StatusCode, &'static str), ScreenBuildError> {
     info!("Validating config update");
     let config = config.0;
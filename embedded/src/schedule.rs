@@ -0,0 +1,108 @@
+use crate::flash::{self, FlashError, FlashResult, Namespace};
+use crate::panel::{BRIGHTNESS, PANEL_ON};
+use crate::sntp::unix_time;
+use crate::CONFIG;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
+use ekv::ReadError;
+use embassy_executor::task;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration};
+use interface::{DaylightSaving, Schedule};
+use log::{error, info};
+
+const SCHEDULE_KEY: &str = "schedule";
+
+/// How often the schedule is checked for a switch
+const CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+const INITIAL_BRIGHTNESS: u8 = CONFIG.panel.initial_brightness as u8;
+
+static SCHEDULE: Mutex<CriticalSectionRawMutex, RefCell<Schedule>> =
+    Mutex::new(RefCell::new(Schedule {
+        utc_offset_minutes: 0,
+        daylight_saving: DaylightSaving::None,
+        entries: Vec::new(),
+    }));
+
+/// Signaled when a new schedule was stored so that it is applied right away
+static SCHEDULE_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct PanelState {
+    on: bool,
+    brightness: u8,
+}
+
+pub fn schedule() -> Schedule {
+    SCHEDULE.lock(|schedule| schedule.borrow().clone())
+}
+
+/// Store a new schedule and apply it right away
+pub async fn set_schedule(schedule: Schedule) -> FlashResult<()> {
+    let data = postcard::to_allocvec(&schedule).expect("Failed to serialize schedule");
+    flash::store(Namespace::Settings, SCHEDULE_KEY.into(), data).await?;
+    SCHEDULE.lock(|stored| *stored.borrow_mut() = schedule);
+    SCHEDULE_CHANGED.signal(());
+    Ok(())
+}
+
+async fn load_schedule() {
+    match flash::read(Namespace::Settings, SCHEDULE_KEY.into()).await {
+        Ok(data) => match postcard::from_bytes::<Schedule>(&data) {
+            Ok(schedule) => {
+                info!("Loaded schedule with {} entries", schedule.entries.len());
+                SCHEDULE.lock(|stored| *stored.borrow_mut() = schedule);
+            }
+            Err(e) => error!("Stored schedule is corrupt: {e}"),
+        },
+        Err(FlashError::ReadErr(ReadError::KeyNotFound)) => {}
+        Err(e) => error!("Failed to read stored schedule: {e:?}"),
+    }
+}
+
+/// Panel state the schedule asks for at the given time. None if there is no schedule
+fn scheduled_state(schedule: &Schedule, unix_time: u64) -> Option<PanelState> {
+    if schedule.entries.is_empty() {
+        return None;
+    }
+    let state = match schedule.active_entry(unix_time as i64) {
+        Some(entry) => PanelState {
+            on: entry.on,
+            brightness: entry.brightness.unwrap_or(INITIAL_BRIGHTNESS),
+        },
+        None => PanelState {
+            on: true,
+            brightness: INITIAL_BRIGHTNESS,
+        },
+    };
+    Some(state)
+}
+
+/// Switch the panel according to the schedule.
+///
+/// The state is only set when the schedule switches, so changes through the REST API
+/// last until the next scheduled switch.
+#[task]
+pub async fn schedule_task() {
+    load_schedule().await;
+    let mut applied = None;
+    loop {
+        let state = unix_time()
+            .and_then(|now| SCHEDULE.lock(|schedule| scheduled_state(&schedule.borrow(), now)));
+        if let Some(state) = state.filter(|state| applied != Some(*state)) {
+            info!("Schedule switches panel to {state:?}");
+            PANEL_ON.store(state.on, Ordering::Relaxed);
+            BRIGHTNESS.store(state.brightness, Ordering::Relaxed);
+            applied = Some(state);
+        }
+        if with_timeout(CHECK_INTERVAL, SCHEDULE_CHANGED.wait())
+            .await
+            .is_ok()
+        {
+            applied = None;
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::CONFIG;
+use alloc::vec::Vec;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use interface::render::Canvas;
+use picoserve::response::Content;
+
+/// Bits per color channel the panel shows, screenshots are reduced to this
+pub const BITS: u8 = CONFIG.panel.color_depth as u8;
+
+pub type ScreenshotRequestSignal = Signal<CriticalSectionRawMutex, ()>;
+pub type ScreenshotResultSignal = Signal<CriticalSectionRawMutex, QoiImage>;
+
+pub static SCREENSHOT_REQUEST: ScreenshotRequestSignal = Signal::new();
+pub static SCREENSHOT_RESULT: ScreenshotResultSignal = Signal::new();
+
+/// Held while a screenshot is taken. A second request would otherwise reset the result
+/// the first one is waiting for
+pub static SCREENSHOT_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+/// A QOI encoded image which can be sent as a HTTP response
+pub struct QoiImage(pub Vec<u8>);
+
+impl Content for QoiImage {
+    fn content_type(&self) -> &'static str {
+        "image/qoi"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.0.as_slice().write_content(writer).await
+    }
+}
+
+/// Draw target which forwards everything to the panel framebuffer and,
+/// while a screenshot is pending, mirrors it into a [`Canvas`] as well.
+pub struct MirroredTarget<'a, T: DrawTarget<Color = Rgb888>> {
+    target: &'a mut T,
+    mirror: Option<&'a mut Canvas>,
+}
+
+impl<'a, T: DrawTarget<Color = Rgb888>> MirroredTarget<'a, T> {
+    pub fn new(target: &'a mut T, mirror: Option<&'a mut Canvas>) -> Self {
+        Self { target, mirror }
+    }
+}
+
+impl<T: DrawTarget<Color = Rgb888>> Dimensions for MirroredTarget<'_, T> {
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<T: DrawTarget<Color = Rgb888>> DrawTarget for MirroredTarget<'_, T> {
+    type Color = Rgb888;
+    type Error = T::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        match self.mirror {
+            Some(ref mut mirror) => self.target.draw_iter(pixels.into_iter().inspect(|pixel| {
+                mirror.draw_iter([*pixel]).ok();
+            })),
+            None => self.target.draw_iter(pixels),
+        }
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        if let Some(ref mut mirror) = self.mirror {
+            mirror.clear(color).ok();
+        }
+        self.target.clear(color)
+    }
+}
@@ -0,0 +1,89 @@
+use crate::CONFIG;
+use core::cell::Cell;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::IpEndpoint;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use log::{error, info};
+
+const NTP_SERVER: &str = CONFIG.time.ntp_server;
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 12300;
+const NTP_PACKET_LEN: usize = 48;
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Unix time at which the system booted. None until the time was synchronized
+static BOOT_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// Current Unix time in seconds, None if the time was not synchronized yet
+pub fn unix_time() -> Option<u64> {
+    BOOT_TIME
+        .lock(|boot_time| boot_time.get())
+        .map(|boot_time| boot_time + Instant::now().as_secs())
+}
+
+/// Ask the NTP server for the current Unix time in seconds
+async fn query_time(stack: embassy_net::Stack<'static>) -> Result<u64, &'static str> {
+    let address = stack
+        .dns_query(NTP_SERVER, DnsQueryType::A)
+        .await
+        .map_err(|_| "DNS lookup failed")?
+        .first()
+        .copied()
+        .ok_or("NTP server has no address")?;
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buf = [0u8; 128];
+    let mut tx_buf = [0u8; 128];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buf, &mut tx_meta, &mut tx_buf);
+    socket
+        .bind(LOCAL_PORT)
+        .map_err(|_| "Failed to bind socket")?;
+
+    let mut packet = [0u8; NTP_PACKET_LEN];
+    // Leap indicator 0, version 4, mode 3 (client)
+    packet[0] = 0x23;
+    socket
+        .send_to(&packet, IpEndpoint::new(address, NTP_PORT))
+        .await
+        .map_err(|_| "Failed to send request")?;
+    let (len, _) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|_| "No response")?
+        .map_err(|_| "Failed to receive response")?;
+    // Mode 4 (server)
+    if len < NTP_PACKET_LEN || packet[0] & 0x07 != 4 {
+        return Err("Invalid response");
+    }
+    // Seconds part of the transmit timestamp
+    let seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    seconds
+        .checked_sub(NTP_UNIX_OFFSET)
+        .ok_or("Server is not synchronized")
+}
+
+/// Keep the wall clock time synchronized with the configured NTP server
+#[embassy_executor::task]
+pub async fn sntp_task(stack: embassy_net::Stack<'static>) {
+    loop {
+        stack.wait_config_up().await;
+        match query_time(stack).await {
+            Ok(now) => {
+                BOOT_TIME.lock(|boot_time| boot_time.set(Some(now - Instant::now().as_secs())));
+                info!("Synchronized time with {NTP_SERVER}: {now}");
+                Timer::after(SYNC_INTERVAL).await;
+            }
+            Err(e) => {
+                error!("Failed to get time from {NTP_SERVER}: {e}");
+                Timer::after(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
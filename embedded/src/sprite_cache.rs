@@ -0,0 +1,266 @@
+use crate::flash::{read_value, FlashType, Namespace};
+use crate::CONFIG;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use embassy_executor::task;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use interface::render::{Lookup, SpriteSource};
+use interface::sprite::{bake_as, BakedResource};
+use interface::{FrameFormat, Resource, SpriteCacheStats};
+use log::{error, info, warn};
+use postcard::from_bytes;
+
+/// Bytes of sprite data kept in RAM. The least recently drawn sprites are evicted first
+const BUDGET: usize = CONFIG.sprites.cache_budget as usize;
+
+/// Bits per color channel the panel shows, colors of RLE frames are reduced to this
+const COLOR_DEPTH: u8 = CONFIG.panel.color_depth as u8;
+
+/// Number of sprites which can wait to be loaded
+const LOAD_QUEUE_SIZE: usize = 16;
+
+/// Format sprite frames are kept in if the sprite element does not set one
+const DEFAULT_FRAME_FORMAT: FrameFormat = match CONFIG.sprites.frame_format.as_bytes() {
+    b"qoi" => FrameFormat::Qoi,
+    b"rgb" => FrameFormat::Rgb,
+    b"rle" => FrameFormat::Rle,
+    _ => panic!("sprites.frame_format has to be qoi, rgb or rle"),
+};
+
+/// A sprite can be kept in several formats at once, as elements may ask for different ones
+type CacheKey = (String, FrameFormat);
+
+struct CachedSprite {
+    sprite: BakedResource,
+    size: usize,
+    /// Frame the sprite was last drawn in
+    last_used: u32,
+}
+
+pub struct SpriteCache {
+    sprites: BTreeMap<CacheKey, CachedSprite>,
+    /// Sprites waiting for the loader task
+    pending: BTreeSet<CacheKey>,
+    /// Sprites which could not be loaded. They are retried with the next config
+    failed: BTreeSet<CacheKey>,
+    used: usize,
+    /// Increased for every rendered frame, orders the sprites by their last use
+    frame: u32,
+}
+
+pub static SPRITE_CACHE: Mutex<CriticalSectionRawMutex, SpriteCache> = Mutex::new(SpriteCache {
+    sprites: BTreeMap::new(),
+    pending: BTreeSet::new(),
+    failed: BTreeSet::new(),
+    used: 0,
+    frame: 0,
+});
+
+static LOAD_REQUESTS: Channel<CriticalSectionRawMutex, CacheKey, LOAD_QUEUE_SIZE> = Channel::new();
+
+/// Signaled when the loader task finished loading a sprite, so the screen is redrawn
+pub static SPRITE_LOADED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static HITS: AtomicU32 = AtomicU32::new(0);
+static MISSES: AtomicU32 = AtomicU32::new(0);
+static EVICTIONS: AtomicU32 = AtomicU32::new(0);
+static USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters of the sprite cache since boot
+pub fn cache_stats() -> SpriteCacheStats {
+    SpriteCacheStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        evictions: EVICTIONS.load(Ordering::Relaxed),
+        used_bytes: USED_BYTES.load(Ordering::Relaxed) as u32,
+        budget_bytes: BUDGET as u32,
+    }
+}
+
+impl SpriteCache {
+    /// Start rendering a new frame. Sprites drawn in the previous frame are on screen
+    /// and are not evicted
+    pub fn next_frame(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Load the sprites of a new config in the background
+    pub fn prefetch(&mut self, sprites: &[(&String, Option<FrameFormat>)]) {
+        self.failed.clear();
+        for (name, format) in sprites {
+            let key = (
+                String::from(name.as_str()),
+                format.unwrap_or(DEFAULT_FRAME_FORMAT),
+            );
+            if !self.sprites.contains_key(&key) {
+                self.request(key);
+            }
+        }
+    }
+
+    fn request(&mut self, key: CacheKey) {
+        if self.pending.contains(&key) {
+            return;
+        }
+        match LOAD_REQUESTS.try_send(key.clone()) {
+            Ok(_) => {
+                self.pending.insert(key);
+            }
+            // Requested again with the next frame
+            Err(_) => warn!("Sprite load queue is full, delaying {}", key.0),
+        }
+    }
+
+    /// Drop a sprite in all its formats when it was changed or deleted in flash,
+    /// or all sprites if no name is given
+    pub fn invalidate(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                let mut freed = 0;
+                self.sprites.retain(|(cached, _), sprite| {
+                    let keep = cached != name;
+                    if !keep {
+                        freed += sprite.size;
+                    }
+                    keep
+                });
+                self.used -= freed;
+                self.failed.retain(|(failed, _)| failed != name);
+            }
+            None => {
+                self.sprites.clear();
+                self.failed.clear();
+                self.used = 0;
+            }
+        }
+        USED_BYTES.store(self.used, Ordering::Relaxed);
+    }
+
+    /// Add a loaded sprite, evicting the least recently drawn sprites which are not on screen
+    fn insert(&mut self, key: CacheKey, sprite: BakedResource) {
+        if let Some(replaced) = self.sprites.remove(&key) {
+            self.used -= replaced.size;
+        }
+        let size = sprite.memory_size();
+        while self.used + size > BUDGET {
+            let Some(oldest) = self
+                .sprites
+                .iter()
+                .filter(|(_, cached)| cached.last_used != self.frame)
+                .max_by_key(|(_, cached)| self.frame.wrapping_sub(cached.last_used))
+                .map(|(key, _)| key.clone())
+            else {
+                warn!(
+                    "Sprite {} with {size} bytes does not fit into the cache of {BUDGET} bytes \
+                    together with the sprites on screen",
+                    key.0
+                );
+                self.failed.insert(key);
+                return;
+            };
+            if let Some(evicted) = self.sprites.remove(&oldest) {
+                info!(
+                    "Evicting sprite {} as {:?} from the cache",
+                    oldest.0, oldest.1
+                );
+                self.used -= evicted.size;
+                USED_BYTES.store(self.used, Ordering::Relaxed);
+                EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.used += size;
+        USED_BYTES.store(self.used, Ordering::Relaxed);
+        self.sprites.insert(
+            key,
+            CachedSprite {
+                sprite,
+                size,
+                last_used: self.frame,
+            },
+        );
+    }
+}
+
+impl SpriteSource for SpriteCache {
+    /// Get the current image of a sprite, asking the loader task for it if it is not in RAM
+    /// or kept in another format
+    fn get(&mut self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> Lookup<'_> {
+        let key = (String::from(name), format.unwrap_or(DEFAULT_FRAME_FORMAT));
+        if self.sprites.contains_key(&key) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            let cached = self.sprites.get_mut(&key).expect("Sprite was just found");
+            cached.last_used = self.frame;
+            return match cached.sprite.get_image(now_ms) {
+                Ok(img) => Lookup::Ready(img),
+                Err(_) => Lookup::Missing,
+            };
+        }
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        if self.failed.contains(&key) {
+            return Lookup::Missing;
+        }
+        self.request(key);
+        Lookup::Loading
+    }
+
+    fn frame_due(&self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> bool {
+        let key = (String::from(name), format.unwrap_or(DEFAULT_FRAME_FORMAT));
+        self.sprites
+            .get(&key)
+            .is_some_and(|cached| cached.sprite.needs_update(now_ms))
+    }
+}
+
+/// Read a sprite from flash and decode it into the format it is drawn from
+async fn load_sprite(flash: &FlashType, name: &str, format: FrameFormat) -> Option<BakedResource> {
+    info!("Loading sprite {name} as {format:?}...");
+    match read_value(flash, Namespace::Sprite, name).await {
+        Ok(data) => {
+            match from_bytes::<Resource>(&data) {
+                Ok(res) => match bake_as(res, format, COLOR_DEPTH) {
+                    Ok(sprite) => {
+                        if sprite.format() != format {
+                            warn!("Sprite {name} has too many colors for {format:?}, keeping it as {:?}", sprite.format());
+                        }
+                        Some(sprite)
+                    }
+                    Err(e) => {
+                        error!("Could not decode '{name}' sprite: {e}");
+                        None
+                    }
+                },
+                Err(e) => {
+                    error!("Could not parse '{name}' sprite from flash: {e:?}");
+                    None
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed reading sprite {name} from flash: {e:?}");
+            None
+        }
+    }
+}
+
+/// Load requested sprites from flash into the cache, without holding up the rendering
+#[task]
+pub async fn sprite_loader_task(flash: &'static FlashType) {
+    loop {
+        let key = LOAD_REQUESTS.receive().await;
+        let loaded = load_sprite(flash, &key.0, key.1).await;
+        let mut cache = SPRITE_CACHE.lock().await;
+        cache.pending.remove(&key);
+        match loaded {
+            Some(sprite) => cache.insert(key, sprite),
+            None => {
+                cache.failed.insert(key);
+            }
+        }
+        drop(cache);
+        SPRITE_LOADED.signal(());
+    }
+}
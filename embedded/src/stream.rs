@@ -0,0 +1,238 @@
+use crate::topology;
+use crate::CONFIG;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use embassy_futures::select::{select, Either};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*};
+use esp_hub75::Color;
+use log::{error, info};
+
+const ENABLED: bool = CONFIG.stream.enabled;
+/// Time without packets after which the configured screen is shown again
+const TIMEOUT: Duration = Duration::from_millis(CONFIG.stream.timeout_ms as u64);
+/// E1.31 universe of the first pixel, the following universes continue the pixels
+const START_UNIVERSE: u16 = CONFIG.stream.start_universe as u16;
+const PIXELS_PER_UNIVERSE: usize = CONFIG.stream.pixels_per_universe as usize;
+/// Whether every other row runs from right to left
+const SERPENTINE: bool = match CONFIG.stream.mapping.as_bytes() {
+    b"rows" => false,
+    b"serpentine" => true,
+    _ => panic!("stream.mapping has to be \"rows\" or \"serpentine\""),
+};
+const _: () = assert!(
+    PIXELS_PER_UNIVERSE >= 1 && PIXELS_PER_UNIVERSE <= 170,
+    "stream.pixels_per_universe has to be between 1 and 170"
+);
+
+const WIDTH: usize = topology::SIZE.width as usize;
+const HEIGHT: usize = topology::SIZE.height as usize;
+const CHANNELS: usize = WIDTH * HEIGHT * 3;
+
+const DDP_PORT: u16 = 4048;
+const E131_PORT: u16 = 5568;
+const MAX_PACKET_SIZE: usize = 1500;
+
+const DDP_HEADER_LEN: usize = 10;
+const DDP_VERSION_MASK: u8 = 0xc0;
+const DDP_VERSION_1: u8 = 0x40;
+const DDP_FLAG_TIMECODE: u8 = 0x10;
+const DDP_FLAG_QUERY: u8 = 0x02;
+const DDP_FLAG_PUSH: u8 = 0x01;
+/// Destination IDs addressing the display, 0 is reserved but sent by some senders
+const DDP_DISPLAY_IDS: [u8; 2] = [0, 1];
+
+const E131_ACN_ID: &[u8] = b"ASC-E1.17\0\0\0";
+const E131_HEADER_LEN: usize = 126;
+const E131_ROOT_VECTOR: u32 = 0x0000_0004;
+const E131_FRAMING_VECTOR: u32 = 0x0000_0002;
+const E131_OPTION_TERMINATED: u8 = 0x40;
+
+/// Pixels received from the stream in the order they were sent, 3 channels each
+static PIXELS: Mutex<CriticalSectionRawMutex, Vec<u8>> = Mutex::new(Vec::new());
+
+/// Time the last packet was received. None if no stream is running
+static LAST_PACKET: BlockingMutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    BlockingMutex::new(Cell::new(None));
+
+/// Signaled when a complete frame was received and should be shown
+pub static STREAM_FRAME: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether a stream is running and should be shown instead of the configured screen
+pub fn is_streaming(now: Instant) -> bool {
+    LAST_PACKET
+        .lock(|last| last.get())
+        .is_some_and(|last| now.duration_since(last) < TIMEOUT)
+}
+
+/// Position on the panel of the pixel with the given index in the stream
+fn pixel_position(index: usize) -> Point {
+    let (row, column) = (index / WIDTH, index % WIDTH);
+    let column = if SERPENTINE && row % 2 == 1 {
+        WIDTH - 1 - column
+    } else {
+        column
+    };
+    Point::new(column as i32, row as i32)
+}
+
+/// Draw the last received frame
+pub async fn draw_stream<D: DrawTarget<Color = Color>>(fb: &mut D) {
+    let pixels = PIXELS.lock().await;
+    fb.draw_iter(
+        pixels
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(index, rgb)| Pixel(pixel_position(index), Rgb888::new(rgb[0], rgb[1], rgb[2]))),
+    )
+    .ok();
+}
+
+/// Copy channel data to the given channel offset, dropping anything past the display
+async fn write_channels(offset: usize, data: &[u8]) {
+    if offset >= CHANNELS {
+        return;
+    }
+    let len = data.len().min(CHANNELS - offset);
+    PIXELS.lock().await[offset..offset + len].copy_from_slice(&data[..len]);
+}
+
+fn received_packet() {
+    LAST_PACKET.lock(|last| last.set(Some(Instant::now())));
+}
+
+fn stop_stream() {
+    LAST_PACKET.lock(|last| last.set(None));
+    STREAM_FRAME.signal(());
+}
+
+/// Handle a DDP packet. Returns whether the frame is complete
+async fn handle_ddp(packet: &[u8]) -> bool {
+    if packet.len() < DDP_HEADER_LEN {
+        return false;
+    }
+    let flags = packet[0];
+    if flags & DDP_VERSION_MASK != DDP_VERSION_1
+        || flags & DDP_FLAG_QUERY != 0
+        || !DDP_DISPLAY_IDS.contains(&packet[3])
+    {
+        return false;
+    }
+    let offset = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]) as usize;
+    let len = u16::from_be_bytes([packet[8], packet[9]]) as usize;
+    let header_len = if flags & DDP_FLAG_TIMECODE != 0 {
+        DDP_HEADER_LEN + 4
+    } else {
+        DDP_HEADER_LEN
+    };
+    let Some(data) = packet.get(header_len..header_len + len) else {
+        return false;
+    };
+    received_packet();
+    write_channels(offset, data).await;
+    flags & DDP_FLAG_PUSH != 0
+}
+
+/// Handle an E1.31 data packet. Returns whether the frame should be shown
+async fn handle_e131(packet: &[u8]) -> bool {
+    let read_u32 = |at: usize| {
+        u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]])
+    };
+    if packet.len() < E131_HEADER_LEN
+        || &packet[4..16] != E131_ACN_ID
+        || read_u32(18) != E131_ROOT_VECTOR
+        || read_u32(40) != E131_FRAMING_VECTOR
+    {
+        return false;
+    }
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let Some(index) = universe.checked_sub(START_UNIVERSE) else {
+        return false;
+    };
+    if packet[112] & E131_OPTION_TERMINATED != 0 {
+        info!("E1.31 stream was terminated");
+        stop_stream();
+        return false;
+    }
+    // Property value count includes the start code, only start code 0 carries pixels
+    let count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    if count == 0 || packet[125] != 0 {
+        return false;
+    }
+    let Some(data) = packet.get(E131_HEADER_LEN..E131_HEADER_LEN + count - 1) else {
+        return false;
+    };
+    let data = &data[..data.len().min(PIXELS_PER_UNIVERSE * 3)];
+    received_packet();
+    write_channels(index as usize * PIXELS_PER_UNIVERSE * 3, data).await;
+    true
+}
+
+/// Receive pixels over DDP and E1.31 (sACN) and show them instead of the configured screen
+/// while the stream is running
+#[embassy_executor::task]
+pub async fn stream_task(stack: embassy_net::Stack<'static>) {
+    if !ENABLED {
+        return;
+    }
+    *PIXELS.lock().await = vec![0; CHANNELS];
+
+    let mut ddp_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut ddp_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut ddp_rx_buf = vec![0u8; 4 * MAX_PACKET_SIZE];
+    let mut ddp_tx_buf = [0u8; 64];
+    let mut ddp = UdpSocket::new(
+        stack,
+        &mut ddp_rx_meta,
+        &mut ddp_rx_buf,
+        &mut ddp_tx_meta,
+        &mut ddp_tx_buf,
+    );
+    let mut e131_rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut e131_tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut e131_rx_buf = vec![0u8; 4 * MAX_PACKET_SIZE];
+    let mut e131_tx_buf = [0u8; 64];
+    let mut e131 = UdpSocket::new(
+        stack,
+        &mut e131_rx_meta,
+        &mut e131_rx_buf,
+        &mut e131_tx_meta,
+        &mut e131_tx_buf,
+    );
+    if ddp.bind(DDP_PORT).is_err() || e131.bind(E131_PORT).is_err() {
+        error!("Failed to bind the pixel stream sockets");
+        return;
+    }
+    info!("Listening for DDP on port {DDP_PORT} and E1.31 on port {E131_PORT}");
+
+    let mut ddp_packet = vec![0u8; MAX_PACKET_SIZE];
+    let mut e131_packet = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let was_streaming = is_streaming(Instant::now());
+        let frame_complete = match select(
+            ddp.recv_from(&mut ddp_packet),
+            e131.recv_from(&mut e131_packet),
+        )
+        .await
+        {
+            Either::First(Ok((len, _))) => handle_ddp(&ddp_packet[..len]).await,
+            Either::Second(Ok((len, _))) => handle_e131(&e131_packet[..len]).await,
+            Either::First(Err(e)) | Either::Second(Err(e)) => {
+                error!("Failed to receive stream packet: {e:?}");
+                false
+            }
+        };
+        if !was_streaming && is_streaming(Instant::now()) {
+            info!("Pixel stream started");
+        }
+        if frame_complete {
+            STREAM_FRAME.signal(());
+        }
+    }
+}
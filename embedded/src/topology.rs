@@ -0,0 +1,175 @@
+use crate::CONFIG;
+use embedded_graphics::{pixelcolor::Rgb888, prelude::*, primitives::Rectangle};
+
+const PANEL_WIDTH: u32 = CONFIG.panel.panel_width as u32;
+const PANEL_HEIGHT: u32 = CONFIG.panel.panel_height as u32;
+const PANELS_X: u32 = CONFIG.panel.num_panels_width as u32;
+const PANELS_Y: u32 = CONFIG.panel.num_panels_height as u32;
+
+/// Size of the wall as it is mounted, before the rotation of the canvas
+const PHYSICAL_WIDTH: u32 = PANEL_WIDTH * PANELS_X;
+const PHYSICAL_HEIGHT: u32 = PANEL_HEIGHT * PANELS_Y;
+
+/// Clockwise rotation in quarter turns
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Rotation {
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+const fn parse_rotation(degrees: i64, name: &str) -> Rotation {
+    match degrees {
+        0 => Rotation::None,
+        90 => Rotation::Quarter,
+        180 => Rotation::Half,
+        270 => Rotation::ThreeQuarters,
+        _ => panic!("{}", name),
+    }
+}
+
+const ROTATION: Rotation = parse_rotation(
+    CONFIG.panel.rotation,
+    "panel.rotation has to be 0, 90, 180 or 270",
+);
+const PANEL_ROTATION: Rotation = parse_rotation(
+    CONFIG.panel.panel_rotation,
+    "panel.panel_rotation has to be 0, 90, 180 or 270",
+);
+const _: () = assert!(
+    PANEL_WIDTH == PANEL_HEIGHT || matches!(PANEL_ROTATION, Rotation::None | Rotation::Half),
+    "panel.panel_rotation can only be 90 or 270 for square panels"
+);
+
+/// Whether the chain starts on the right side of the wall, seen from the front
+const START_RIGHT: bool = match CONFIG.panel.chain_start.as_bytes() {
+    b"top-left" | b"bottom-left" => false,
+    b"top-right" | b"bottom-right" => true,
+    _ => panic!("panel.chain_start has to be top-left, top-right, bottom-left or bottom-right"),
+};
+const START_BOTTOM: bool = match CONFIG.panel.chain_start.as_bytes() {
+    b"top-left" | b"top-right" => false,
+    _ => true,
+};
+/// Every other row of panels runs in the opposite direction
+const SERPENTINE: bool = CONFIG.panel.serpentine;
+
+const MIRROR_X: bool = match CONFIG.panel.mirror.as_bytes() {
+    b"none" | b"vertical" => false,
+    b"horizontal" | b"both" => true,
+    _ => panic!("panel.mirror has to be none, horizontal, vertical or both"),
+};
+const MIRROR_Y: bool = matches!(CONFIG.panel.mirror.as_bytes(), b"vertical" | b"both");
+
+/// Size of the canvas layouts are drawn on
+pub const SIZE: Size = match ROTATION {
+    Rotation::None | Rotation::Half => Size::new(PHYSICAL_WIDTH, PHYSICAL_HEIGHT),
+    Rotation::Quarter | Rotation::ThreeQuarters => Size::new(PHYSICAL_HEIGHT, PHYSICAL_WIDTH),
+};
+
+/// Rotate a point on an area of the given size (after the rotation) clockwise
+fn rotate(point: Point, size: Size, rotation: Rotation) -> Point {
+    let (width, height) = (size.width as i32, size.height as i32);
+    match rotation {
+        Rotation::None => point,
+        Rotation::Quarter => Point::new(width - 1 - point.y, point.x),
+        Rotation::Half => Point::new(width - 1 - point.x, height - 1 - point.y),
+        Rotation::ThreeQuarters => Point::new(point.y, height - 1 - point.x),
+    }
+}
+
+/// Position in the framebuffer, where all panels form one long row in the order of the chain,
+/// of a point on the canvas
+fn to_framebuffer(point: Point) -> Point {
+    let point = Point::new(
+        if MIRROR_X {
+            SIZE.width as i32 - 1 - point.x
+        } else {
+            point.x
+        },
+        if MIRROR_Y {
+            SIZE.height as i32 - 1 - point.y
+        } else {
+            point.y
+        },
+    );
+    let point = rotate(point, Size::new(PHYSICAL_WIDTH, PHYSICAL_HEIGHT), ROTATION);
+
+    let (x, y) = (point.x as u32, point.y as u32);
+    let column = x / PANEL_WIDTH;
+    let row = if START_BOTTOM {
+        PANELS_Y - 1 - y / PANEL_HEIGHT
+    } else {
+        y / PANEL_HEIGHT
+    };
+    let row_starts_right = START_RIGHT != (SERPENTINE && row % 2 == 1);
+    let position = if row_starts_right {
+        PANELS_X - 1 - column
+    } else {
+        column
+    };
+    // Pixels shifted in first end up in the last panel of the chain,
+    // so the framebuffer starts with the last panel
+    let block = PANELS_X * PANELS_Y - 1 - (row * PANELS_X + position);
+
+    let panel_size = Size::new(PANEL_WIDTH, PANEL_HEIGHT);
+    let local = Point::new((x % PANEL_WIDTH) as i32, (y % PANEL_HEIGHT) as i32);
+    // Panels are rotated back so the point is in the orientation the panel is driven in
+    let local = rotate(local, panel_size, inverse(PANEL_ROTATION));
+    // The data of a panel enters on its right, so rows running to the right are upside down
+    let local = if row_starts_right {
+        local
+    } else {
+        rotate(local, panel_size, Rotation::Half)
+    };
+    Point::new((block * PANEL_WIDTH) as i32 + local.x, local.y)
+}
+
+fn inverse(rotation: Rotation) -> Rotation {
+    match rotation {
+        Rotation::Quarter => Rotation::ThreeQuarters,
+        Rotation::ThreeQuarters => Rotation::Quarter,
+        other => other,
+    }
+}
+
+/// Draw target which maps the canvas layouts are drawn on to the panel chain,
+/// applying the chain topology, rotation and mirroring from the config
+pub struct PanelTarget<'a, T: DrawTarget<Color = Rgb888>> {
+    target: &'a mut T,
+}
+
+impl<'a, T: DrawTarget<Color = Rgb888>> PanelTarget<'a, T> {
+    pub fn new(target: &'a mut T) -> Self {
+        Self { target }
+    }
+}
+
+impl<T: DrawTarget<Color = Rgb888>> OriginDimensions for PanelTarget<'_, T> {
+    fn size(&self) -> Size {
+        SIZE
+    }
+}
+
+impl<T: DrawTarget<Color = Rgb888>> DrawTarget for PanelTarget<'_, T> {
+    type Color = Rgb888;
+    type Error = T::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let area = Rectangle::new(Point::zero(), SIZE);
+        self.target.draw_iter(
+            pixels
+                .into_iter()
+                .filter(|Pixel(point, _)| area.contains(*point))
+                .map(|Pixel(point, color)| Pixel(to_framebuffer(point), color)),
+        )
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.target.clear(color)
+    }
+}
@@ -1,243 +1,77 @@
 use core::sync::atomic::Ordering;
 
 use crate::{
-    flash::{make_buf, FlashType},
-    panel::{FrameBufferExchange, TiledFBType, SYSTEM_IS_UP},
-    resources::{bake, get_dino_sprite, get_no_image_sprite, get_wifi_sprite, BakedResource},
-    rest::DISPLAY_CONFIG_SIGNAL,
+    crash::RECOVERED,
+    panel::{FBType, FrameBufferExchange, DIMMED, SYSTEM_IS_UP},
+    provisioning::{AP_ADDRESS, AP_SSID},
+    resources::{get_dino_sprite, get_wifi_sprite},
+    rest::{DISPLAY_CONFIG_SIGNAL, HEARTBEAT_SIGNAL},
+    screenshot::{MirroredTarget, QoiImage, BITS, SCREENSHOT_REQUEST, SCREENSHOT_RESULT},
+    sprite_cache::{SPRITE_CACHE, SPRITE_LOADED},
+    stream::{draw_stream, is_streaming, STREAM_FRAME},
+    topology::{self, PanelTarget},
     wifi::{CurrentStateSignal, SystemState},
 };
-use alloc::{collections::btree_map::BTreeMap, string::String, vec::Vec};
+use alloc::{format, string::String, vec::Vec};
 use embassy_executor::task;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::geometry::Point;
+use embedded_graphics::mono_font::{ascii::FONT_5X7, MonoTextStyle};
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
 use embedded_graphics::Drawable;
-use embedded_graphics::{geometry::Point, primitives::Line};
 use embedded_graphics::{image::Image, primitives::PrimitiveStyleBuilder};
 use embedded_graphics::{mono_font::MonoTextStyleBuilder, primitives::Rectangle};
-use embedded_graphics::{
-    mono_font::{ascii::FONT_5X7, MonoTextStyle},
-    primitives::Polyline,
-};
 use embedded_graphics::{pixelcolor::Rgb888, primitives::PrimitiveStyle};
-use embedded_graphics::{prelude::*, primitives::CornerRadiiBuilder};
-use embedded_graphics::{primitives::RoundedRectangle, text::Text};
 use embedded_layout::{layout::linear::LinearLayout, prelude::*};
 use esp_hub75::Color;
-use interface::{
-    embedded::{string_to_color, CheckedScreenConfig},
-    Resource,
-};
-use interface::{Element, RectangleCorners};
-use log::{error, info};
-use postcard::from_bytes;
-
-struct SpriteRegister {
-    sprites: BTreeMap<String, BakedResource>,
-    flash: &'static FlashType,
-}
+use interface::render::{animated_areas, render_area, render_config, Canvas};
+use interface::sprite::{no_image_sprite, BakedResource};
+use interface::{Element, StaleFallback};
+use log::{info, warn};
 
-async fn bake_sprite(flash: &FlashType, name: &String) -> Option<BakedResource> {
-    let tr = flash.read_transaction().await;
-    let mut buf = make_buf();
-    info!("Baking sprite {name}...");
-    match tr.read(name.as_bytes(), &mut buf).await {
-        Ok(len) => match from_bytes::<Resource>(&buf[..len]) {
-            Ok(res) => return Some(bake(res)),
-            Err(e) => {
-                error!("Could not parse '{name}' sprite from flash: {e:?}");
-            }
-        },
-        Err(e) => {
-            error!("Failed reading sprite {name} from flash: {e:?}");
-        }
-    }
-    None
-}
-
-impl SpriteRegister {
-    fn new(flash: &'static FlashType) -> Self {
-        Self {
-            sprites: BTreeMap::new(),
-            flash,
-        }
-    }
-
-    /// Clear out any sprites which are not in the keep list
-    fn clear(&mut self, keep: &[&String]) {
-        let keys = self.sprites.keys().cloned().collect::<Vec<_>>();
-        for sprite in keys {
-            if !keep.contains(&&sprite) {
-                self.sprites.remove(&sprite);
-            }
-        }
-    }
+/// How long a toast message stays on screen
+const TOAST_DURATION: Duration = Duration::from_secs(10);
 
-    /// Prepare all sprites in the config to be rendered
-    async fn prepare(&mut self, keys: &[&String]) {
-        for name in keys {
-            if !self.sprites.contains_key(*name) {
-                if let Some(res) = bake_sprite(self.flash, name).await {
-                    self.sprites.insert((**name).clone(), res);
-                }
-            };
-        }
-    }
+pub type ToastSignal = Signal<CriticalSectionRawMutex, String>;
 
-    async fn get_sprite<'a>(&'a mut self, name: &String, now: Instant) -> Option<tinyqoi::Qoi<'a>> {
-        let sprite = if self.sprites.contains_key(name) {
-            self.sprites.get_mut(name)
-        } else {
-            None
-        };
-        sprite?.get_image(now).ok()
-    }
+/// Short message which is shown on top of the current screen for a while
+pub static TOAST_SIGNAL: ToastSignal = Signal::new();
 
-    fn needs_redraw(&self, now: Instant) -> bool {
-        for (_, sprite) in self.sprites.iter() {
-            if sprite.needs_update(now) {
-                return true;
-            }
-        }
-        false
-    }
+/// Tracks the areas in which the framebuffer being drawn lags behind the screen.
+/// The display task and `hub75_task` take turns with two framebuffers, so every change
+/// drawn into one of them has to be drawn into the other one with the next frame as well
+struct Damage {
+    /// The framebuffer being drawn holds an older screen and has to be drawn completely
+    stale: bool,
+    /// Areas changed with the last frame, which the framebuffer being drawn does not have yet
+    previous: Vec<Rectangle>,
 }
 
-fn make_primitive_style(
-    stroke_color: &Option<String>,
-    stroke_width: &Option<u32>,
-    fill_color: &Option<String>,
-) -> PrimitiveStyle<Color> {
-    let mut style = PrimitiveStyleBuilder::new();
-    if let Some(color) = stroke_color {
-        if let Some(color) = string_to_color(color) {
-            style = style.stroke_color(color);
-        }
+impl Damage {
+    /// The whole screen was drawn, so the other framebuffer is behind everywhere
+    fn full_redraw(&mut self) {
+        self.stale = true;
+        self.previous.clear();
     }
-    if let Some(stroke) = stroke_width {
-        style = style.stroke_width(*stroke);
-    }
-    if let Some(fill) = fill_color {
-        if let Some(fill) = string_to_color(fill) {
-            style = style.fill_color(fill)
-        }
-    }
-    style.build()
-}
 
-async fn render_config(
-    fb: &mut TiledFBType,
-    config: &mut CheckedScreenConfig,
-    sprite_register: &mut SpriteRegister,
-    err_img: &mut BakedResource,
-    now: Instant,
-) {
-    for element in config.screen.elements.iter_mut() {
-        let pos = element.position();
-        match element {
-            interface::Element::Sprite { name, center, .. } => {
-                if let Some(img) = sprite_register.get_sprite(name, now).await {
-                    if let Some(point) = center {
-                        Image::with_center(&img, point.into()).draw(fb).ok();
-                    } else {
-                        Image::new(&img, pos).draw(fb).ok();
-                    }
-                } else if let Ok(img) = err_img.get_image(now) {
-                    if let Some(point) = center {
-                        Image::with_center(&img, point.into()).draw(fb).ok();
-                    } else {
-                        Image::new(&img, pos).draw(fb).ok();
-                    }
-                }
-            }
-            interface::Element::Text {
-                style, text, align, ..
-            } => {
-                if let Some(style) = config.styles.get(style) {
-                    if let Some(align) = align {
-                        Text::with_alignment(text, pos, *style, align.alignment())
-                            .draw(fb)
-                            .ok();
-                    } else {
-                        Text::new(text, pos, *style).draw(fb).ok();
-                    }
-                } else {
-                    error!("Style {style} not found");
-                }
-            }
-            Element::Line {
-                start,
-                end,
-                color,
-                stroke,
-            } => {
-                let style = make_primitive_style(color, stroke, &None);
-                Line::new(start.into(), end.into())
-                    .into_styled(style)
-                    .draw(fb)
-                    .ok();
-            }
-            Element::Polyline {
-                color,
-                stroke,
-                points,
-            } => {
-                let style = make_primitive_style(color, stroke, &None);
-                let points: Vec<Point> = points.iter().map(|p| p.into()).collect();
-                Polyline::new(points.as_slice())
-                    .into_styled(style)
-                    .draw(fb)
-                    .ok();
-            }
-            Element::Rectangle {
-                top_left,
-                size,
-                fill_color,
-                stroke_color,
-                stroke,
-                rounded_corners,
-            } => {
-                let style = make_primitive_style(stroke_color, stroke, fill_color);
-                let rect = Rectangle::new(top_left.into(), size.into());
-                if let Some(corners) = rounded_corners {
-                    let corners = match corners {
-                        RectangleCorners::Uniform(size) => {
-                            CornerRadiiBuilder::new().all(size.into()).build()
-                        }
-                        RectangleCorners::Different {
-                            top_left,
-                            top_right,
-                            bottom_left,
-                            bottom_right,
-                        } => {
-                            let mut builder = CornerRadiiBuilder::new();
-                            if let Some(radius) = top_left {
-                                builder = builder.top_left(radius.into());
-                            }
-                            if let Some(radius) = top_right {
-                                builder = builder.top_right(radius.into());
-                            }
-                            if let Some(radius) = bottom_left {
-                                builder = builder.bottom_left(radius.into());
-                            }
-                            if let Some(radius) = bottom_right {
-                                builder = builder.bottom_right(radius.into());
-                            }
-                            builder.build()
-                        }
-                    };
-                    RoundedRectangle::new(rect, corners)
-                        .into_styled(style)
-                        .draw(fb)
-                        .ok();
-                } else {
-                    rect.into_styled(style).draw(fb).ok();
-                }
-            }
+    /// Areas to draw for the given changes, or None if the whole screen has to be drawn
+    fn areas(&mut self, changed: Vec<Rectangle>) -> Option<Vec<Rectangle>> {
+        let previous = core::mem::replace(&mut self.previous, changed);
+        if core::mem::take(&mut self.stale) {
+            return None;
         }
+        Some(
+            previous
+                .into_iter()
+                .chain(self.previous.iter().copied())
+                .collect(),
+        )
     }
 }
 
-fn must_redraw(cond: bool, is_dirty: &mut bool, fb: &mut TiledFBType) -> bool {
+fn must_redraw<D: DrawTarget<Color = Color>>(cond: bool, is_dirty: &mut bool, fb: &mut D) -> bool {
     if *is_dirty || cond {
         fb.clear(Color::BLACK).ok();
         *is_dirty = true;
@@ -247,8 +81,8 @@ fn must_redraw(cond: bool, is_dirty: &mut bool, fb: &mut TiledFBType) -> bool {
     }
 }
 
-fn draw_connect_screen(
-    fb: &mut TiledFBType,
+fn draw_connect_screen<D: DrawTarget<Color = Color>>(
+    fb: &mut D,
     text_style: MonoTextStyle<'_, Color>,
     display_area: Rectangle,
     wifi: &mut BakedResource,
@@ -256,8 +90,9 @@ fn draw_connect_screen(
     needs_render: &mut bool,
     message: &str,
 ) {
-    if must_redraw(wifi.needs_update(now), needs_render, fb) {
-        if let Ok(img) = wifi.get_image(now) {
+    let now_ms = now.as_millis();
+    if must_redraw(wifi.needs_update(now_ms), needs_render, fb) {
+        if let Ok(img) = wifi.get_image(now_ms) {
             LinearLayout::vertical(
                 Chain::new(Image::new(&img, Point::zero())).append(Text::new(
                     message,
@@ -274,126 +109,288 @@ fn draw_connect_screen(
     }
 }
 
+/// Small badge in the top right corner which marks the shown data as outdated
+fn draw_stale_badge<D: DrawTarget<Color = Color>>(fb: &mut D, display_area: Rectangle) {
+    let text_style = MonoTextStyle::new(&FONT_5X7, Rgb888::WHITE);
+    let text = Text::with_baseline("STALE", Point::zero(), text_style, Baseline::Top);
+    let badge = Rectangle::new(Point::zero(), text.bounding_box().size + Size::new(2, 2)).align_to(
+        &display_area,
+        horizontal::Right,
+        vertical::Top,
+    );
+    badge
+        .into_styled(PrimitiveStyle::with_fill(Rgb888::RED))
+        .draw(fb)
+        .ok();
+    text.align_to(&badge, horizontal::Center, vertical::Center)
+        .draw(fb)
+        .ok();
+}
+
+fn draw_toast<D: DrawTarget<Color = Color>>(fb: &mut D, message: &str, display_area: Rectangle) {
+    let text_style = MonoTextStyle::new(&FONT_5X7, Rgb888::WHITE);
+    let text = Text::with_baseline(message, Point::zero(), text_style, Baseline::Top);
+    let toast = Rectangle::new(Point::zero(), text.bounding_box().size + Size::new(4, 4)).align_to(
+        &display_area,
+        horizontal::Center,
+        vertical::Bottom,
+    );
+    toast
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .fill_color(Rgb888::BLACK)
+                .stroke_color(Rgb888::WHITE)
+                .stroke_width(1)
+                .build(),
+        )
+        .draw(fb)
+        .ok();
+    text.align_to(&toast, horizontal::Center, vertical::Center)
+        .draw(fb)
+        .ok();
+}
+
+fn draw_unreachable_screen<D: DrawTarget<Color = Color>>(
+    fb: &mut D,
+    text_style: MonoTextStyle<'_, Color>,
+    display_area: Rectangle,
+) {
+    Text::new("Server unreachable", Point::zero(), text_style)
+        .align_to(&display_area, horizontal::Center, vertical::Center)
+        .draw(fb)
+        .ok();
+}
+
+/// Text shown below the WIFI logo while the system is not ready
+fn state_message(state: &SystemState) -> String {
+    let message = match state {
+        SystemState::WIFIScanning => String::from("Searching for WIFI"),
+        SystemState::WIFIConnecting(ssid) => format!("Connecting to\n{ssid}"),
+        SystemState::WIFIWaitForIP(ssid) => format!("Joined {ssid}\nWaiting for IP"),
+        SystemState::WIFIConnected(ssid) => format!("Joined {ssid}"),
+        SystemState::Disconnected(ssid) => format!("Lost {ssid}..."),
+        SystemState::Failed => String::from("Failed to connect. Retrying..."),
+        SystemState::Provisioning => {
+            format!("Join WIFI {AP_SSID}\nand open\nhttp://{AP_ADDRESS}")
+        }
+        SystemState::Ready => String::new(),
+    };
+    if RECOVERED.load(Ordering::Relaxed) && !message.is_empty() {
+        format!("{message}\nRecovered from crash")
+    } else {
+        message
+    }
+}
+
 #[task]
 pub async fn display_task(
     rx: &'static FrameBufferExchange,
     tx: &'static FrameBufferExchange,
-    mut fb: &'static mut TiledFBType,
+    mut fb: &'static mut FBType,
     wifi_up: &'static CurrentStateSignal,
-    flash: &'static FlashType,
 ) {
     info!("display_task: starting!");
 
     let mut wifi = get_wifi_sprite();
     let mut dino = get_dino_sprite();
-    let mut err_img = get_no_image_sprite();
+    let mut err_img = no_image_sprite();
 
-    let mut wifi_state = SystemState::WIFIConnecting;
+    let mut wifi_state = SystemState::WIFIScanning;
+    let mut status_message = state_message(&wifi_state);
 
     let wifi_text_style = MonoTextStyleBuilder::new()
         .font(&FONT_5X7)
         .text_color(Rgb888::YELLOW)
         .build();
 
-    let display_area = fb.bounding_box();
+    let display_area = Rectangle::new(Point::zero(), topology::SIZE);
 
     let mut display_config = None;
-    let mut sprite_register = SpriteRegister::new(flash);
     let mut needs_render = true;
+    let mut screenshot = None;
+    // Time of the last config or heartbeat, to detect when the server stopped sending updates
+    let mut last_update = Instant::now();
+    let mut is_stale = false;
+    // Message currently shown on top of the screen and when it disappears again
+    let mut toast: Option<(String, Instant)> = None;
+    let mut was_streaming = false;
+    // Areas covered by the elements of the config, in the order of the elements
+    let mut bounds = Vec::new();
+    let mut damage = Damage {
+        stale: true,
+        previous: Vec::new(),
+    };
+    // Only some areas of the screen were drawn again
+    let mut partial_render = false;
 
     loop {
         if wifi_up.signaled() {
             wifi_state = wifi_up.wait().await;
+            status_message = state_message(&wifi_state);
+            needs_render = true;
+        }
+        if SCREENSHOT_REQUEST.signaled() {
+            SCREENSHOT_REQUEST.wait().await;
+            // force a full redraw so the whole screen ends up in the screenshot
+            screenshot = Some(Canvas::new(display_area.size, BITS));
             needs_render = true;
         }
         let now = Instant::now();
+        let now_ms = now.as_millis();
+        let panel = &mut PanelTarget::new(&mut *fb);
+        let target = &mut MirroredTarget::new(panel, screenshot.as_mut());
         match wifi_state {
-            SystemState::Ready | SystemState::WIFIConnected => {
+            SystemState::Ready | SystemState::WIFIConnected(_) => {
                 SYSTEM_IS_UP.store(true, Ordering::Relaxed);
+                // The crash hint is shown once more as toast on the ready screen, then it is gone
+                if RECOVERED.swap(false, Ordering::Relaxed) {
+                    toast = Some((String::from("Recovered from crash"), now + TOAST_DURATION));
+                    needs_render = true;
+                }
                 if DISPLAY_CONFIG_SIGNAL.signaled() {
                     display_config = DISPLAY_CONFIG_SIGNAL.wait().await;
                     if let Some(ref conf) = display_config {
-                        let keep: Vec<_> = conf
+                        let sprites: Vec<_> = conf
                             .screen
                             .elements
                             .iter()
                             .filter_map(|e| {
-                                if let Element::Sprite { name, .. } = e {
-                                    Some(name)
+                                if let Element::Sprite { name, format, .. } = e {
+                                    Some((name, *format))
                                 } else {
                                     None
                                 }
                             })
                             .collect();
-                        sprite_register.clear(keep.as_slice());
-                        sprite_register.prepare(keep.as_slice()).await;
+                        SPRITE_CACHE.lock().await.prefetch(sprites.as_slice());
+                    }
+                    last_update = now;
+                    needs_render = true;
+                }
+                if HEARTBEAT_SIGNAL.signaled() {
+                    HEARTBEAT_SIGNAL.reset();
+                    last_update = now;
+                }
+                if TOAST_SIGNAL.signaled() {
+                    toast = Some((TOAST_SIGNAL.wait().await, now + TOAST_DURATION));
+                    needs_render = true;
+                }
+                if toast.as_ref().is_some_and(|(_, until)| now >= *until) {
+                    toast = None;
+                    needs_render = true;
+                }
+                let stale = display_config.as_ref().is_some_and(|conf| {
+                    conf.max_age.is_some_and(|max_age| {
+                        now.duration_since(last_update) > Duration::from_secs(max_age.into())
+                    })
+                });
+                if stale != is_stale {
+                    if stale {
+                        warn!("No update received in time, the shown data is outdated");
                     } else {
-                        sprite_register.clear(&[]);
+                        info!("Received update, the shown data is current again");
                     }
+                    is_stale = stale;
                     needs_render = true;
                 }
-                if let Some(ref mut conf) = display_config {
-                    if must_redraw(sprite_register.needs_redraw(now), &mut needs_render, fb) {
-                        render_config(fb, conf, &mut sprite_register, &mut err_img, now).await;
+                let fallback = display_config
+                    .as_ref()
+                    .filter(|_| is_stale)
+                    .map(|conf| conf.stale_fallback);
+                DIMMED.store(fallback == Some(StaleFallback::Dim), Ordering::Relaxed);
+                // A running pixel stream pre-empts the configured screen
+                let streaming = is_streaming(now);
+                if streaming != was_streaming {
+                    if !streaming {
+                        info!("Pixel stream stopped, showing the configured screen again");
                     }
-                } else if must_redraw(dino.needs_update(now), &mut needs_render, fb) {
-                    if let Ok(img) = dino.get_image(now) {
-                        Image::new(&img, Point::zero()).draw(fb).ok();
+                    was_streaming = streaming;
+                    needs_render = true;
+                }
+                if streaming {
+                    if must_redraw(STREAM_FRAME.signaled(), &mut needs_render, target) {
+                        STREAM_FRAME.reset();
+                        draw_stream(target).await;
+                    }
+                } else if let Some(ref conf) = display_config {
+                    if fallback == Some(StaleFallback::Unreachable) {
+                        if must_redraw(false, &mut needs_render, target) {
+                            draw_unreachable_screen(target, wifi_text_style, display_area);
+                        }
+                    } else {
+                        let mut sprite_cache = SPRITE_CACHE.lock().await;
+                        if SPRITE_LOADED.signaled() {
+                            // A loaded sprite replaces its placeholder, which has another size
+                            SPRITE_LOADED.reset();
+                            needs_render = true;
+                        }
+                        let areas = if needs_render {
+                            None
+                        } else {
+                            let changed = animated_areas(conf, &bounds, &*sprite_cache, now_ms);
+                            if changed.is_empty() {
+                                Some(Vec::new())
+                            } else {
+                                partial_render = true;
+                                damage.areas(changed)
+                            }
+                        };
+                        let mut render_all = areas.is_none();
+                        for area in areas.unwrap_or_default() {
+                            let (cache, err_img) = (&mut *sprite_cache, &mut err_img);
+                            if !render_area(target, area, conf, cache, err_img, now_ms, &bounds) {
+                                // The other framebuffer lags behind everywhere as well
+                                needs_render = true;
+                                render_all = true;
+                                break;
+                            }
+                        }
+                        if render_all {
+                            target.clear(Color::BLACK).ok();
+                            let cache = &mut *sprite_cache;
+                            cache.next_frame();
+                            render_config(target, conf, cache, &mut err_img, now_ms, &mut bounds);
+                        }
+                        if fallback == Some(StaleFallback::Badge)
+                            && (needs_render || partial_render)
+                        {
+                            draw_stale_badge(target, display_area);
+                        }
+                    }
+                } else if must_redraw(dino.needs_update(now_ms), &mut needs_render, target) {
+                    if let Ok(img) = dino.get_image(now_ms) {
+                        Image::new(&img, Point::zero()).draw(target).ok();
                     }
                 }
+                // The changed areas may have been drawn over the toast, so it is drawn again
+                if let (true, Some((message, _))) = (needs_render || partial_render, &toast) {
+                    draw_toast(target, message, display_area);
+                }
             }
-            SystemState::WIFIConnecting => {
-                SYSTEM_IS_UP.store(false, Ordering::Relaxed);
-                draw_connect_screen(
-                    fb,
-                    wifi_text_style,
-                    display_area,
-                    &mut wifi,
-                    now,
-                    &mut needs_render,
-                    "Connecting to WIFI",
-                );
-            }
-            SystemState::Disconnected => {
-                SYSTEM_IS_UP.store(false, Ordering::Relaxed);
-                draw_connect_screen(
-                    fb,
-                    wifi_text_style,
-                    display_area,
-                    &mut wifi,
-                    now,
-                    &mut needs_render,
-                    "Lost WIFI...",
-                );
-            }
-            SystemState::Failed => {
-                SYSTEM_IS_UP.store(false, Ordering::Relaxed);
-                draw_connect_screen(
-                    fb,
-                    wifi_text_style,
-                    display_area,
-                    &mut wifi,
-                    now,
-                    &mut needs_render,
-                    "Failed to connect. Retrying...",
-                );
-            }
-            SystemState::WIFIWaitForIP => {
+            _ => {
                 SYSTEM_IS_UP.store(false, Ordering::Relaxed);
+                DIMMED.store(false, Ordering::Relaxed);
                 draw_connect_screen(
-                    fb,
+                    target,
                     wifi_text_style,
                     display_area,
                     &mut wifi,
                     now,
                     &mut needs_render,
-                    "Waiting for IP",
+                    &status_message,
                 );
             }
         }
         // only exchange the framebuffers if there is something new to render
-        if needs_render {
+        if needs_render || partial_render {
+            if needs_render {
+                damage.full_redraw();
+            }
             needs_render = false;
+            partial_render = false;
+            if let Some(canvas) = screenshot.take() {
+                SCREENSHOT_RESULT.signal(QoiImage(canvas.to_qoi()));
+            }
             // send the frame buffer to be rendered
             tx.signal(fb);
             // get the next frame buffer
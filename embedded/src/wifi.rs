@@ -1,26 +1,254 @@
+use crate::flash::{self, FlashError, FlashResult, Namespace};
+use crate::provisioning::{AP_SSID, PROVISIONED};
 use crate::CONFIG;
-use embassy_net::Runner;
+use alloc::{string::String, vec::Vec};
+use core::cell::RefCell;
+use core::net::Ipv4Addr;
+use core::sync::atomic::{AtomicI32, Ordering};
+use ekv::ReadError;
+use embassy_futures::select::{select, Either};
+use embassy_net::{DhcpConfig, Ipv4Cidr, Runner, StaticConfigV4};
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Timer};
 use esp_wifi::wifi::{
-    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState,
+    AccessPointConfiguration, ClientConfiguration, Configuration, WifiController, WifiDevice,
+    WifiEvent, WifiState,
 };
+use interface::{KnownNetwork, WifiNetwork, WifiStatus};
 use log::{error, info};
 
-const SSID: &str = CONFIG.wifi.ssid;
-const PASSWORD: &str = CONFIG.wifi.password;
+/// Number of failed connection rounds after which the provisioning access point is opened
+const MAX_CONNECT_ATTEMPTS: u32 = CONFIG.wifi.max_connect_attempts as u32;
+
+/// How long the provisioning access point stays open before the known networks are retried
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum number of access points to consider when scanning for known networks
+const MAX_SCAN_RESULTS: usize = 32;
+
+const NETWORKS_KEY: &str = "wifi_networks";
+
+/// Name the display uses for DHCP and mDNS
+pub const HOSTNAME: &str = CONFIG.network.hostname;
+
+/// How often the signal strength is read while connected
+const RSSI_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Signal strength of the current connection in dBm, 0 while disconnected
+pub static RSSI: AtomicI32 = AtomicI32::new(0);
 
 pub enum SystemState {
-    WIFIConnecting,
-    WIFIWaitForIP,
-    WIFIConnected,
-    Disconnected,
+    WIFIScanning,
+    WIFIConnecting(String),
+    WIFIWaitForIP(String),
+    WIFIConnected(String),
+    Disconnected(String),
     Failed,
+    Provisioning,
     Ready,
 }
 
 pub type CurrentStateSignal = Signal<CriticalSectionRawMutex, SystemState>;
 
+type NetworkList = Mutex<CriticalSectionRawMutex, RefCell<Vec<WifiNetwork>>>;
+type ConnectedNetwork = Mutex<CriticalSectionRawMutex, RefCell<Option<String>>>;
+
+/// All networks the display knows the credentials of
+static KNOWN_NETWORKS: NetworkList = Mutex::new(RefCell::new(Vec::new()));
+/// SSID of the network the display is currently connected to
+static CONNECTED_NETWORK: ConnectedNetwork = Mutex::new(RefCell::new(None));
+
+pub fn connected_network() -> Option<String> {
+    CONNECTED_NETWORK.lock(|network| network.borrow().clone())
+}
+
+fn set_connected_network(ssid: Option<String>) {
+    CONNECTED_NETWORK.lock(|network| *network.borrow_mut() = ssid);
+}
+
+pub fn wifi_status() -> WifiStatus {
+    WifiStatus {
+        connected: connected_network(),
+        networks: KNOWN_NETWORKS.lock(|networks| {
+            networks
+                .borrow()
+                .iter()
+                .map(|network| KnownNetwork {
+                    ssid: network.ssid.clone(),
+                    priority: network.priority,
+                })
+                .collect()
+        }),
+    }
+}
+
+pub fn validate_network(network: &WifiNetwork) -> Result<(), &'static str> {
+    if network.ssid.is_empty() || network.ssid.len() > 32 {
+        return Err("The SSID has to be between 1 and 32 characters long");
+    }
+    if !network.password.is_empty() && !(8..=64).contains(&network.password.len()) {
+        return Err("The password has to be empty or between 8 and 64 characters long");
+    }
+    Ok(())
+}
+
+fn parse_address(address: &str) -> Option<Ipv4Addr> {
+    (!address.is_empty()).then(|| {
+        address
+            .parse()
+            .expect("build.rs checks the addresses of the network config")
+    })
+}
+
+/// Build the IP configuration of the station interface from config.toml.
+/// Uses DHCP unless a static IP is configured. The addresses are checked by build.rs,
+/// so a broken network config fails the build instead of the boot.
+pub fn network_config() -> embassy_net::Config {
+    let Some((address, prefix_len)) = CONFIG.network.static_ip.split_once('/') else {
+        let mut dhcp = DhcpConfig::default();
+        dhcp.hostname = Some(HOSTNAME.try_into().expect("Hostname is too long"));
+        return embassy_net::Config::dhcpv4(dhcp);
+    };
+    let mut dns_servers = heapless::Vec::new();
+    if let Some(dns) = parse_address(CONFIG.network.dns_server) {
+        dns_servers.push(dns).ok();
+    }
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(
+            parse_address(address).expect("build.rs checks the static IP"),
+            prefix_len
+                .parse()
+                .expect("build.rs checks the prefix length of the static IP"),
+        ),
+        gateway: parse_address(CONFIG.network.gateway),
+        dns_servers,
+    })
+}
+
+/// Load the stored networks. The network from config.toml is always added if there is one
+async fn load_networks() {
+    let mut networks = Vec::new();
+    match flash::read(Namespace::Settings, NETWORKS_KEY.into()).await {
+        Ok(data) => match postcard::from_bytes::<Vec<WifiNetwork>>(&data) {
+            Ok(stored) => networks = stored,
+            Err(e) => error!("Stored WIFI networks are corrupt: {e}"),
+        },
+        Err(FlashError::ReadErr(ReadError::KeyNotFound)) => {}
+        Err(e) => error!("Failed to read stored WIFI networks: {e:?}"),
+    }
+    if !CONFIG.wifi.ssid.is_empty() && !networks.iter().any(|n| n.ssid == CONFIG.wifi.ssid) {
+        networks.push(WifiNetwork {
+            ssid: CONFIG.wifi.ssid.into(),
+            password: CONFIG.wifi.password.into(),
+            priority: 0,
+        });
+    }
+    info!("Loaded {} known WIFI networks", networks.len());
+    KNOWN_NETWORKS.lock(|known| *known.borrow_mut() = networks);
+}
+
+async fn store_networks(networks: Vec<WifiNetwork>) -> FlashResult<()> {
+    let data = postcard::to_allocvec(&networks).expect("Failed to serialize WIFI networks");
+    flash::store(Namespace::Settings, NETWORKS_KEY.into(), data).await?;
+    KNOWN_NETWORKS.lock(|known| *known.borrow_mut() = networks);
+    Ok(())
+}
+
+/// Add a network to the known networks, replacing one with the same SSID
+pub async fn add_network(network: WifiNetwork) -> FlashResult<()> {
+    let mut networks = KNOWN_NETWORKS.lock(|known| known.borrow().clone());
+    networks.retain(|n| n.ssid != network.ssid);
+    networks.push(network);
+    store_networks(networks).await
+}
+
+/// Remove a network from the known networks. Returns false if there was no such network
+pub async fn remove_network(ssid: &str) -> FlashResult<bool> {
+    let mut networks = KNOWN_NETWORKS.lock(|known| known.borrow().clone());
+    let count = networks.len();
+    networks.retain(|n| n.ssid != ssid);
+    if networks.len() == count {
+        return Ok(false);
+    }
+    store_networks(networks).await?;
+    Ok(true)
+}
+
+/// Scan for access points and return the known networks which are in range.
+/// The networks are ordered by priority first and signal strength second.
+async fn scan_known_networks(controller: &mut WifiController<'static>) -> Vec<WifiNetwork> {
+    let access_points = match controller.scan_n_async(MAX_SCAN_RESULTS).await {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            error!("Failed to scan for WIFI networks: {e:?}");
+            return Vec::new();
+        }
+    };
+    let mut candidates: Vec<_> = KNOWN_NETWORKS.lock(|known| {
+        known
+            .borrow()
+            .iter()
+            .filter_map(|network| {
+                access_points
+                    .iter()
+                    .filter(|ap| ap.ssid.as_str() == network.ssid)
+                    .map(|ap| ap.signal_strength)
+                    .max()
+                    .map(|rssi| (network.clone(), rssi))
+            })
+            .collect()
+    });
+    candidates
+        .sort_by(|(a, a_rssi), (b, b_rssi)| b.priority.cmp(&a.priority).then(b_rssi.cmp(a_rssi)));
+    for (network, rssi) in candidates.iter() {
+        info!(
+            "Found known network '{}' (priority {}, {rssi}dBm)",
+            network.ssid, network.priority
+        );
+    }
+    candidates.into_iter().map(|(network, _)| network).collect()
+}
+
+/// Open an access point and wait until a network is entered on the captive portal.
+///
+/// If there are already known networks which just failed to connect, the access point is
+/// closed again after [`PROVISIONING_TIMEOUT`] so that a temporary outage of the network
+/// does not leave the display stuck in provisioning mode.
+async fn provision(
+    controller: &mut WifiController<'static>,
+    system_state: &'static CurrentStateSignal,
+    has_networks: bool,
+) {
+    if matches!(controller.is_started(), Ok(true)) {
+        controller.stop_async().await.ok();
+    }
+    let ap_config = Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: AP_SSID.into(),
+        ..Default::default()
+    });
+    controller.set_configuration(&ap_config).unwrap();
+    info!("Starting provisioning access point '{AP_SSID}'");
+    controller.start_async().await.unwrap();
+    system_state.signal(SystemState::Provisioning);
+
+    PROVISIONED.reset();
+    let provisioned = if has_networks {
+        with_timeout(PROVISIONING_TIMEOUT, PROVISIONED.wait())
+            .await
+            .is_ok()
+    } else {
+        PROVISIONED.wait().await;
+        true
+    };
+    if provisioned {
+        // Give the portal some time to send out the response
+        Timer::after(Duration::from_secs(1)).await;
+    }
+    info!("Stopping provisioning access point");
+    controller.stop_async().await.ok();
+}
+
 #[embassy_executor::task]
 pub async fn connection(
     mut controller: WifiController<'static>,
@@ -28,42 +256,91 @@ pub async fn connection(
 ) {
     info!("start connection task");
     info!("Device capabilities: {:?}", controller.capabilities());
+    load_networks().await;
+    let mut failed_attempts = 0;
     loop {
         if esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            system_state.signal(SystemState::Disconnected);
+            // wait until we're no longer connected, reading the signal strength in between
+            loop {
+                match select(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    Timer::after(RSSI_INTERVAL),
+                )
+                .await
+                {
+                    Either::First(_) => break,
+                    Either::Second(_) => {
+                        if let Ok(rssi) = controller.rssi() {
+                            RSSI.store(rssi, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+            RSSI.store(0, Ordering::Relaxed);
+            let ssid = connected_network().unwrap_or_default();
+            set_connected_network(None);
+            system_state.signal(SystemState::Disconnected(ssid));
             Timer::after(Duration::from_millis(5000)).await
         }
 
+        let has_networks = KNOWN_NETWORKS.lock(|known| !known.borrow().is_empty());
+        if !has_networks || failed_attempts >= MAX_CONNECT_ATTEMPTS {
+            info!("No known WIFI network could be joined");
+            provision(&mut controller, system_state, has_networks).await;
+            failed_attempts = 0;
+            continue;
+        }
+
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.into(),
-                password: PASSWORD.into(),
-                ..Default::default()
-            });
+            let client_config = Configuration::Client(ClientConfiguration::default());
             controller.set_configuration(&client_config).unwrap();
             info!("Starting wifi");
             controller.start_async().await.unwrap();
             info!("Wifi started!");
         }
-        info!("About to connect...");
 
-        match controller.connect_async().await {
-            Ok(_) => {
-                info!("Wifi connected!");
-                system_state.signal(SystemState::WIFIConnected);
-            }
-            Err(e) => {
-                error!("Failed to connect to wifi: {e:?}");
-                system_state.signal(SystemState::Failed);
-                Timer::after(Duration::from_millis(5000)).await
+        system_state.signal(SystemState::WIFIScanning);
+        let candidates = scan_known_networks(&mut controller).await;
+        if candidates.is_empty() {
+            info!("No known WIFI network in range");
+        }
+
+        let mut connected = false;
+        for network in candidates {
+            let client_config = Configuration::Client(ClientConfiguration {
+                ssid: network.ssid.as_str().into(),
+                password: network.password.as_str().into(),
+                ..Default::default()
+            });
+            controller.set_configuration(&client_config).unwrap();
+            info!("About to connect to '{}'...", network.ssid);
+            system_state.signal(SystemState::WIFIConnecting(network.ssid.clone()));
+
+            match controller.connect_async().await {
+                Ok(_) => {
+                    info!("Wifi connected!");
+                    set_connected_network(Some(network.ssid.clone()));
+                    system_state.signal(SystemState::WIFIConnected(network.ssid));
+                    connected = true;
+                    break;
+                }
+                Err(e) => {
+                    error!("Failed to connect to '{}': {e:?}", network.ssid);
+                }
             }
         }
+
+        if connected {
+            failed_attempts = 0;
+        } else {
+            failed_attempts += 1;
+            system_state.signal(SystemState::Failed);
+            Timer::after(Duration::from_millis(5000)).await
+        }
     }
 }
 
-#[embassy_executor::task]
+#[embassy_executor::task(pool_size = 2)]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
@@ -1,5 +1,8 @@
 use super::TextStyle;
-use crate::{Alignment, Configuration, Element, FontName, GlobalStylesType, Point, Screen, Size};
+use crate::{
+    Alignment, Configuration, Element, FontName, GlobalStylesType, Point, Screen, Size,
+    StaleFallback,
+};
 use alloc::collections::btree_map::BTreeMap;
 use alloc::string::String;
 use embedded_graphics::mono_font::iso_8859_1::{
@@ -12,6 +15,7 @@ use embedded_graphics::{
     mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder},
     pixelcolor::Rgb888,
 };
+#[cfg(feature = "picoserve")]
 use picoserve::response::ErrorWithStatusCode;
 use profont::{
     PROFONT_7_POINT, PROFONT_9_POINT, PROFONT_10_POINT, PROFONT_12_POINT, PROFONT_14_POINT,
@@ -24,6 +28,9 @@ pub type BuiltTextStyles = BTreeMap<String, MonoTextStyle<'static, Rgb888>>;
 pub struct CheckedScreenConfig {
     pub screen: Screen,
     pub styles: BuiltTextStyles,
+    /// Seconds after which the config is outdated without an update
+    pub max_age: Option<u32>,
+    pub stale_fallback: StaleFallback,
 }
 
 impl CheckedScreenConfig {
@@ -35,37 +42,43 @@ impl CheckedScreenConfig {
         } else if let Some(screen) = config.screens.into_iter().next() {
             let styles = build_styles(config.text_styles)?;
             // TODO: Implement sanity checks to confirm all styles are defined and all sprites are in flash
-            Ok(Self { screen, styles })
+            Ok(Self {
+                screen,
+                styles,
+                max_age: config.max_age,
+                stale_fallback: config.stale_fallback,
+            })
         } else {
             Err(ScreenBuildError::CouldNotGetScreen)
         }
     }
 }
 
-#[derive(Error, Debug, ErrorWithStatusCode)]
+#[derive(Error, Debug)]
+#[cfg_attr(feature = "picoserve", derive(ErrorWithStatusCode))]
 pub enum ScreenBuildError {
     #[error("The color string `{0}` was invalid")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     InvalidColorString(String),
 
     #[error("Could not get screen from config")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     CouldNotGetScreen,
 
     #[error("Config must contain at least one screen")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     NoScreen,
 
     #[error("Only configs using a single screen are supported for now")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     TooManyScreens,
 
     #[error("Configuration uses style `{0}` but this style is not defined")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     MissingStyle(String),
 
     #[error("Configuration uses sprite `{0}` but this sprite is not present in flash")]
-    #[status_code(BAD_REQUEST)]
+    #[cfg_attr(feature = "picoserve", status_code(BAD_REQUEST))]
     MissingSprite(String),
 }
 
@@ -115,21 +128,22 @@ impl FontName {
 
 impl TextStyle {
     pub fn build(self) -> Result<MonoTextStyle<'static, Rgb888>, ScreenBuildError> {
-        let style: MonoTextStyleBuilder<'static, Rgb888> = MonoTextStyleBuilder::new()
+        let mut style: MonoTextStyleBuilder<'static, Rgb888> = MonoTextStyleBuilder::new()
             .text_color(string_to_color(&self.text_color).ok_or(
                 ScreenBuildError::InvalidColorString(self.text_color.clone()),
             )?)
             .font(self.font.build());
         if let Some(color) = &self.background_color {
-            style.background_color(string_to_color(color).ok_or(
-                ScreenBuildError::InvalidColorString(self.text_color.clone()),
-            )?);
+            style = style.background_color(
+                string_to_color(color)
+                    .ok_or(ScreenBuildError::InvalidColorString(color.clone()))?,
+            );
         }
         if let Some(true) = self.strikethrough {
-            style.strikethrough();
+            style = style.strikethrough();
         }
         if let Some(true) = self.underline {
-            style.underline();
+            style = style.underline();
         }
         Ok(style.build())
     }
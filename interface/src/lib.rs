@@ -12,8 +12,13 @@ use serde::{Deserialize, Serialize};
 #[cfg(feature = "server")]
 extern crate std;
 
-#[cfg(not(feature = "server"))]
+#[cfg(feature = "render")]
 pub mod embedded;
+pub mod mqtt;
+#[cfg(feature = "render")]
+pub mod render;
+#[cfg(feature = "render")]
+pub mod sprite;
 
 pub type GlobalStylesType = BTreeMap<String, TextStyle>;
 
@@ -93,6 +98,8 @@ pub enum Element {
         name: String,
         /// Center the sprite around a given point
         center: Option<Point>,
+        /// How the frames are kept in RAM. The default of the display is used if not set
+        format: Option<FrameFormat>,
     },
     /// Draw a line
     Line {
@@ -129,6 +136,19 @@ pub enum Element {
     },
 }
 
+/// Representation of sprite frames in the RAM of the display, trading memory for speed
+#[derive(Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+#[cfg_attr(feature = "server", derive(Serialize, JsonSchema))]
+pub enum FrameFormat {
+    /// Keep the compressed QOI images and decode them whenever they are drawn. Uses the least memory
+    Qoi,
+    /// Decode the frames once into 24 bit pixels. Fastest to draw, but uses 3 bytes per pixel
+    Rgb,
+    /// Decode the frames once, reduce the colors to the color depth of the panel and run
+    /// length encode them. Fast to draw and small for sprites with areas of a single color
+    Rle,
+}
+
 #[derive(Deserialize, Debug, PartialEq)]
 #[cfg_attr(feature = "server", derive(Serialize, JsonSchema))]
 pub enum RectangleCorners {
@@ -235,6 +255,7 @@ impl Element {
             name,
             position,
             center: None,
+            format: None,
         }
     }
 
@@ -442,6 +463,19 @@ impl Screen {
     }
 }
 
+/// What the display does once its configuration is older than `max_age`
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+#[cfg_attr(feature = "server", derive(Serialize, JsonSchema))]
+pub enum StaleFallback {
+    /// Keep showing the configuration with reduced brightness
+    Dim,
+    /// Keep showing the configuration with a "stale" badge in the top right corner
+    #[default]
+    Badge,
+    /// Replace the configuration with a "server unreachable" message
+    Unreachable,
+}
+
 #[derive(Deserialize, Debug, PartialEq)]
 #[cfg_attr(feature = "server", derive(Serialize, JsonSchema))]
 pub struct Configuration {
@@ -449,6 +483,13 @@ pub struct Configuration {
     pub screens: Vec<Screen>,
     /// Map of text styles
     pub text_styles: GlobalStylesType,
+    /// Seconds after which the configuration is outdated unless a new configuration or
+    /// heartbeat arrives. The configuration never gets outdated if not set.
+    #[serde(default)]
+    pub max_age: Option<u32>,
+    /// How to show that the configuration is outdated
+    #[serde(default)]
+    pub stale_fallback: StaleFallback,
 }
 
 impl Configuration {
@@ -456,9 +497,17 @@ impl Configuration {
         Self {
             screens,
             text_styles: GlobalStylesType::new(),
+            max_age: None,
+            stale_fallback: StaleFallback::default(),
         }
     }
 
+    pub fn with_max_age(mut self, max_age: Option<u32>, stale_fallback: StaleFallback) -> Self {
+        self.max_age = max_age;
+        self.stale_fallback = stale_fallback;
+        self
+    }
+
     pub fn add_style(mut self, name: &str, style: TextStyle) -> Self {
         self.text_styles.insert(name.to_string(), style);
         self
@@ -479,3 +528,517 @@ impl Resource {
         }
     }
 }
+
+/// Details about a [`Resource`] stored in the flash of the display
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResourceInfo {
+    /// Number of frames of the sprite
+    pub frame_count: u32,
+    /// Time each frame is displayed for in ms
+    pub frame_time_ms: u16,
+    /// Width of the first frame in pixels
+    pub width: u32,
+    /// Height of the first frame in pixels
+    pub height: u32,
+}
+
+/// A single entry of the flash storage of the display
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoredItem {
+    /// Key the value is stored under
+    pub key: String,
+    /// Size of the stored value in bytes
+    pub size: u32,
+    /// Resource details. None if the value could not be parsed as a [`Resource`]
+    pub resource: Option<ResourceInfo>,
+}
+
+/// Content and usage of the flash storage of the display
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StorageInfo {
+    /// All entries currently stored
+    pub items: Vec<StoredItem>,
+    /// Number of bytes used by all keys and values
+    pub used_bytes: u32,
+    /// Total capacity of the storage in bytes
+    pub total_bytes: u32,
+}
+
+/// State of an interrupted sprite upload on the display
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadProgress {
+    /// Number of bytes which are stored. The upload can be resumed at this offset
+    pub offset: u32,
+}
+
+/// Counters of the sprite cache of the display since boot
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpriteCacheStats {
+    /// Sprite lookups which were drawn from RAM
+    pub hits: u32,
+    /// Sprite lookups which had to wait for the sprite to be loaded from flash
+    pub misses: u32,
+    /// Sprites which were dropped to make room for others
+    pub evictions: u32,
+    /// Bytes of sprite data currently kept in RAM
+    pub used_bytes: u32,
+    /// Bytes of sprite data which may be kept in RAM
+    pub budget_bytes: u32,
+}
+
+/// Credentials of a WIFI network the display is allowed to join
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+    /// Networks with a higher priority are preferred, regardless of their signal strength
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// A known WIFI network as reported by the display, without its password
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    pub priority: u8,
+}
+
+/// WIFI connection state of the display
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WifiStatus {
+    /// SSID of the network the display is currently connected to
+    pub connected: Option<String>,
+    /// All networks the display knows the credentials of
+    pub networks: Vec<KnownNetwork>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+}
+
+/// Local time of day with minute precision
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+}
+
+impl TimeOfDay {
+    /// Minutes since midnight
+    pub fn minutes(&self) -> u16 {
+        self.hour as u16 * 60 + self.minute as u16
+    }
+}
+
+/// Panel state the display switches to during a time range on some weekdays
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleEntry {
+    /// Days on which the range starts. Applies to every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub start: TimeOfDay,
+    /// End of the range. If it is before the start the range continues on the next day
+    pub end: TimeOfDay,
+    /// Whether the panel is on during the range
+    pub on: bool,
+    /// Brightness in % during the range. The configured initial brightness is used if not set
+    #[serde(default)]
+    pub brightness: Option<u8>,
+}
+
+impl ScheduleEntry {
+    /// Whether the entry covers the given minute of the day on the given weekday (0 is Monday)
+    pub fn applies(&self, weekday: usize, minute: u16) -> bool {
+        let starts_on = |day: usize| self.days.is_empty() || self.days.contains(&Weekday::ALL[day]);
+        let (start, end) = (self.start.minutes(), self.end.minutes());
+        if start <= end {
+            starts_on(weekday) && (start..end).contains(&minute)
+        } else {
+            // The range started on the previous day and continues past midnight
+            (starts_on(weekday) && minute >= start)
+                || (starts_on((weekday + 6) % 7) && minute < end)
+        }
+    }
+}
+
+/// Rule by which the local time is one hour ahead during the summer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DaylightSaving {
+    /// The UTC offset never changes
+    #[default]
+    None,
+    /// From the last Sunday of March to the last Sunday of October, switching at 01:00 UTC
+    Eu,
+    /// From the second Sunday of March to the first Sunday of November, switching at 02:00 local time
+    Us,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Year of the given number of days since 1970-01-01
+fn year_from_days(days: i64) -> i64 {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // The era starts in March, so January and February belong to the next year
+    let march_based_month = (5 * day_of_year + 2) / 153;
+    era * 400 + year_of_era + (march_based_month >= 10) as i64
+}
+
+/// Day of the week with 0 for Monday. 1970-01-01 was a Thursday
+fn weekday_from_days(days: i64) -> i64 {
+    (days + 3).rem_euclid(7)
+}
+
+/// Day of the n-th Sunday of a month, counted from 1
+fn nth_sunday(year: i64, month: i64, n: i64) -> i64 {
+    let first = days_from_civil(year, month, 1);
+    first + (6 - weekday_from_days(first)).rem_euclid(7) + 7 * (n - 1)
+}
+
+/// Day of the last Sunday of a month
+fn last_sunday(year: i64, month: i64) -> i64 {
+    let last = days_from_civil(year + month / 12, month % 12 + 1, 1) - 1;
+    last - (weekday_from_days(last) + 1) % 7
+}
+
+impl DaylightSaving {
+    /// Whether daylight saving time is in effect at the given unix time,
+    /// with `utc_offset_minutes` as offset of the standard time
+    pub fn in_effect(self, unix_time: i64, utc_offset_minutes: i64) -> bool {
+        const DAY: i64 = 24 * 60 * 60;
+        const HOUR: i64 = 60 * 60;
+        let year = year_from_days(unix_time.div_euclid(DAY));
+        let (start, end) = match self {
+            DaylightSaving::None => return false,
+            DaylightSaving::Eu => (
+                last_sunday(year, 3) * DAY + HOUR,
+                last_sunday(year, 10) * DAY + HOUR,
+            ),
+            DaylightSaving::Us => {
+                let offset = utc_offset_minutes * 60;
+                (
+                    nth_sunday(year, 3, 2) * DAY + 2 * HOUR - offset,
+                    // The switch back happens at 02:00 of the daylight saving time
+                    nth_sunday(year, 11, 1) * DAY + 2 * HOUR - offset - HOUR,
+                )
+            }
+        };
+        (start..end).contains(&unix_time)
+    }
+}
+
+/// Times at which the display switches the panel on and off or changes its brightness.
+/// Outside of all entries the panel is on with the initial brightness.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Schedule {
+    /// Offset of the local standard time to UTC in minutes, e.g. 60 for CET
+    #[serde(default)]
+    pub utc_offset_minutes: i16,
+    /// Rule which moves the local time one hour ahead during the summer
+    #[serde(default)]
+    pub daylight_saving: DaylightSaving,
+    /// If multiple entries apply at the same time the last one wins
+    pub entries: Vec<ScheduleEntry>,
+}
+
+impl Schedule {
+    /// Check the offset, times and brightness values before the schedule is used
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("The UTC offset has to be between -14 and +14 hours");
+        }
+        for entry in self.entries.iter() {
+            if [entry.start, entry.end]
+                .iter()
+                .any(|time| time.hour > 23 || time.minute > 59)
+            {
+                return Err("Times have to be between 00:00 and 23:59");
+            }
+            if entry.brightness.is_some_and(|brightness| brightness > 100) {
+                return Err("The brightness has to be between 0 and 100");
+            }
+        }
+        Ok(())
+    }
+
+    /// Offset of the local time to UTC in minutes at the given unix time
+    pub fn utc_offset_at(&self, unix_time: i64) -> i64 {
+        let offset = self.utc_offset_minutes as i64;
+        if self.daylight_saving.in_effect(unix_time, offset) {
+            offset + 60
+        } else {
+            offset
+        }
+    }
+
+    /// Entry which decides the panel state at the given unix time. None if no entry applies
+    pub fn active_entry(&self, unix_time: i64) -> Option<&ScheduleEntry> {
+        const DAY: i64 = 24 * 60 * 60;
+        let local_time = unix_time + self.utc_offset_at(unix_time) * 60;
+        let minute = (local_time.rem_euclid(DAY) / 60) as u16;
+        let weekday = weekday_from_days(local_time.div_euclid(DAY)) as usize;
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.applies(weekday, minute))
+    }
+}
+
+/// Severity of a log record, from the most to the least severe
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A log message kept in the RAM of the display
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogRecord {
+    /// Increases by one with every record, so gaps show records that were dropped
+    pub id: u32,
+    /// Time since boot in ms at which the record was logged
+    pub uptime_ms: u64,
+    pub level: LogLevel,
+    /// Module the record was logged from
+    pub target: String,
+    pub message: String,
+}
+
+/// Why and when the display crashed, kept across the reboot that followed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CrashReport {
+    /// Panic message with its location. Empty if the chip was reset by a watchdog or fault
+    pub message: String,
+    /// Program counters of the stack frames at the time of the panic
+    pub backtrace: Vec<u32>,
+    /// Reason of the reset as reported by the chip
+    pub reset_reason: String,
+    /// Time since boot in ms at which the display crashed. Only precise for panics,
+    /// otherwise it is the last saved uptime
+    pub uptime_ms: u64,
+    /// Unix time of the crash, if the clock was synchronized
+    pub unix_time: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Check that daylight saving time starts at `start` and ends at `end`, to the second
+    fn assert_switches(rule: DaylightSaving, utc_offset_minutes: i64, start: i64, end: i64) {
+        assert!(!rule.in_effect(start - 1, utc_offset_minutes));
+        assert!(rule.in_effect(start, utc_offset_minutes));
+        assert!(rule.in_effect(end - 1, utc_offset_minutes));
+        assert!(!rule.in_effect(end, utc_offset_minutes));
+    }
+
+    fn entry(days: Vec<Weekday>, start: (u8, u8), end: (u8, u8)) -> ScheduleEntry {
+        ScheduleEntry {
+            days,
+            start: TimeOfDay {
+                hour: start.0,
+                minute: start.1,
+            },
+            end: TimeOfDay {
+                hour: end.0,
+                minute: end.1,
+            },
+            on: false,
+            brightness: None,
+        }
+    }
+
+    #[test]
+    fn test_days_from_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+    }
+
+    #[test]
+    fn test_year_from_days() {
+        assert_eq!(year_from_days(0), 1970);
+        assert_eq!(year_from_days(-1), 1969);
+        for year in [1999, 2000, 2024, 2025, 2100] {
+            assert_eq!(year_from_days(days_from_civil(year, 1, 1)), year);
+            assert_eq!(year_from_days(days_from_civil(year, 1, 1) - 1), year - 1);
+            assert_eq!(year_from_days(days_from_civil(year, 12, 31)), year);
+        }
+    }
+
+    #[test]
+    fn test_sundays() {
+        // 2024-03-10, 2024-11-03 and 2024-03-31
+        assert_eq!(nth_sunday(2024, 3, 2), days_from_civil(2024, 3, 10));
+        assert_eq!(nth_sunday(2024, 11, 1), days_from_civil(2024, 11, 3));
+        assert_eq!(last_sunday(2024, 3), days_from_civil(2024, 3, 31));
+        // December has to wrap into the next year
+        assert_eq!(last_sunday(2024, 12), days_from_civil(2024, 12, 29));
+        assert_eq!(weekday_from_days(last_sunday(2025, 10)), 6);
+    }
+
+    #[test]
+    fn test_eu_daylight_saving() {
+        // The EU switches at 01:00 UTC, whatever the offset of the time zone
+        for offset in [0, 60, 120] {
+            assert_switches(DaylightSaving::Eu, offset, 1711846800, 1729990800);
+            assert_switches(DaylightSaving::Eu, offset, 1743296400, 1761440400);
+            assert_switches(DaylightSaving::Eu, offset, 1774746000, 1792890000);
+        }
+    }
+
+    #[test]
+    fn test_us_daylight_saving() {
+        // 2024-03-10 07:00 UTC to 2024-11-03 06:00 UTC for Eastern time
+        assert_switches(DaylightSaving::Us, -300, 1710054000, 1730613600);
+        assert_switches(DaylightSaving::Us, -300, 1741503600, 1762063200);
+        // Pacific time switches three hours later
+        assert_switches(
+            DaylightSaving::Us,
+            -480,
+            1710054000 + 3 * 3600,
+            1730613600 + 3 * 3600,
+        );
+    }
+
+    #[test]
+    fn test_utc_offset_at() {
+        let schedule = Schedule {
+            utc_offset_minutes: 60,
+            ..Default::default()
+        };
+        assert_eq!(schedule.utc_offset_at(1720000000), 60);
+        let schedule = Schedule {
+            daylight_saving: DaylightSaving::Eu,
+            ..schedule
+        };
+        assert_eq!(schedule.utc_offset_at(1720000000), 120);
+        assert_eq!(schedule.utc_offset_at(1735000000), 60);
+    }
+
+    #[test]
+    fn test_entry_within_day() {
+        let entry = entry(vec![Weekday::Monday], (8, 0), (17, 30));
+        assert!(!entry.applies(0, 7 * 60 + 59));
+        assert!(entry.applies(0, 8 * 60));
+        assert!(entry.applies(0, 17 * 60 + 29));
+        assert!(!entry.applies(0, 17 * 60 + 30));
+        assert!(!entry.applies(1, 12 * 60));
+    }
+
+    #[test]
+    fn test_entry_across_midnight() {
+        // Friday 22:00 to Saturday 06:00
+        let entry = entry(vec![Weekday::Friday], (22, 0), (6, 0));
+        assert!(!entry.applies(4, 21 * 60 + 59));
+        assert!(entry.applies(4, 22 * 60));
+        assert!(entry.applies(4, 23 * 60 + 59));
+        assert!(entry.applies(5, 0));
+        assert!(entry.applies(5, 5 * 60 + 59));
+        assert!(!entry.applies(5, 6 * 60));
+        // Only the night starting on Friday is covered
+        assert!(!entry.applies(5, 23 * 60));
+        assert!(!entry.applies(4, 3 * 60));
+        // Without days it covers every night, including the one from Sunday to Monday
+        let every_night = ScheduleEntry {
+            days: Vec::new(),
+            ..entry
+        };
+        assert!(every_night.applies(0, 3 * 60));
+        assert!(every_night.applies(6, 23 * 60));
+    }
+
+    #[test]
+    fn test_active_entry() {
+        let schedule = Schedule {
+            utc_offset_minutes: 60,
+            daylight_saving: DaylightSaving::Eu,
+            entries: vec![
+                entry(vec![Weekday::Friday], (22, 0), (6, 0)),
+                entry(Vec::new(), (5, 0), (5, 30)),
+            ],
+        };
+        // 2024-05-03 is a Friday, 23:00 UTC is 01:00 on Saturday in CEST
+        assert_eq!(
+            schedule
+                .active_entry(1714777200)
+                .map(|entry| entry.start.hour),
+            Some(22)
+        );
+        // Saturday 05:10 CEST, the later entry wins
+        assert_eq!(
+            schedule
+                .active_entry(1714777200 + (4 * 60 + 10) * 60)
+                .map(|entry| entry.start.hour),
+            Some(5)
+        );
+        // Saturday 06:00 CEST is 04:00 UTC, the night is over
+        assert!(schedule.active_entry(1714795200).is_none());
+    }
+
+    #[test]
+    fn test_validate_schedule() {
+        let schedule = |utc_offset_minutes, entry| Schedule {
+            utc_offset_minutes,
+            daylight_saving: DaylightSaving::None,
+            entries: vec![entry],
+        };
+        let night = entry(Vec::new(), (23, 59), (0, 0));
+        assert!(schedule(14 * 60, night.clone()).validate().is_ok());
+        assert!(schedule(-14 * 60, night.clone()).validate().is_ok());
+        assert!(schedule(14 * 60 + 1, night.clone()).validate().is_err());
+        assert!(schedule(-14 * 60 - 1, night.clone()).validate().is_err());
+        assert!(
+            schedule(0, entry(Vec::new(), (24, 0), (1, 0)))
+                .validate()
+                .is_err()
+        );
+        assert!(
+            schedule(0, entry(Vec::new(), (1, 0), (2, 60)))
+                .validate()
+                .is_err()
+        );
+        let bright = |brightness| ScheduleEntry {
+            brightness: Some(brightness),
+            ..night.clone()
+        };
+        assert!(schedule(0, bright(100)).validate().is_ok());
+        assert!(schedule(0, bright(101)).validate().is_err());
+    }
+}
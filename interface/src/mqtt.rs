@@ -0,0 +1,253 @@
+use alloc::format;
+use alloc::string::String;
+use core::str::from_utf8;
+use serde::Serialize;
+use thiserror::Error;
+
+/// Topics the display subscribes and publishes to
+pub struct Topics {
+    pub config: String,
+    pub power_set: String,
+    pub brightness_set: String,
+    pub toast: String,
+    pub power_state: String,
+    pub brightness_state: String,
+    pub status: String,
+    pub availability: String,
+    pub discovery: String,
+    hostname: String,
+}
+
+/// A message received on one of the topics the display subscribes to
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    /// New configuration to show
+    Config(ConfigPayload<'a>),
+    /// Turn the panel on or off
+    Power(bool),
+    /// Brightness in %
+    Brightness(u8),
+    /// Text to show as toast
+    Toast(&'a str),
+}
+
+/// Config messages are accepted as JSON or postcard
+#[derive(Debug, PartialEq)]
+pub enum ConfigPayload<'a> {
+    Json(&'a [u8]),
+    Postcard(&'a [u8]),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CommandError {
+    #[error("Message is not UTF-8")]
+    NotUtf8,
+    #[error("Invalid power state '{0}', expected ON or OFF")]
+    InvalidPower(String),
+    #[error("Invalid brightness '{0}', expected 0 to 100")]
+    InvalidBrightness(String),
+    #[error("Not subscribed to this topic")]
+    UnknownTopic,
+}
+
+/// Home Assistant discovery message exposing the panel as a dimmable light
+#[derive(Serialize, Debug)]
+pub struct DiscoveryMessage<'a> {
+    /// Always empty, so Home Assistant names the light after the device
+    pub name: Option<&'a str>,
+    pub unique_id: &'a str,
+    pub command_topic: &'a str,
+    pub state_topic: &'a str,
+    pub brightness_command_topic: &'a str,
+    pub brightness_state_topic: &'a str,
+    pub brightness_scale: u8,
+    pub payload_on: &'a str,
+    pub payload_off: &'a str,
+    pub availability_topic: &'a str,
+    pub device: DiscoveryDevice<'a>,
+}
+
+/// Device the light of a [`DiscoveryMessage`] belongs to
+#[derive(Serialize, Debug)]
+pub struct DiscoveryDevice<'a> {
+    pub identifiers: [&'a str; 1],
+    pub name: &'a str,
+    pub model: &'a str,
+    pub sw_version: &'a str,
+}
+
+impl Topics {
+    /// Topics of the display with the given hostname. `topic_prefix` is put in front of
+    /// all topics and defaults to the hostname, discovery is announced below `discovery_prefix`
+    pub fn new(topic_prefix: &str, discovery_prefix: &str, hostname: &str) -> Self {
+        let prefix = if topic_prefix.is_empty() {
+            hostname
+        } else {
+            topic_prefix
+        };
+        Self {
+            config: format!("{prefix}/config"),
+            power_set: format!("{prefix}/power/set"),
+            brightness_set: format!("{prefix}/brightness/set"),
+            toast: format!("{prefix}/toast"),
+            power_state: format!("{prefix}/power/state"),
+            brightness_state: format!("{prefix}/brightness/state"),
+            status: format!("{prefix}/status"),
+            availability: format!("{prefix}/availability"),
+            discovery: format!("{discovery_prefix}/light/{hostname}/config"),
+            hostname: String::from(hostname),
+        }
+    }
+
+    /// Work out what a message received on the given topic asks the display to do
+    pub fn parse<'a>(&self, topic: &str, payload: &'a [u8]) -> Result<Command<'a>, CommandError> {
+        if topic == self.config {
+            return Ok(Command::Config(if payload.first() == Some(&b'{') {
+                ConfigPayload::Json(payload)
+            } else {
+                ConfigPayload::Postcard(payload)
+            }));
+        }
+        let text = from_utf8(payload)
+            .map_err(|_| CommandError::NotUtf8)?
+            .trim();
+        if topic == self.power_set {
+            match text {
+                "ON" => Ok(Command::Power(true)),
+                "OFF" => Ok(Command::Power(false)),
+                _ => Err(CommandError::InvalidPower(String::from(text))),
+            }
+        } else if topic == self.brightness_set {
+            match text.parse::<u8>() {
+                Ok(brightness) if brightness <= 100 => Ok(Command::Brightness(brightness)),
+                _ => Err(CommandError::InvalidBrightness(String::from(text))),
+            }
+        } else if topic == self.toast {
+            Ok(Command::Toast(text))
+        } else {
+            Err(CommandError::UnknownTopic)
+        }
+    }
+
+    /// Discovery message for the firmware with the given version
+    pub fn discovery_message<'a>(&'a self, sw_version: &'a str) -> DiscoveryMessage<'a> {
+        DiscoveryMessage {
+            name: None,
+            unique_id: &self.hostname,
+            command_topic: &self.power_set,
+            state_topic: &self.power_state,
+            brightness_command_topic: &self.brightness_set,
+            brightness_state_topic: &self.brightness_state,
+            brightness_scale: 100,
+            payload_on: "ON",
+            payload_off: "OFF",
+            availability_topic: &self.availability,
+            device: DiscoveryDevice {
+                identifiers: [&self.hostname],
+                name: &self.hostname,
+                model: "Headless LED wall",
+                sw_version,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topics_use_hostname_as_default_prefix() {
+        let topics = Topics::new("", "homeassistant", "led-wall");
+        assert_eq!(topics.config, "led-wall/config");
+        assert_eq!(topics.power_set, "led-wall/power/set");
+        assert_eq!(topics.discovery, "homeassistant/light/led-wall/config");
+
+        let topics = Topics::new("office/display", "ha", "led-wall");
+        assert_eq!(topics.brightness_state, "office/display/brightness/state");
+        assert_eq!(topics.availability, "office/display/availability");
+        assert_eq!(topics.discovery, "ha/light/led-wall/config");
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let topics = Topics::new("", "", "wall");
+        assert_eq!(
+            topics.parse("wall/config", b"{\"screens\":[]}"),
+            Ok(Command::Config(ConfigPayload::Json(b"{\"screens\":[]}")))
+        );
+        assert_eq!(
+            topics.parse("wall/config", &[1, 0, 0xff]),
+            Ok(Command::Config(ConfigPayload::Postcard(&[1, 0, 0xff])))
+        );
+    }
+
+    #[test]
+    fn test_parse_power_and_brightness() {
+        let topics = Topics::new("", "", "wall");
+        assert_eq!(
+            topics.parse("wall/power/set", b"ON"),
+            Ok(Command::Power(true))
+        );
+        assert_eq!(
+            topics.parse("wall/power/set", b" OFF\n"),
+            Ok(Command::Power(false))
+        );
+        assert_eq!(
+            topics.parse("wall/power/set", b"on"),
+            Err(CommandError::InvalidPower(String::from("on")))
+        );
+        assert_eq!(
+            topics.parse("wall/brightness/set", b"100"),
+            Ok(Command::Brightness(100))
+        );
+        assert_eq!(
+            topics.parse("wall/brightness/set", b"101"),
+            Err(CommandError::InvalidBrightness(String::from("101")))
+        );
+        assert_eq!(
+            topics.parse("wall/brightness/set", &[0xff]),
+            Err(CommandError::NotUtf8)
+        );
+    }
+
+    #[test]
+    fn test_parse_toast_and_unknown_topic() {
+        let topics = Topics::new("", "", "wall");
+        assert_eq!(
+            topics.parse("wall/toast", b" Train delayed "),
+            Ok(Command::Toast("Train delayed"))
+        );
+        assert_eq!(
+            topics.parse("wall/status", b"{}"),
+            Err(CommandError::UnknownTopic)
+        );
+    }
+
+    #[test]
+    fn test_discovery_message() {
+        let topics = Topics::new("", "homeassistant", "wall");
+        let message = serde_json::to_value(topics.discovery_message("1.2.3")).unwrap();
+        assert_eq!(
+            message,
+            serde_json::json!({
+                "name": null,
+                "unique_id": "wall",
+                "command_topic": "wall/power/set",
+                "state_topic": "wall/power/state",
+                "brightness_command_topic": "wall/brightness/set",
+                "brightness_state_topic": "wall/brightness/state",
+                "brightness_scale": 100,
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "availability_topic": "wall/availability",
+                "device": {
+                    "identifiers": ["wall"],
+                    "name": "wall",
+                    "model": "Headless LED wall",
+                    "sw_version": "1.2.3",
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,371 @@
+use crate::embedded::{CheckedScreenConfig, string_to_color};
+use crate::sprite::{BakedResource, Frame};
+use crate::{Element, FrameFormat, RectangleCorners};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::image::Image;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::{
+    CornerRadiiBuilder, Line, Polyline, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle,
+    RoundedRectangle,
+};
+use embedded_graphics::text::Text;
+use log::error;
+
+/// Size of the outline drawn where a sprite is shown once it is loaded
+const PLACEHOLDER_SIZE: Size = Size::new(8, 8);
+
+/// Result of looking up a sprite to draw it
+pub enum Lookup<'a> {
+    Ready(Frame<'a>),
+    /// The sprite is being loaded in the background
+    Loading,
+    /// The sprite does not exist or could not be loaded
+    Missing,
+}
+
+/// Provides the sprites the elements of a config refer to
+pub trait SpriteSource {
+    /// Current frame of a sprite kept in the given format, or the default format of the
+    /// source if none is given
+    fn get(&mut self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> Lookup<'_>;
+
+    /// Whether the sprite shows a new animation frame
+    fn frame_due(&self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> bool;
+}
+
+fn make_primitive_style(
+    stroke_color: &Option<String>,
+    stroke_width: &Option<u32>,
+    fill_color: &Option<String>,
+) -> PrimitiveStyle<Rgb888> {
+    let mut style = PrimitiveStyleBuilder::new();
+    if let Some(color) = stroke_color
+        && let Some(color) = string_to_color(color)
+    {
+        style = style.stroke_color(color);
+    }
+    if let Some(stroke) = stroke_width {
+        style = style.stroke_width(*stroke);
+    }
+    if let Some(fill) = fill_color
+        && let Some(fill) = string_to_color(fill)
+    {
+        style = style.fill_color(fill)
+    }
+    style.build()
+}
+
+/// Marks where a sprite will be shown once it is loaded
+fn draw_placeholder<D: DrawTarget<Color = Rgb888>>(
+    fb: &mut D,
+    pos: Point,
+    center: Option<Point>,
+) -> Rectangle {
+    let area = match center {
+        Some(point) => Rectangle::with_center(point, PLACEHOLDER_SIZE),
+        None => Rectangle::new(pos, PLACEHOLDER_SIZE),
+    };
+    let outline = area.into_styled(PrimitiveStyle::with_stroke(Rgb888::CSS_DIM_GRAY, 1));
+    outline.draw(fb).ok();
+    outline.bounding_box()
+}
+
+/// Draw a single element of the config. Returns the area it covers
+fn draw_element<D, S>(
+    fb: &mut D,
+    element: &Element,
+    config: &CheckedScreenConfig,
+    sprites: &mut S,
+    err_img: &mut BakedResource,
+    now_ms: u64,
+) -> Rectangle
+where
+    D: DrawTarget<Color = Rgb888>,
+    S: SpriteSource + ?Sized,
+{
+    let pos = element.position();
+    match element {
+        Element::Sprite {
+            name,
+            center,
+            format,
+            ..
+        } => {
+            let center = center.as_ref().map(Point::from);
+            let img = match sprites.get(name, *format, now_ms) {
+                Lookup::Ready(img) => img,
+                Lookup::Loading => return draw_placeholder(fb, pos, center),
+                Lookup::Missing => match err_img.get_image(now_ms) {
+                    Ok(img) => img,
+                    Err(_) => return Rectangle::zero(),
+                },
+            };
+            let image = match center {
+                Some(point) => Image::with_center(&img, point),
+                None => Image::new(&img, pos),
+            };
+            image.draw(fb).ok();
+            image.bounding_box()
+        }
+        Element::Text {
+            style, text, align, ..
+        } => {
+            let Some(style) = config.styles.get(style) else {
+                error!("Style {style} not found");
+                return Rectangle::zero();
+            };
+            let text = match align {
+                Some(align) => Text::with_alignment(text, pos, *style, align.alignment()),
+                None => Text::new(text, pos, *style),
+            };
+            text.draw(fb).ok();
+            text.bounding_box()
+        }
+        Element::Line {
+            start,
+            end,
+            color,
+            stroke,
+        } => {
+            let style = make_primitive_style(color, stroke, &None);
+            let line = Line::new(start.into(), end.into()).into_styled(style);
+            line.draw(fb).ok();
+            line.bounding_box()
+        }
+        Element::Polyline {
+            color,
+            stroke,
+            points,
+        } => {
+            let style = make_primitive_style(color, stroke, &None);
+            let points: Vec<Point> = points.iter().map(|p| p.into()).collect();
+            let polyline = Polyline::new(points.as_slice()).into_styled(style);
+            polyline.draw(fb).ok();
+            polyline.bounding_box()
+        }
+        Element::Rectangle {
+            top_left,
+            size,
+            fill_color,
+            stroke_color,
+            stroke,
+            rounded_corners,
+        } => {
+            let style = make_primitive_style(stroke_color, stroke, fill_color);
+            let rect = Rectangle::new(top_left.into(), size.into());
+            if let Some(corners) = rounded_corners {
+                let corners = match corners {
+                    RectangleCorners::Uniform(size) => {
+                        CornerRadiiBuilder::new().all(size.into()).build()
+                    }
+                    RectangleCorners::Different {
+                        top_left,
+                        top_right,
+                        bottom_left,
+                        bottom_right,
+                    } => {
+                        let mut builder = CornerRadiiBuilder::new();
+                        if let Some(radius) = top_left {
+                            builder = builder.top_left(radius.into());
+                        }
+                        if let Some(radius) = top_right {
+                            builder = builder.top_right(radius.into());
+                        }
+                        if let Some(radius) = bottom_left {
+                            builder = builder.bottom_left(radius.into());
+                        }
+                        if let Some(radius) = bottom_right {
+                            builder = builder.bottom_right(radius.into());
+                        }
+                        builder.build()
+                    }
+                };
+                let rounded = RoundedRectangle::new(rect, corners).into_styled(style);
+                rounded.draw(fb).ok();
+                rounded.bounding_box()
+            } else {
+                let rect = rect.into_styled(style);
+                rect.draw(fb).ok();
+                rect.bounding_box()
+            }
+        }
+    }
+}
+
+/// Draw all elements of the config and remember the area each of them covers
+pub fn render_config<D, S>(
+    fb: &mut D,
+    config: &CheckedScreenConfig,
+    sprites: &mut S,
+    err_img: &mut BakedResource,
+    now_ms: u64,
+    bounds: &mut Vec<Rectangle>,
+) where
+    D: DrawTarget<Color = Rgb888>,
+    S: SpriteSource + ?Sized,
+{
+    bounds.clear();
+    for element in config.screen.elements.iter() {
+        bounds.push(draw_element(fb, element, config, sprites, err_img, now_ms));
+    }
+}
+
+/// Clear an area and draw the elements of the config overlapping it again.
+/// Returns false if an element now covers another area, so the whole screen has to be drawn
+pub fn render_area<D, S>(
+    fb: &mut D,
+    area: Rectangle,
+    config: &CheckedScreenConfig,
+    sprites: &mut S,
+    err_img: &mut BakedResource,
+    now_ms: u64,
+    bounds: &[Rectangle],
+) -> bool
+where
+    D: DrawTarget<Color = Rgb888>,
+    S: SpriteSource + ?Sized,
+{
+    fb.fill_solid(&area, Rgb888::BLACK).ok();
+    let clipped = &mut fb.clipped(&area);
+    for (element, bound) in config.screen.elements.iter().zip(bounds) {
+        if bound.intersection(&area).is_zero_sized() {
+            continue;
+        }
+        if draw_element(clipped, element, config, sprites, err_img, now_ms) != *bound {
+            return false;
+        }
+    }
+    true
+}
+
+/// Areas of the sprites of the config which show a new animation frame
+pub fn animated_areas<S: SpriteSource + ?Sized>(
+    config: &CheckedScreenConfig,
+    bounds: &[Rectangle],
+    sprites: &S,
+    now_ms: u64,
+) -> Vec<Rectangle> {
+    config
+        .screen
+        .elements
+        .iter()
+        .zip(bounds)
+        .filter_map(|(element, bound)| match element {
+            Element::Sprite { name, format, .. } => {
+                sprites.frame_due(name, *format, now_ms).then_some(*bound)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// In memory image of a panel with the given color depth
+pub struct Canvas {
+    size: Size,
+    color_depth: u8,
+    pixels: Vec<Rgb888>,
+}
+
+impl Canvas {
+    pub fn new(size: Size, color_depth: u8) -> Self {
+        Self {
+            size,
+            color_depth,
+            pixels: vec![Rgb888::BLACK; (size.width * size.height) as usize],
+        }
+    }
+
+    /// Pixels in rows from top to bottom
+    pub fn pixels(&self) -> &[Rgb888] {
+        &self.pixels
+    }
+
+    /// Convert a color to what the panel is actually able to show with its color depth
+    fn quantize(&self, color: Rgb888) -> Rgb888 {
+        let bits = self.color_depth as u32;
+        let max_level = (1u32 << bits) - 1;
+        let level = |v: u8| ((((v as u32) >> (8 - bits)) * 255) / max_level) as u8;
+        Rgb888::new(level(color.r()), level(color.g()), level(color.b()))
+    }
+
+    /// Encode the canvas content as a QOI image
+    pub fn to_qoi(&self) -> Vec<u8> {
+        const QOI_OP_INDEX: u8 = 0x00;
+        const QOI_OP_RUN: u8 = 0xc0;
+        const QOI_OP_RGB: u8 = 0xfe;
+
+        let mut out = Vec::with_capacity(14 + self.pixels.len() + 8);
+        out.extend_from_slice(b"qoif");
+        out.extend_from_slice(&self.size.width.to_be_bytes());
+        out.extend_from_slice(&self.size.height.to_be_bytes());
+        // 3 channels, sRGB with linear alpha
+        out.extend_from_slice(&[3, 0]);
+
+        let mut index = [[0u8; 4]; 64];
+        let mut prev = [0u8, 0, 0, 255];
+        let mut run = 0u8;
+        for (i, pixel) in self.pixels.iter().enumerate() {
+            let px = [pixel.r(), pixel.g(), pixel.b(), 255];
+            if px == prev {
+                run += 1;
+                if run == 62 || i == self.pixels.len() - 1 {
+                    out.push(QOI_OP_RUN | (run - 1));
+                    run = 0;
+                }
+                continue;
+            }
+            if run > 0 {
+                out.push(QOI_OP_RUN | (run - 1));
+                run = 0;
+            }
+            let hash = (px[0] as usize * 3
+                + px[1] as usize * 5
+                + px[2] as usize * 7
+                + px[3] as usize * 11)
+                % index.len();
+            if index[hash] == px {
+                out.push(QOI_OP_INDEX | hash as u8);
+            } else {
+                index[hash] = px;
+                out.extend_from_slice(&[QOI_OP_RGB, px[0], px[1], px[2]]);
+            }
+            prev = px;
+        }
+        out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        out
+    }
+}
+
+impl OriginDimensions for Canvas {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Canvas {
+    type Color = Rgb888;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let width = self.size.width as i32;
+        let height = self.size.height as i32;
+        for Pixel(point, color) in pixels {
+            if point.x >= 0 && point.y >= 0 && point.x < width && point.y < height {
+                self.pixels[(point.y * width + point.x) as usize] = self.quantize(color);
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let color = self.quantize(color);
+        self.pixels.fill(color);
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{FrameFormat, Resource};
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_graphics::{
+    image::{ImageDrawable, ImageRaw},
+    pixelcolor::Rgb888,
+    prelude::*,
+    primitives::Rectangle,
+};
+use thiserror::Error;
+use tinyqoi::Qoi;
+
+#[derive(Debug, Error)]
+pub enum SpriteError {
+    #[error("Failed to get next sprite frame")]
+    IteratorFail,
+    #[error("Qoi failed to parse the image: {0:#?}")]
+    ImageParseError(tinyqoi::Error),
+}
+
+/// A frame decoded into 24 bit pixels
+pub struct RgbFrame {
+    width: u32,
+    pixels: Vec<u8>,
+}
+
+/// A frame reduced to the color depth of the panel, stored as runs of palette colors
+/// going through the rows from top to bottom
+pub struct RleFrame {
+    size: Size,
+    palette: Vec<Rgb888>,
+    /// Length and palette index of each run
+    runs: Vec<[u8; 2]>,
+}
+
+impl RleFrame {
+    fn colors(&self) -> impl Iterator<Item = Rgb888> + '_ {
+        self.runs.iter().flat_map(|[len, index]| {
+            core::iter::repeat_n(self.palette[*index as usize], *len as usize)
+        })
+    }
+
+    /// Encode the pixels of a decoded frame, keeping the given number of bits per channel.
+    /// None if it has more than 256 colors
+    fn encode(frame: &RgbFrame, color_depth: u8) -> Option<Self> {
+        let mask = !(0xff_u8.checked_shr(color_depth.into()).unwrap_or(0));
+        let mut palette: Vec<Rgb888> = Vec::new();
+        let mut runs: Vec<[u8; 2]> = Vec::new();
+        for rgb in frame.pixels.chunks_exact(3) {
+            let color = Rgb888::new(rgb[0] & mask, rgb[1] & mask, rgb[2] & mask);
+            let index = match palette.iter().position(|c| *c == color) {
+                Some(index) => index,
+                None => {
+                    palette.push(color);
+                    palette.len() - 1
+                }
+            };
+            let index = u8::try_from(index).ok()?;
+            match runs.last_mut() {
+                Some([len, last]) if *last == index && *len < u8::MAX => *len += 1,
+                _ => runs.push([1, index]),
+            }
+        }
+        let height = (frame.pixels.len() / 3) as u32 / frame.width.max(1);
+        Some(Self {
+            size: Size::new(frame.width, height),
+            palette,
+            runs,
+        })
+    }
+}
+
+/// Draw target collecting the pixels of a QOI image
+struct Decoder {
+    size: Size,
+    pixels: Vec<u8>,
+}
+
+impl OriginDimensions for Decoder {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl DrawTarget for Decoder {
+    type Color = Rgb888;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let area = self.bounding_box();
+        for Pixel(point, color) in pixels.into_iter().filter(|p| area.contains(p.0)) {
+            let index = (point.y as usize * self.size.width as usize + point.x as usize) * 3;
+            self.pixels[index..index + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+        }
+        Ok(())
+    }
+}
+
+fn decode_qoi(data: &[u8]) -> Result<RgbFrame, SpriteError> {
+    let img = Qoi::new(data).map_err(SpriteError::ImageParseError)?;
+    let size = img.size();
+    let mut decoder = Decoder {
+        size,
+        pixels: vec![0; size.width as usize * size.height as usize * 3],
+    };
+    img.draw(&mut decoder).ok();
+    Ok(RgbFrame {
+        width: size.width,
+        pixels: decoder.pixels,
+    })
+}
+
+enum Frames {
+    Qoi(Vec<Vec<u8>>),
+    Rgb(Vec<RgbFrame>),
+    Rle(Vec<RleFrame>),
+}
+
+impl Frames {
+    fn len(&self) -> usize {
+        match self {
+            Frames::Qoi(frames) => frames.len(),
+            Frames::Rgb(frames) => frames.len(),
+            Frames::Rle(frames) => frames.len(),
+        }
+    }
+}
+
+/// A single frame of a sprite, ready to be drawn
+pub enum Frame<'a> {
+    Qoi(Qoi<'a>),
+    Rgb(ImageRaw<'a, Rgb888>),
+    Rle(&'a RleFrame),
+}
+
+impl OriginDimensions for Frame<'_> {
+    fn size(&self) -> Size {
+        match self {
+            Frame::Qoi(img) => img.size(),
+            Frame::Rgb(raw) => raw.size(),
+            Frame::Rle(frame) => frame.size,
+        }
+    }
+}
+
+impl ImageDrawable for Frame<'_> {
+    type Color = Rgb888;
+
+    fn draw<D: DrawTarget<Color = Self::Color>>(&self, target: &mut D) -> Result<(), D::Error> {
+        match self {
+            Frame::Qoi(img) => img.draw(target),
+            Frame::Rgb(raw) => raw.draw(target),
+            Frame::Rle(frame) => {
+                target.fill_contiguous(&Rectangle::new(Point::zero(), frame.size), frame.colors())
+            }
+        }
+    }
+
+    fn draw_sub_image<D: DrawTarget<Color = Self::Color>>(
+        &self,
+        target: &mut D,
+        area: &Rectangle,
+    ) -> Result<(), D::Error> {
+        match self {
+            Frame::Qoi(img) => img.draw_sub_image(target, area),
+            Frame::Rgb(raw) => raw.draw_sub_image(target, area),
+            Frame::Rle(_) => {
+                let mut clipped = target.clipped(&Rectangle::new(Point::zero(), area.size));
+                self.draw(&mut clipped.translated(-area.top_left))
+            }
+        }
+    }
+}
+
+pub struct BakedResource {
+    frames: Frames,
+    current: usize,
+    /// Uptime in ms the current frame was first shown at. None until it is drawn
+    last_iteration: Option<u64>,
+    frame_time_ms: u64,
+}
+
+/// Prepare a resource to be drawn, keeping its frames as QOI images
+pub fn bake(res: Resource) -> BakedResource {
+    BakedResource {
+        frames: Frames::Qoi(res.frames),
+        current: 0,
+        last_iteration: None,
+        frame_time_ms: res.frame_time_ms.into(),
+    }
+}
+
+/// Prepare a resource to be drawn, decoding its frames once into the given format.
+/// RLE frames keep `color_depth` bits per channel. Sprites with more than 256 colors
+/// at that depth are decoded to RGB instead, see [`BakedResource::format`]
+pub fn bake_as(
+    res: Resource,
+    format: FrameFormat,
+    color_depth: u8,
+) -> Result<BakedResource, SpriteError> {
+    let frame_time_ms = res.frame_time_ms.into();
+    let frames = match format {
+        FrameFormat::Qoi => return Ok(bake(res)),
+        FrameFormat::Rgb => Frames::Rgb(
+            res.frames
+                .iter()
+                .map(|frame| decode_qoi(frame))
+                .collect::<Result<_, _>>()?,
+        ),
+        FrameFormat::Rle => {
+            let decoded = res
+                .frames
+                .iter()
+                .map(|frame| decode_qoi(frame))
+                .collect::<Result<Vec<_>, _>>()?;
+            match decoded
+                .iter()
+                .map(|frame| RleFrame::encode(frame, color_depth))
+                .collect()
+            {
+                Some(encoded) => Frames::Rle(encoded),
+                None => Frames::Rgb(decoded),
+            }
+        }
+    };
+    Ok(BakedResource {
+        frames,
+        current: 0,
+        last_iteration: None,
+        frame_time_ms,
+    })
+}
+
+/// Sprite drawn in place of sprites which do not exist or could not be loaded
+pub fn no_image_sprite() -> BakedResource {
+    bake(Resource {
+        frames: vec![include_bytes!("../sprites/no_image.qoi").to_vec()],
+        frame_time_ms: 0,
+    })
+}
+
+impl BakedResource {
+    /// The frame to show at the given uptime in ms, moving on to the next frame when it is due
+    pub fn get_image(&mut self, now_ms: u64) -> Result<Frame<'_>, SpriteError> {
+        let count = self.frames.len();
+        if count == 0 {
+            return Err(SpriteError::IteratorFail);
+        }
+        if self.needs_update(now_ms) {
+            self.current = (self.current + 1) % count;
+            self.last_iteration = Some(now_ms);
+        } else if self.last_iteration.is_none() {
+            self.last_iteration = Some(now_ms);
+        }
+        Ok(match &self.frames {
+            Frames::Qoi(frames) => {
+                Frame::Qoi(Qoi::new(&frames[self.current]).map_err(SpriteError::ImageParseError)?)
+            }
+            Frames::Rgb(frames) => {
+                let frame = &frames[self.current];
+                Frame::Rgb(ImageRaw::new(&frame.pixels, frame.width))
+            }
+            Frames::Rle(frames) => Frame::Rle(&frames[self.current]),
+        })
+    }
+
+    pub fn needs_update(&self, now_ms: u64) -> bool {
+        self.last_iteration
+            .is_some_and(|last| last + self.frame_time_ms < now_ms)
+    }
+
+    /// Format the frames are actually kept in
+    pub fn format(&self) -> FrameFormat {
+        match self.frames {
+            Frames::Qoi(_) => FrameFormat::Qoi,
+            Frames::Rgb(_) => FrameFormat::Rgb,
+            Frames::Rle(_) => FrameFormat::Rle,
+        }
+    }
+
+    /// Bytes of RAM used by the frames
+    pub fn memory_size(&self) -> usize {
+        match &self.frames {
+            Frames::Qoi(frames) => frames.iter().map(|frame| frame.len()).sum(),
+            Frames::Rgb(frames) => frames.iter().map(|frame| frame.pixels.len()).sum(),
+            Frames::Rle(frames) => frames
+                .iter()
+                .map(|frame| frame.palette.len() * 3 + frame.runs.len() * 2)
+                .sum(),
+        }
+    }
+}
@@ -1,7 +1,12 @@
 use anyhow::{Result, anyhow};
 use clap::{Parser, Subcommand};
-use indicatif::ProgressIterator;
-use interface::{Configuration, Resource};
+use embedded_graphics::prelude::Size;
+use futures_util::stream;
+use indicatif::{ProgressBar, ProgressIterator};
+use interface::{
+    Configuration, LogRecord, Resource, Schedule, StorageInfo, UploadProgress, WifiNetwork,
+    WifiStatus,
+};
 use log::{error, info, warn};
 use postcard::to_allocvec;
 use schemars::schema_for;
@@ -9,9 +14,9 @@ use serde::Deserialize;
 use static_cell::StaticCell;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, BufWriter, Read};
 use std::path::Path;
-use std::{fs, net::Ipv4Addr, path::PathBuf};
+use std::{fs, net::SocketAddrV4, path::PathBuf};
 use tokio::signal;
 use tokio::signal::unix::SignalKind;
 use tokio::sync::mpsc;
@@ -20,6 +25,7 @@ use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
 
 use crate::config::ServerConfig;
+use crate::preview::{self, PanelSettings, PreviewSprites, render_preview, sprite_names};
 use crate::server::{
     DataUpdate, fetch_transport_data, fetch_weather_data, maintain_display, push_display_update,
 };
@@ -54,6 +60,35 @@ enum Commands {
         output_file: PathBuf,
     },
 
+    /// Render a configuration in json format exactly like the display shows it.
+    /// Writes an animated GIF of the sprite animations if the output file ends in .gif
+    /// and a PNG otherwise
+    Render {
+        /// Configuration json file to render
+        input_file: PathBuf,
+        /// PNG or GIF file to write
+        output_file: PathBuf,
+        /// sprites.toml file with the sprites the configuration uses.
+        /// The sprites are downloaded from the display if not given
+        #[arg(short, long)]
+        sprites: Option<PathBuf>,
+        /// Number of image pixels each pixel of the panel is enlarged to in both directions
+        #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=16))]
+        scale: u32,
+        /// Length of the GIF in ms
+        #[arg(long, default_value_t = 5000)]
+        duration: u64,
+        /// Width of the panel in pixels
+        #[arg(long, default_value_t = 192)]
+        width: u32,
+        /// Height of the panel in pixels
+        #[arg(long, default_value_t = 96)]
+        height: u32,
+        /// Bits per color channel the panel shows, as set in the config.toml of the display
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=8))]
+        color_depth: u8,
+    },
+
     /// Push a configuration in json format to the display server
     PushConfig {
         /// Configuration json file to push
@@ -70,6 +105,81 @@ enum Commands {
         input_files: Vec<PathBuf>,
     },
 
+    /// Take a screenshot of what the display is currently showing and save it as PNG
+    Screenshot {
+        /// PNG file to write the screenshot to
+        output_file: PathBuf,
+    },
+
+    /// List all items stored in the flash of the display
+    List,
+
+    /// Download a sprite from the display and save its frames as QOI files
+    Download {
+        /// Name of the sprite
+        name: String,
+        /// Directory to write the frames to
+        output_dir: PathBuf,
+    },
+
+    /// Check if a sprite with the given name exists on the display
+    Exists {
+        /// Name of the sprite
+        name: String,
+    },
+
+    /// Delete a sprite from the display
+    Delete {
+        /// Name of the sprite
+        name: String,
+    },
+
+    /// Update the firmware of the display over the air.
+    /// The firmware has to be an app image, for example created with `espflash save-image`
+    FlashFirmware {
+        /// Firmware image file to upload
+        input_file: PathBuf,
+    },
+
+    /// Show the log messages the display keeps in RAM
+    Logs {
+        /// Most verbose level to show: error, warn, info, debug or trace
+        #[arg(short, long, default_value = "trace")]
+        level: String,
+        /// Keep waiting for new messages and show them as they arrive
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Show which WIFI network the display is connected to and which networks it knows
+    Wifi,
+
+    /// Add a WIFI network the display may connect to, or update an existing one
+    WifiAdd {
+        /// SSID of the network
+        ssid: String,
+        /// Password of the network, leave out for open networks
+        password: Option<String>,
+        /// Networks with a higher priority are preferred over ones with a better signal
+        #[arg(short, long, default_value_t = 0)]
+        priority: u8,
+    },
+
+    /// Remove a WIFI network from the networks known by the display
+    WifiRemove {
+        /// SSID of the network
+        ssid: String,
+    },
+
+    /// Show the power and brightness schedule of the display
+    Schedule,
+
+    /// Replace the power and brightness schedule of the display
+    SetSchedule {
+        /// Schedule json file to push
+        input_file: PathBuf,
+    },
+
     /// Bulk upload all sprites from a sprites.toml file
     BulkUpload {
         /// Path to the sprites.toml file which contains all meta information about all the sprites
@@ -83,6 +193,9 @@ enum Commands {
     },
 }
 
+/// First byte of every ESP app image
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+
 type SpriteCollection = HashMap<String, SpriteDefinition>;
 
 #[derive(Debug, Deserialize)]
@@ -94,7 +207,6 @@ struct SpriteDefinition {
 impl Cli {
     pub async fn run(self) {
         let conf = ServerConfig::from_toml(self.config);
-        let ip = conf.display.ip;
         match self.command {
             Commands::GenerateSchema { output_file } => {
                 let schema = schema_for!(Configuration);
@@ -105,7 +217,7 @@ impl Cli {
                 .expect("Failed to write schema to file");
             }
             Commands::Server => {
-                info!("Running server pushing updates to IP: {}", ip);
+                info!("Running server pushing updates to {}", conf.display.address);
 
                 let (tx, rx) = mpsc::channel::<DataUpdate>(100);
                 let mut set = JoinSet::new();
@@ -126,7 +238,7 @@ impl Cli {
                     client,
                     conf.clone(),
                 ));
-                set.spawn(push_display_update(token.clone(), ip, rx));
+                set.spawn(push_display_update(token.clone(), conf.display.clone(), rx));
                 set.spawn(maintain_display(token.clone(), tx));
 
                 #[cfg(not(target_family = "unix"))]
@@ -163,14 +275,74 @@ impl Cli {
                     to_allocvec(&parsed).expect("Could not convert to postcard format");
                 fs::write(output_file, output).expect("Could not write output file");
             }
+            Commands::Render {
+                input_file,
+                output_file,
+                sprites,
+                scale,
+                duration,
+                width,
+                height,
+                color_depth,
+            } => {
+                let f = File::open(input_file).expect("Could not open file");
+                let parsed: Configuration =
+                    serde_json::from_reader(BufReader::new(f)).expect("Could not parse json");
+                let names = sprite_names(&parsed);
+                let resources = match sprites {
+                    Some(meta_file) => load_sprites(&meta_file, &names),
+                    None => {
+                        let ip = resolve_display(&conf).await;
+                        let client = conf.display.http_client();
+                        let mut resources = HashMap::new();
+                        for name in names.iter().progress() {
+                            match download_sprite(&client, &ip, name).await {
+                                Ok(sprite) => {
+                                    resources.insert(name.clone(), sprite);
+                                }
+                                Err(e) => warn!("Failed to download sprite {name}: {e}"),
+                            }
+                        }
+                        resources
+                    }
+                };
+                let panel = PanelSettings {
+                    size: Size::new(width, height),
+                    color_depth,
+                };
+                let is_gif = output_file
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+                let mut sprites = PreviewSprites::new(resources, color_depth);
+                let frames = render_preview(
+                    parsed,
+                    &mut sprites,
+                    &panel,
+                    if is_gif { duration } else { 0 },
+                )
+                .expect("Could not render configuration");
+                if is_gif {
+                    preview::write_gif(&output_file, &frames, panel.size, scale)
+                        .expect("Failed to write GIF file");
+                } else {
+                    preview::write_png(&output_file, &frames[0], panel.size, scale)
+                        .expect("Failed to write PNG file");
+                }
+                info!(
+                    "Preview with {} frames saved to {}",
+                    frames.len(),
+                    output_file.display()
+                );
+            }
             Commands::PushConfig { input_file } => {
+                let ip = resolve_display(&conf).await;
                 let f = File::open(input_file).expect("Could not open file");
                 let reader = BufReader::new(f);
                 let parsed: Configuration =
                     serde_json::from_reader(reader).expect("Could not parse json");
                 let buf = postcard::to_allocvec(&parsed)
                     .expect("Could not serialize configuration to postcard format");
-                let client = reqwest::Client::new();
+                let client = conf.display.http_client();
                 let res = client
                     .post(format!("http://{ip}/api/config"))
                     .body(buf)
@@ -189,14 +361,255 @@ impl Cli {
                 input_files,
                 frame_time,
             } => {
-                let client = reqwest::Client::new();
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
                 sprite_upload(&client, &input_files, &ip, &name, frame_time).await;
             }
+            Commands::Screenshot { output_file } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let res = client
+                    .get(format!("http://{ip}/api/screenshot"))
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .expect("Failed to send request");
+                let status = res.status();
+                if !status.is_success() {
+                    error!("Error: {:#?}", res.text().await);
+                    return;
+                }
+                let data = res.bytes().await.expect("Failed to read screenshot data");
+                let (header, pixels) =
+                    qoi::decode_to_vec(&data).expect("Display sent an invalid QOI image");
+                write_png(&output_file, &header, &pixels).expect("Failed to write PNG file");
+                info!("Screenshot saved to {}", output_file.display());
+            }
+            Commands::List => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let info = list_storage(&client, &ip)
+                    .await
+                    .expect("Failed to list storage");
+                println!(
+                    "{:<24} {:>8} {:>7} {:>11} {:>10}",
+                    "KEY", "SIZE", "FRAMES", "FRAME TIME", "DIMENSIONS"
+                );
+                for item in info.items.iter() {
+                    if let Some(res) = &item.resource {
+                        println!(
+                            "{:<24} {:>8} {:>7} {:>9}ms {:>10}",
+                            item.key,
+                            item.size,
+                            res.frame_count,
+                            res.frame_time_ms,
+                            format!("{}x{}", res.width, res.height)
+                        );
+                    } else {
+                        println!("{:<24} {:>8} {:>7}", item.key, item.size, "?");
+                    }
+                }
+                println!(
+                    "{} items, {} of {} bytes used",
+                    info.items.len(),
+                    info.used_bytes,
+                    info.total_bytes
+                );
+            }
+            Commands::Download { name, output_dir } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let sprite = download_sprite(&client, &ip, &name)
+                    .await
+                    .expect("Failed to download sprite");
+                fs::create_dir_all(&output_dir).expect("Could not create output directory");
+                for (i, frame) in sprite.frames.iter().enumerate() {
+                    let file = output_dir.join(format!("{name}{}.qoi", i + 1));
+                    fs::write(&file, frame).expect("Could not write frame to file");
+                    info!("Wrote {}", file.display());
+                }
+                println!(
+                    "Downloaded {} frames of '{name}' with a frame time of {}ms",
+                    sprite.frames.len(),
+                    sprite.frame_time_ms
+                );
+            }
+            Commands::Exists { name } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let text = storage_request(&client, &ip, "exists", &name)
+                    .await
+                    .expect("Failed to check if sprite exists");
+                println!("{text}");
+            }
+            Commands::Delete { name } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let text = storage_request(&client, &ip, "delete", &name)
+                    .await
+                    .expect("Failed to delete sprite");
+                println!("{text}");
+            }
+            Commands::FlashFirmware { input_file } => {
+                let ip = resolve_display(&conf).await;
+                let firmware = fs::read(input_file).expect("Could not read firmware file");
+                if firmware.first() != Some(&ESP_IMAGE_MAGIC) {
+                    error!("The given file is not an ESP app image");
+                    return;
+                }
+                let client = conf.display.http_client();
+                match flash_firmware(&client, &ip, firmware).await {
+                    Ok(text) => info!("{text}"),
+                    Err(e) => error!("Firmware update failed: {e}"),
+                }
+            }
+            Commands::Logs { level, follow } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let mut since = 0;
+                loop {
+                    match get_logs(&client, &ip, &level, since, follow).await {
+                        Ok(records) => {
+                            for record in records.iter() {
+                                println!(
+                                    "{:>10.3} {:<5} {}: {}",
+                                    record.uptime_ms as f64 / 1000.0,
+                                    format!("{:?}", record.level).to_uppercase(),
+                                    record.target,
+                                    record.message
+                                );
+                            }
+                            since = records.last().map_or(since, |record| record.id);
+                        }
+                        Err(e) if follow => {
+                            warn!("Failed to get logs: {e}");
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                        }
+                        Err(e) => {
+                            error!("Failed to get logs: {e}");
+                            return;
+                        }
+                    }
+                    if !follow {
+                        break;
+                    }
+                }
+            }
+            Commands::Wifi => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let status = wifi_status(&client, &ip)
+                    .await
+                    .expect("Failed to get WIFI status");
+                match status.connected {
+                    Some(ref ssid) => println!("Connected to '{ssid}'"),
+                    None => println!("Not connected"),
+                }
+                println!("{:<32} {:>8}", "SSID", "PRIORITY");
+                for network in status.networks.iter() {
+                    println!("{:<32} {:>8}", network.ssid, network.priority);
+                }
+            }
+            Commands::WifiAdd {
+                ssid,
+                password,
+                priority,
+            } => {
+                let ip = resolve_display(&conf).await;
+                let network = WifiNetwork {
+                    ssid,
+                    password: password.unwrap_or_default(),
+                    priority,
+                };
+                let client = conf.display.http_client();
+                let text = add_wifi_network(&client, &ip, &network)
+                    .await
+                    .expect("Failed to add WIFI network");
+                println!("{text}");
+            }
+            Commands::WifiRemove { ssid } => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let res = client
+                    .post(format!("http://{ip}/api/wifi/delete"))
+                    .query(&[("ssid", &ssid)])
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .expect("Failed to send request");
+                let status = res.status();
+                let text = res.text().await.expect("Failed to read response");
+                if status.is_success() {
+                    println!("{text}");
+                } else {
+                    error!("Display responded with {status}: {text}");
+                }
+            }
+            Commands::Schedule => {
+                let ip = resolve_display(&conf).await;
+                let client = conf.display.http_client();
+                let schedule = get_schedule(&client, &ip)
+                    .await
+                    .expect("Failed to get schedule");
+                println!("UTC offset: {} minutes", schedule.utc_offset_minutes);
+                println!("Daylight saving time: {:?}", schedule.daylight_saving);
+                println!(
+                    "{:<5} {:<5} {:<3} {:>10}  DAYS",
+                    "START", "END", "ON", "BRIGHTNESS"
+                );
+                for entry in schedule.entries.iter() {
+                    let days = if entry.days.is_empty() {
+                        String::from("every day")
+                    } else {
+                        entry
+                            .days
+                            .iter()
+                            .map(|day| format!("{day:?}"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    };
+                    let brightness = entry
+                        .brightness
+                        .map(|brightness| format!("{brightness}%"))
+                        .unwrap_or_else(|| String::from("default"));
+                    println!(
+                        "{:02}:{:02} {:02}:{:02} {:<3} {:>10}  {days}",
+                        entry.start.hour,
+                        entry.start.minute,
+                        entry.end.hour,
+                        entry.end.minute,
+                        if entry.on { "yes" } else { "no" },
+                        brightness,
+                    );
+                }
+            }
+            Commands::SetSchedule { input_file } => {
+                let ip = resolve_display(&conf).await;
+                let f = File::open(input_file).expect("Could not open file");
+                let schedule: Schedule =
+                    serde_json::from_reader(BufReader::new(f)).expect("Failed to parse schedule");
+                let client = conf.display.http_client();
+                let res = client
+                    .post(format!("http://{ip}/api/schedule"))
+                    .body(to_allocvec(&schedule).expect("Failed to serialize schedule"))
+                    .timeout(Duration::from_secs(10))
+                    .send()
+                    .await
+                    .expect("Failed to send schedule");
+                let status = res.status();
+                let text = res.text().await.expect("Failed to read response");
+                if status.is_success() {
+                    println!("{text}");
+                } else {
+                    error!("Display responded with {status}: {text}");
+                }
+            }
             Commands::BulkUpload {
                 meta_file,
                 format,
                 filter,
             } => {
+                let ip = resolve_display(&conf).await;
                 let mut config = get_sprites(&meta_file);
                 if let Some(filter) = filter {
                     config = config
@@ -204,7 +617,7 @@ impl Cli {
                         .filter(|s| filter.contains(&s.0))
                         .collect();
                 }
-                let client = reqwest::Client::new();
+                let client = conf.display.http_client();
                 if format {
                     info!("Formatting flash. This may take a while...");
                     format_flash(&client, &ip)
@@ -227,7 +640,7 @@ impl Cli {
 async fn sprite_upload(
     client: &reqwest::Client,
     input_files: &Vec<PathBuf>,
-    ip: &Ipv4Addr,
+    ip: &SocketAddrV4,
     name: &String,
     frame_time: u16,
 ) {
@@ -243,11 +656,14 @@ async fn sprite_upload(
     let buf =
         postcard::to_allocvec(&sprite).expect("Could not serialize sprite to postcard format");
     let mut res = None;
+    let mut offset = 0;
     for _ in 0..3 {
         res = Some(
             client
-                .post(format!("http://{ip}/api/storage/upload?key={name}"))
-                .body(buf.clone())
+                .post(format!("http://{ip}/api/storage/upload"))
+                .query(&[("key", name)])
+                .query(&[("offset", offset)])
+                .body(buf[offset as usize..].to_vec())
                 .timeout(Duration::from_secs(10))
                 .send()
                 .await,
@@ -257,6 +673,15 @@ async fn sprite_upload(
         } else {
             warn!("Failed to send sprite data");
         }
+        // Only send the part the display did not store before the connection broke
+        offset = match upload_offset(client, ip, name).await {
+            Ok(offset) if offset as usize <= buf.len() => offset,
+            Ok(_) => 0,
+            Err(e) => {
+                warn!("Could not check how much was stored, sending everything again: {e}");
+                0
+            }
+        };
     }
     match res {
         Some(Err(e)) => {
@@ -272,7 +697,33 @@ async fn sprite_upload(
     }
 }
 
-async fn format_flash(client: &reqwest::Client, ip: &Ipv4Addr) -> Result<()> {
+/// Number of bytes of an interrupted upload the display stored and can resume at
+async fn upload_offset(client: &reqwest::Client, ip: &SocketAddrV4, name: &str) -> Result<u32> {
+    let res = client
+        .get(format!("http://{ip}/api/storage/upload"))
+        .query(&[("key", name)])
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    let progress: UploadProgress = serde_json::from_slice(&res.bytes().await?)?;
+    Ok(progress.offset)
+}
+
+async fn resolve_display(conf: &ServerConfig) -> SocketAddrV4 {
+    conf.display
+        .resolve()
+        .await
+        .expect("Could not resolve the address of the display")
+}
+
+async fn format_flash(client: &reqwest::Client, ip: &SocketAddrV4) -> Result<()> {
     let res = client
         .post(format!("http://{ip}/api/storage/format"))
         .send()
@@ -288,6 +739,213 @@ async fn format_flash(client: &reqwest::Client, ip: &Ipv4Addr) -> Result<()> {
     }
 }
 
+async fn list_storage(client: &reqwest::Client, ip: &SocketAddrV4) -> Result<StorageInfo> {
+    let res = client
+        .get(format!("http://{ip}/api/storage/list"))
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    Ok(serde_json::from_slice(&res.bytes().await?)?)
+}
+
+async fn download_sprite(
+    client: &reqwest::Client,
+    ip: &SocketAddrV4,
+    name: &str,
+) -> Result<Resource> {
+    let res = client
+        .get(format!("http://{ip}/api/storage/download"))
+        .query(&[("key", name)])
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    Ok(postcard::from_bytes(&res.bytes().await?)?)
+}
+
+/// Send a POST request to one of the storage endpoints which take a key as parameter
+async fn storage_request(
+    client: &reqwest::Client,
+    ip: &SocketAddrV4,
+    action: &str,
+    name: &str,
+) -> Result<String> {
+    let res = client
+        .post(format!("http://{ip}/api/storage/{action}"))
+        .query(&[("key", name)])
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    let text = res.text().await?;
+    if status.is_success() {
+        Ok(text)
+    } else {
+        Err(anyhow!("Display responded with {status}: {text}"))
+    }
+}
+
+async fn wifi_status(client: &reqwest::Client, ip: &SocketAddrV4) -> Result<WifiStatus> {
+    let res = client
+        .get(format!("http://{ip}/api/wifi"))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    Ok(serde_json::from_slice(&res.bytes().await?)?)
+}
+
+async fn get_logs(
+    client: &reqwest::Client,
+    ip: &SocketAddrV4,
+    level: &str,
+    since: u32,
+    follow: bool,
+) -> Result<Vec<LogRecord>> {
+    let res = client
+        .get(format!("http://{ip}/api/logs"))
+        .query(&[
+            ("level", level.to_string()),
+            ("since", since.to_string()),
+            ("follow", follow.to_string()),
+        ])
+        // The display holds follow requests for up to 10 seconds
+        .timeout(Duration::from_secs(20))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    Ok(serde_json::from_slice(&res.bytes().await?)?)
+}
+
+async fn get_schedule(client: &reqwest::Client, ip: &SocketAddrV4) -> Result<Schedule> {
+    let res = client
+        .get(format!("http://{ip}/api/schedule"))
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    if !status.is_success() {
+        return Err(anyhow!(
+            "Display responded with {status}: {}",
+            res.text().await?
+        ));
+    }
+    Ok(serde_json::from_slice(&res.bytes().await?)?)
+}
+
+async fn add_wifi_network(
+    client: &reqwest::Client,
+    ip: &SocketAddrV4,
+    network: &WifiNetwork,
+) -> Result<String> {
+    let res = client
+        .post(format!("http://{ip}/api/wifi/add"))
+        .body(to_allocvec(network)?)
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await?;
+    let status = res.status();
+    let text = res.text().await?;
+    if status.is_success() {
+        Ok(text)
+    } else {
+        Err(anyhow!("Display responded with {status}: {text}"))
+    }
+}
+
+/// Upload a firmware image to the display while showing the progress
+async fn flash_firmware(
+    client: &reqwest::Client,
+    ip: &SocketAddrV4,
+    firmware: Vec<u8>,
+) -> Result<String> {
+    let size = firmware.len();
+    let progress = ProgressBar::new(size as u64);
+    let chunks: Vec<Vec<u8>> = firmware.chunks(4096).map(|c| c.to_vec()).collect();
+    let bar = progress.clone();
+    let body = stream::iter(chunks.into_iter().map(move |chunk| {
+        bar.inc(chunk.len() as u64);
+        Ok::<_, std::io::Error>(chunk)
+    }));
+    let res = client
+        .post(format!("http://{ip}/api/ota"))
+        .header(reqwest::header::CONTENT_LENGTH, size)
+        .body(reqwest::Body::wrap_stream(body))
+        .timeout(Duration::from_secs(300))
+        .send()
+        .await;
+    progress.finish();
+    let res = res?;
+    let status = res.status();
+    let text = res.text().await?;
+    if status.is_success() {
+        Ok(text)
+    } else {
+        Err(anyhow!("Display responded with {status}: {text}"))
+    }
+}
+
+fn write_png(output_file: &Path, header: &qoi::Header, pixels: &[u8]) -> Result<()> {
+    let f = File::create(output_file)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(f), header.width, header.height);
+    encoder.set_color(match header.channels {
+        qoi::Channels::Rgb => png::ColorType::Rgb,
+        qoi::Channels::Rgba => png::ColorType::Rgba,
+    });
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Read the sprites with the given names from a sprites.toml file and their frame files
+pub fn load_sprites(meta_file: &Path, names: &[String]) -> HashMap<String, Resource> {
+    let config = get_sprites(meta_file);
+    let base_path = meta_file
+        .parent()
+        .expect("Could not get folder of metadata file");
+    let mut resources = HashMap::new();
+    for name in names {
+        let Some(sprite) = config.get(name) else {
+            warn!("Sprite {name} is not listed in {}", meta_file.display());
+            continue;
+        };
+        let frames = sprite
+            .frames
+            .iter()
+            .map(|frame| fs::read(base_path.join(frame)).expect("Could not read frame file"))
+            .collect();
+        resources.insert(name.clone(), Resource::new(frames, sprite.frame_time));
+    }
+    resources
+}
+
 fn get_sprites(meta_file: &Path) -> SpriteCollection {
     let meta_file = meta_file
         .canonicalize()
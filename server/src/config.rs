@@ -1,14 +1,98 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
 
+use anyhow::{Result, anyhow};
+use interface::StaleFallback;
+use mdns_sd::{HostnameResolutionEvent, ServiceDaemon};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue};
 use serde::Deserialize;
+use tokio::net::lookup_host;
+
+/// How long to wait for the display to answer an mDNS query in milliseconds
+const MDNS_TIMEOUT_MS: u64 = 3000;
+
+/// Port of the REST API of the display, unless the address sets another one
+const DEFAULT_PORT: u16 = 80;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct DisplayConfig {
-    pub ip: Ipv4Addr,
+    /// IPv4 address, hostname or mDNS name (e.g. led-wall.local) of the display,
+    /// optionally followed by a port like `127.0.0.1:8080` for the simulator
+    #[serde(alias = "ip")]
+    pub address: String,
+    /// Token for the REST API of the display, if it requires one
+    pub api_token: Option<String>,
+    /// Seconds after which the display marks the shown data as outdated if the server
+    /// stops sending updates
+    pub max_age: Option<u32>,
+    /// How the display marks outdated data
+    #[serde(default)]
+    pub stale_fallback: StaleFallback,
+}
+
+impl DisplayConfig {
+    /// HTTP client which sends the API token with every request to the display
+    pub fn http_client(&self) -> reqwest::Client {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &self.api_token {
+            let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .expect("API token contains invalid characters");
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .expect("Failed to build HTTP client")
+    }
+
+    /// Resolve the configured address of the display to an IPv4 address and port
+    pub async fn resolve(&self) -> Result<SocketAddrV4> {
+        let (address, port) = match self.address.rsplit_once(':') {
+            Some((address, port)) => (address, port.parse()?),
+            None => (self.address.as_str(), DEFAULT_PORT),
+        };
+        let address = address.trim_end_matches('.');
+        if let Ok(ip) = address.parse() {
+            return Ok(SocketAddrV4::new(ip, port));
+        }
+        if address.ends_with(".local") {
+            return Ok(SocketAddrV4::new(resolve_mdns(address).await?, port));
+        }
+        lookup_host((address, port))
+            .await?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| anyhow!("No IPv4 address found for {address}"))
+    }
+}
+
+async fn resolve_mdns(hostname: &str) -> Result<Ipv4Addr> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.resolve_hostname(&format!("{hostname}."), Some(MDNS_TIMEOUT_MS))?;
+    let mut result = Err(anyhow!("{hostname} did not answer the mDNS query"));
+    while let Ok(event) = receiver.recv_async().await {
+        match event {
+            HostnameResolutionEvent::AddressesFound(_, addresses) => {
+                if let Some(ip) = addresses.iter().find_map(|addr| match addr.to_ip_addr() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                }) {
+                    result = Ok(ip);
+                    break;
+                }
+            }
+            HostnameResolutionEvent::SearchTimeout(_) => break,
+            _ => {}
+        }
+    }
+    daemon.shutdown().ok();
+    result
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -1,6 +1,7 @@
 mod cli;
 mod config;
 mod display;
+mod preview;
 mod server;
 mod weather;
 mod wl;
@@ -20,10 +21,10 @@ async fn main() {
 mod tests {
     use std::{collections::BTreeMap, fs::File, io::BufReader};
 
-    use interface::{Configuration, Element, FontName, Point, Screen, TextStyle};
-    use jsonschema;
+    use interface::{Configuration, Element, FontName, Point, Screen, StaleFallback, TextStyle};
     use schemars::schema_for;
-    use serde_json;
+
+    use crate::config::DisplayConfig;
 
     /// validate the test json file against the schema
     #[test]
@@ -58,9 +59,27 @@ mod tests {
                     align: None,
                 }],
             }],
+            max_age: Some(120),
+            stale_fallback: StaleFallback::Dim,
         };
         let buf = postcard::to_allocvec(&config).unwrap();
         let config2: Configuration = postcard::from_bytes(&buf).unwrap();
         assert_eq!(config, config2);
     }
+
+    /// The port of the display defaults to 80 but can be set for the simulator
+    #[tokio::test]
+    async fn test_resolve_display_address_with_port() {
+        let display = |address: &str| DisplayConfig {
+            address: address.into(),
+            api_token: None,
+            max_age: None,
+            stale_fallback: StaleFallback::default(),
+        };
+        let resolved = display("127.0.0.1").resolve().await.unwrap();
+        assert_eq!(resolved, "127.0.0.1:80".parse().unwrap());
+        let resolved = display("127.0.0.1:8080").resolve().await.unwrap();
+        assert_eq!(resolved, "127.0.0.1:8080".parse().unwrap());
+        assert!(display("127.0.0.1:http").resolve().await.is_err());
+    }
 }
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use interface::embedded::CheckedScreenConfig;
+use interface::render::{Canvas, Lookup, SpriteSource, animated_areas, render_config};
+use interface::sprite::{BakedResource, bake_as, no_image_sprite};
+use interface::{Configuration, Element, FrameFormat, Resource};
+use log::{error, warn};
+
+/// Time between two checks for new animation frames, like the display task of the firmware
+const TICK_MS: u64 = 30;
+
+/// Panel a configuration is rendered for
+pub struct PanelSettings {
+    pub size: Size,
+    /// Bits per color channel the panel shows
+    pub color_depth: u8,
+}
+
+/// Sprites a configuration is rendered with, prepared the same way the display does it
+pub struct PreviewSprites {
+    resources: HashMap<String, Resource>,
+    baked: HashMap<String, BakedResource>,
+    color_depth: u8,
+}
+
+impl PreviewSprites {
+    pub fn new(resources: HashMap<String, Resource>, color_depth: u8) -> Self {
+        Self {
+            resources,
+            baked: HashMap::new(),
+            color_depth,
+        }
+    }
+}
+
+impl SpriteSource for PreviewSprites {
+    /// All frame formats draw the same pixels, so a sprite is only prepared once,
+    /// in the format the first element showing it asks for or as QOI
+    fn get(&mut self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> Lookup<'_> {
+        if !self.baked.contains_key(name) {
+            let Some(res) = self.resources.remove(name) else {
+                return Lookup::Missing;
+            };
+            let format = format.unwrap_or(FrameFormat::Qoi);
+            match bake_as(res, format, self.color_depth) {
+                Ok(sprite) => {
+                    self.baked.insert(name.into(), sprite);
+                }
+                Err(e) => {
+                    error!("Could not decode '{name}' sprite: {e}");
+                    return Lookup::Missing;
+                }
+            }
+        }
+        match self
+            .baked
+            .get_mut(name)
+            .map(|sprite| sprite.get_image(now_ms))
+        {
+            Some(Ok(img)) => Lookup::Ready(img),
+            _ => Lookup::Missing,
+        }
+    }
+
+    fn frame_due(&self, name: &str, _format: Option<FrameFormat>, now_ms: u64) -> bool {
+        self.baked
+            .get(name)
+            .is_some_and(|sprite| sprite.needs_update(now_ms))
+    }
+}
+
+/// Names of all sprites the configuration shows
+pub fn sprite_names(config: &Configuration) -> Vec<String> {
+    let mut names: Vec<String> = config
+        .screens
+        .iter()
+        .flat_map(|screen| screen.elements.iter())
+        .filter_map(|element| match element {
+            Element::Sprite { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// A rendered image of the panel and how long it is shown in ms
+pub struct PreviewFrame {
+    pub pixels: Vec<Rgb888>,
+    pub duration_ms: u64,
+}
+
+/// Render the configuration like the display does, with a new frame whenever a sprite shows
+/// its next animation frame within `duration_ms`. A single frame is returned for
+/// configurations without animations
+pub fn render_preview(
+    config: Configuration,
+    sprites: &mut PreviewSprites,
+    panel: &PanelSettings,
+    duration_ms: u64,
+) -> Result<Vec<PreviewFrame>> {
+    let config = CheckedScreenConfig::new(config).map_err(|e| anyhow!("{e}"))?;
+    let mut canvas = Canvas::new(panel.size, panel.color_depth);
+    let mut err_img = no_image_sprite();
+    let mut bounds = Vec::new();
+    let mut frames = Vec::new();
+    let mut now_ms = 0;
+    loop {
+        canvas.clear(Rgb888::BLACK).ok();
+        render_config(
+            &mut canvas,
+            &config,
+            sprites,
+            &mut err_img,
+            now_ms,
+            &mut bounds,
+        );
+        let shown_at = now_ms;
+        loop {
+            now_ms += TICK_MS;
+            if now_ms >= duration_ms
+                || !animated_areas(&config, &bounds, sprites, now_ms).is_empty()
+            {
+                break;
+            }
+        }
+        frames.push(PreviewFrame {
+            pixels: canvas.pixels().to_vec(),
+            duration_ms: now_ms.min(duration_ms).saturating_sub(shown_at),
+        });
+        if now_ms >= duration_ms {
+            break;
+        }
+    }
+    Ok(frames)
+}
+
+/// Enlarge every pixel to a square of `scale` pixels and return the RGB bytes
+fn scale_pixels(pixels: &[Rgb888], size: Size, scale: u32) -> Vec<u8> {
+    let (width, scale) = (size.width as usize, scale as usize);
+    let mut out = Vec::with_capacity(pixels.len() * scale * scale * 3);
+    for row in pixels.chunks_exact(width) {
+        let scaled_row: Vec<u8> = row
+            .iter()
+            .flat_map(|color| [color.r(), color.g(), color.b()].repeat(scale))
+            .collect();
+        for _ in 0..scale {
+            out.extend_from_slice(&scaled_row);
+        }
+    }
+    out
+}
+
+pub fn write_png(output_file: &Path, frame: &PreviewFrame, size: Size, scale: u32) -> Result<()> {
+    let f = File::create(output_file)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(f), size.width * scale, size.height * scale);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&scale_pixels(&frame.pixels, size, scale))?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Write the frames as an endlessly repeating GIF. The colors are kept exactly as long as
+/// a frame has at most 256 of them
+pub fn write_gif(
+    output_file: &Path,
+    frames: &[PreviewFrame],
+    size: Size,
+    scale: u32,
+) -> Result<()> {
+    let width = u16::try_from(size.width * scale)?;
+    let height = u16::try_from(size.height * scale)?;
+    let f = File::create(output_file)?;
+    let mut encoder = gif::Encoder::new(BufWriter::new(f), width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    for frame in frames {
+        let rgb = scale_pixels(&frame.pixels, size, scale);
+        let mut palette: Vec<[u8; 3]> = Vec::new();
+        let mut indices = Vec::with_capacity(rgb.len() / 3);
+        for color in rgb.chunks_exact(3) {
+            let color = [color[0], color[1], color[2]];
+            let index = match palette.iter().position(|c| *c == color) {
+                Some(index) => index,
+                None => {
+                    palette.push(color);
+                    palette.len() - 1
+                }
+            };
+            indices.push(index);
+            if palette.len() > 256 {
+                break;
+            }
+        }
+        let mut gif_frame = if palette.len() <= 256 {
+            let indices: Vec<u8> = indices.into_iter().map(|index| index as u8).collect();
+            gif::Frame::from_palette_pixels(width, height, indices, palette.concat(), None)
+        } else {
+            warn!("Frame has more than 256 colors, the GIF only approximates them");
+            gif::Frame::from_rgb(width, height, &rgb)
+        };
+        // GIF delays are given in 10 ms
+        gif_frame.delay = u16::try_from(frame.duration_ms.div_ceil(10)).unwrap_or(u16::MAX);
+        encoder.write_frame(&gif_frame)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::BufReader;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::cli::load_sprites;
+
+    /// Fixture configurations and the images they have to render to
+    const GOLDEN_DIR: &str = "golden";
+    /// Actual and diff images of failed comparisons are written here
+    const DIFF_DIR: &str = "target/golden";
+    /// Set to rewrite the golden images with what is rendered now
+    const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+    /// Animations are compared for this long, with all frames stacked from top to bottom
+    const DURATION_MS: u64 = 1000;
+
+    const PANEL: PanelSettings = PanelSettings {
+        size: Size::new(192, 96),
+        color_depth: 2,
+    };
+
+    fn read_png(file: &Path) -> Result<(Size, Vec<u8>)> {
+        let decoder = png::Decoder::new(BufReader::new(File::open(file)?));
+        let mut reader = decoder.read_info()?;
+        let mut pixels = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let info = reader.next_frame(&mut pixels)?;
+        if info.color_type != png::ColorType::Rgb || info.bit_depth != png::BitDepth::Eight {
+            return Err(anyhow!("Golden images have to be 8 bit RGB"));
+        }
+        pixels.truncate(info.buffer_size());
+        Ok((Size::new(info.width, info.height), pixels))
+    }
+
+    fn write_rgb(file: &Path, size: Size, pixels: &[u8]) {
+        let f = File::create(file).expect("Could not create image file");
+        let mut encoder = png::Encoder::new(BufWriter::new(f), size.width, size.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().expect("Could not write PNG header");
+        writer
+            .write_image_data(pixels)
+            .expect("Could not write PNG data");
+    }
+
+    /// Render a fixture, with the sprites from the sprites of the repo
+    fn render_fixture(fixture: &Path) -> (Size, Vec<u8>) {
+        let f = File::open(fixture).expect("Could not open fixture");
+        let config: Configuration =
+            serde_json::from_reader(BufReader::new(f)).expect("Could not parse fixture");
+        let resources = load_sprites(
+            Path::new("../resources/sprites/sprites.toml"),
+            &sprite_names(&config),
+        );
+        let mut sprites = PreviewSprites::new(resources, PANEL.color_depth);
+        let frames = render_preview(config, &mut sprites, &PANEL, DURATION_MS)
+            .expect("Could not render fixture");
+        let size = Size::new(PANEL.size.width, PANEL.size.height * frames.len() as u32);
+        let pixels = frames
+            .iter()
+            .flat_map(|frame| frame.pixels.iter())
+            .flat_map(|color| [color.r(), color.g(), color.b()])
+            .collect();
+        (size, pixels)
+    }
+
+    /// Differing pixels in red on a darkened copy of the golden image
+    fn diff_image(expected: &[u8], actual: &[u8]) -> Vec<u8> {
+        expected
+            .chunks_exact(3)
+            .zip(actual.chunks_exact(3))
+            .flat_map(|(expected, actual)| {
+                if expected == actual {
+                    [expected[0] / 4, expected[1] / 4, expected[2] / 4]
+                } else {
+                    [255, 0, 0]
+                }
+            })
+            .collect()
+    }
+
+    /// Compare the rendering of every fixture to its golden image.
+    /// Run with `UPDATE_GOLDEN=1` to accept the current rendering as the new golden images
+    #[test]
+    fn test_golden_images() {
+        let update = std::env::var_os(UPDATE_VAR).is_some();
+        let mut fixtures: Vec<PathBuf> = fs::read_dir(GOLDEN_DIR)
+            .expect("Could not list golden images")
+            .map(|entry| entry.expect("Could not read directory entry").path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        fixtures.sort();
+        assert!(!fixtures.is_empty(), "No fixtures found in {GOLDEN_DIR}");
+
+        let mut failures = Vec::new();
+        for fixture in fixtures.iter() {
+            let golden = fixture.with_extension("png");
+            let (size, actual) = render_fixture(fixture);
+            if update {
+                write_rgb(&golden, size, &actual);
+                continue;
+            }
+            let name = fixture.file_stem().unwrap().to_string_lossy();
+            let (expected_size, expected) = match read_png(&golden) {
+                Ok(image) => image,
+                Err(e) => {
+                    failures.push(format!("{name}: could not read golden image: {e}"));
+                    continue;
+                }
+            };
+            if expected_size == size && expected == actual {
+                continue;
+            }
+            fs::create_dir_all(DIFF_DIR).expect("Could not create diff directory");
+            let actual_file = Path::new(DIFF_DIR).join(format!("{name}.actual.png"));
+            write_rgb(&actual_file, size, &actual);
+            if expected_size == size {
+                let diff_file = Path::new(DIFF_DIR).join(format!("{name}.diff.png"));
+                write_rgb(&diff_file, size, &diff_image(&expected, &actual));
+                let differing = expected
+                    .chunks_exact(3)
+                    .zip(actual.chunks_exact(3))
+                    .filter(|(expected, actual)| expected != actual)
+                    .count();
+                failures.push(format!(
+                    "{name}: {differing} pixels differ, see {}",
+                    diff_file.display()
+                ));
+            } else {
+                failures.push(format!(
+                    "{name}: rendered {}x{} instead of {}x{}, see {}",
+                    size.width,
+                    size.height,
+                    expected_size.width,
+                    expected_size.height,
+                    actual_file.display()
+                ));
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "Rendering does not match the golden images. Run with {UPDATE_VAR}=1 if the \
+            change is intended:\n{}",
+            failures.join("\n")
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use anyhow::Result;
 use log::{error, info};
-use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
 use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::time::{self, Duration};
 use tokio_util::sync::CancellationToken;
 
-use crate::config::ServerConfig;
+use crate::config::{DisplayConfig, ServerConfig};
 use crate::display::build_display;
 use crate::weather::{WeatherData, WeatherUpdateResult, get_weather_data};
 use crate::wl::{TransportData, get_transport_data};
@@ -138,14 +138,16 @@ pub async fn maintain_display(token: CancellationToken, tx: Sender<DataUpdate>)
 
 pub async fn push_display_update(
     token: CancellationToken,
-    ip: Ipv4Addr,
+    display: DisplayConfig,
     mut rx: Receiver<DataUpdate>,
 ) -> Result<()> {
+    // Resolved lazily and again after a failed send, in case the display got a new address
+    let mut resolved_ip: Option<SocketAddrV4> = None;
     let mut current_weather = None;
     let mut current_transport = None;
     let mut last_send_failed = false;
     let mut retry_ticker = time::interval(RETRY_POLL_RATE);
-    let client = reqwest::Client::new();
+    let client = display.http_client();
     loop {
         select! {
             data = rx.recv() => {
@@ -181,7 +183,8 @@ pub async fn push_display_update(
         if let Some(current_weather) = &current_weather
             && let Some(current_transport) = &current_transport
         {
-            let display_data = build_display(current_weather, current_transport);
+            let display_data = build_display(current_weather, current_transport)
+                .with_max_age(display.max_age, display.stale_fallback);
             let buf = postcard::to_allocvec(&display_data);
             let buf = match buf {
                 Ok(buf) => buf,
@@ -190,6 +193,20 @@ pub async fn push_display_update(
                     continue;
                 }
             };
+            let ip = match resolved_ip {
+                Some(ip) => ip,
+                None => match display.resolve().await {
+                    Ok(ip) => {
+                        info!("Resolved display {} to {ip}", display.address);
+                        *resolved_ip.insert(ip)
+                    }
+                    Err(e) => {
+                        error!("Failed to resolve display {}: {e}", display.address);
+                        last_send_failed = true;
+                        continue;
+                    }
+                },
+            };
             let res = client
                 .post(format!("http://{ip}/api/config"))
                 .body(buf)
@@ -201,6 +218,7 @@ pub async fn push_display_update(
                 Err(e) => {
                     error!("Failed to send display data: {e}");
                     last_send_failed = true;
+                    resolved_ip = None;
                     continue;
                 }
             };
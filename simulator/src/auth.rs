@@ -0,0 +1,89 @@
+use std::sync::OnceLock;
+
+use picoserve::{
+    ResponseSent,
+    io::Read,
+    request::RequestParts,
+    response::{IntoResponse, ResponseWriter, StatusCode},
+    routing::{Layer, Next},
+};
+
+/// Token settings of the simulated display, like the `[rest]` section of the firmware config
+pub struct AuthSettings {
+    /// Token clients have to send as `Authorization: Bearer <token>`.
+    /// Authentication is disabled if it is empty.
+    pub api_token: String,
+    /// Whether GET requests are allowed without a token
+    pub public_read_only: bool,
+}
+
+static SETTINGS: OnceLock<AuthSettings> = OnceLock::new();
+
+/// Set the token settings. Has to be called once before the REST API is served
+pub fn init(settings: AuthSettings) {
+    if SETTINGS.set(settings).is_err() {
+        panic!("The API token was set up twice");
+    }
+}
+
+/// Compare in constant time so the token can not be guessed byte by byte
+fn token_matches(token: &str, expected: &str) -> bool {
+    token.len() == expected.len()
+        && token
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn is_authorized(request: &RequestParts<'_>) -> bool {
+    let settings = SETTINGS.get().expect("The API token is not set up");
+    if settings.api_token.is_empty() {
+        return true;
+    }
+    if request.path() == "/" || (settings.public_read_only && request.method() == "GET") {
+        return true;
+    }
+    let Some(value) = request.headers().get("Authorization") else {
+        return false;
+    };
+    value
+        .as_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token_matches(token, &settings.api_token))
+}
+
+/// Rejects requests without a valid API token with 401 before they reach the handler
+pub struct AuthLayer;
+
+impl<State, PathParameters> Layer<State, PathParameters> for AuthLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        if is_authorized(&request_parts) {
+            return next.run(state, path_parameters, response_writer).await;
+        }
+        let connection = next.into_connection().await?;
+        (
+            StatusCode::UNAUTHORIZED,
+            ("WWW-Authenticate", "Bearer"),
+            "Missing or invalid API token",
+        )
+            .write_to(connection, response_writer)
+            .await
+    }
+}
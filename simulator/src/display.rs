@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+
+use anyhow::Result;
+use embedded_graphics::pixelcolor::Rgb888;
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::Rectangle;
+use interface::embedded::CheckedScreenConfig;
+use interface::render::{Canvas, Lookup, SpriteSource, animated_areas, render_config};
+use interface::sprite::{BakedResource, bake_as, no_image_sprite};
+use interface::{FrameFormat, Resource};
+use log::{error, info, warn};
+
+/// Settings of the simulated display
+pub struct DisplaySettings {
+    pub size: Size,
+    /// Bits per color channel the panel shows
+    pub color_depth: u8,
+    /// Format sprite frames are kept in if the sprite element does not set one
+    pub frame_format: FrameFormat,
+    /// PNG file the screen is written to whenever it changes
+    pub output: Option<PathBuf>,
+}
+
+/// Sprites uploaded to the simulator, kept in memory instead of flash
+pub struct Sprites {
+    /// Sprites as uploaded, in the postcard format
+    pub stored: BTreeMap<String, Vec<u8>>,
+    /// Complete chunks of interrupted uploads, kept like on the display so they can be resumed
+    pub unfinished: BTreeMap<String, Vec<u8>>,
+    /// Sprites which were drawn already, with the format they were prepared in
+    baked: BTreeMap<String, (FrameFormat, BakedResource)>,
+    default_format: FrameFormat,
+    color_depth: u8,
+}
+
+impl Sprites {
+    /// Drop a prepared sprite which was changed or deleted, or all of them if no name is given
+    pub fn invalidate(&mut self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.baked.remove(name);
+            }
+            None => self.baked.clear(),
+        }
+    }
+}
+
+impl SpriteSource for Sprites {
+    /// Sprites are prepared right away, so they are never shown as loading
+    fn get(&mut self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> Lookup<'_> {
+        let format = format.unwrap_or(self.default_format);
+        if self
+            .baked
+            .get(name)
+            .is_none_or(|(baked, _)| *baked != format)
+        {
+            let Some(data) = self.stored.get(name) else {
+                return Lookup::Missing;
+            };
+            let sprite = match postcard::from_bytes::<Resource>(data) {
+                Ok(res) => bake_as(res, format, self.color_depth),
+                Err(e) => {
+                    error!("Could not parse '{name}' sprite: {e}");
+                    return Lookup::Missing;
+                }
+            };
+            match sprite {
+                Ok(sprite) => {
+                    if sprite.format() != format {
+                        warn!(
+                            "Sprite {name} has too many colors for {format:?}, keeping it as {:?}",
+                            sprite.format()
+                        );
+                    }
+                    self.baked.insert(name.into(), (format, sprite));
+                }
+                Err(e) => {
+                    error!("Could not decode '{name}' sprite: {e}");
+                    return Lookup::Missing;
+                }
+            }
+        }
+        match self
+            .baked
+            .get_mut(name)
+            .map(|(_, sprite)| sprite.get_image(now_ms))
+        {
+            Some(Ok(img)) => Lookup::Ready(img),
+            _ => Lookup::Missing,
+        }
+    }
+
+    fn frame_due(&self, name: &str, format: Option<FrameFormat>, now_ms: u64) -> bool {
+        let format = format.unwrap_or(self.default_format);
+        self.baked
+            .get(name)
+            .is_some_and(|(baked, sprite)| *baked == format && sprite.needs_update(now_ms))
+    }
+}
+
+/// State of the simulated display, shared by the REST API and the render loop
+pub struct Display {
+    pub canvas: Canvas,
+    pub sprites: Sprites,
+    config: Option<CheckedScreenConfig>,
+    err_img: BakedResource,
+    /// Areas covered by the elements of the config, in the order of the elements
+    bounds: Vec<Rectangle>,
+    /// The screen has to be drawn again
+    needs_render: bool,
+    output: Option<PathBuf>,
+    started: Instant,
+}
+
+static DISPLAY: OnceLock<Mutex<Display>> = OnceLock::new();
+
+/// Set up the simulated display. Has to be called once before anything else uses it
+pub fn init(settings: DisplaySettings) {
+    let display = Display {
+        canvas: Canvas::new(settings.size, settings.color_depth),
+        sprites: Sprites {
+            stored: BTreeMap::new(),
+            unfinished: BTreeMap::new(),
+            baked: BTreeMap::new(),
+            default_format: settings.frame_format,
+            color_depth: settings.color_depth,
+        },
+        config: None,
+        err_img: no_image_sprite(),
+        bounds: Vec::new(),
+        needs_render: true,
+        output: settings.output,
+        started: Instant::now(),
+    };
+    if DISPLAY.set(Mutex::new(display)).is_err() {
+        panic!("The display was set up twice");
+    }
+}
+
+pub fn display() -> MutexGuard<'static, Display> {
+    DISPLAY
+        .get()
+        .expect("The display is not set up")
+        .lock()
+        .expect("The display lock is poisoned")
+}
+
+impl Display {
+    /// Show a new config, or a blank screen if there is none
+    pub fn set_config(&mut self, config: Option<CheckedScreenConfig>) {
+        self.config = config;
+        self.needs_render = true;
+    }
+
+    /// Draw the screen again, as sprites it shows changed
+    pub fn redraw(&mut self) {
+        self.needs_render = true;
+    }
+
+    /// Draw the screen if the config changed or a sprite shows a new animation frame.
+    /// Called periodically like the display task of the firmware
+    pub fn render(&mut self) {
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        let animated = self.config.as_ref().is_some_and(|config| {
+            !animated_areas(config, &self.bounds, &self.sprites, now_ms).is_empty()
+        });
+        if !self.needs_render && !animated {
+            return;
+        }
+        self.needs_render = false;
+        self.canvas.clear(Rgb888::BLACK).ok();
+        match &self.config {
+            Some(config) => render_config(
+                &mut self.canvas,
+                config,
+                &mut self.sprites,
+                &mut self.err_img,
+                now_ms,
+                &mut self.bounds,
+            ),
+            None => self.bounds.clear(),
+        }
+        if let Some(output) = &self.output
+            && let Err(e) = self.write_png(output)
+        {
+            error!("Failed to write the screen to {}: {e}", output.display());
+        }
+    }
+
+    fn write_png(&self, output: &PathBuf) -> Result<()> {
+        let size = self.canvas.size();
+        let pixels: Vec<u8> = self
+            .canvas
+            .pixels()
+            .iter()
+            .flat_map(|color| [color.r(), color.g(), color.b()])
+            .collect();
+        let f = File::create(output)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(f), size.width, size.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        info!("Wrote the screen to {}", output.display());
+        Ok(())
+    }
+}
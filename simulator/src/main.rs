@@ -0,0 +1,123 @@
+mod auth;
+mod display;
+mod rest;
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow};
+use clap::Parser;
+use embedded_graphics::prelude::Size;
+use interface::FrameFormat;
+use log::{error, info};
+use tokio::net::TcpListener;
+use tokio::time::{Duration, interval};
+
+use crate::auth::AuthSettings;
+use crate::display::{DisplaySettings, display};
+
+/// How often the screen is checked for changes, like the display task of the firmware
+const RENDER_INTERVAL: Duration = Duration::from_millis(30);
+
+/// Simulate the Public transport display on the desktop.
+/// Offers the REST API of the display and renders the screen with the renderer of the firmware
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Port the REST API listens on
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+    /// Width of the panel in pixels
+    #[arg(long, default_value_t = 192)]
+    width: u32,
+    /// Height of the panel in pixels
+    #[arg(long, default_value_t = 96)]
+    height: u32,
+    /// Bits per color channel the panel shows
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..=8))]
+    color_depth: u8,
+    /// Format sprite frames are kept in if the config does not set one: qoi, rgb or rle
+    #[arg(long, default_value = "qoi", value_parser = parse_frame_format)]
+    frame_format: FrameFormat,
+    /// PNG file the screen is written to whenever it changes
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Token required to use the API, sent as "Authorization: Bearer <token>".
+    /// Everyone may use the API if it is empty
+    #[arg(long, default_value = "")]
+    api_token: String,
+    /// Allow GET requests without the token
+    #[arg(long)]
+    public_read_only: bool,
+}
+
+fn parse_frame_format(format: &str) -> Result<FrameFormat> {
+    match format {
+        "qoi" => Ok(FrameFormat::Qoi),
+        "rgb" => Ok(FrameFormat::Rgb),
+        "rle" => Ok(FrameFormat::Rle),
+        _ => Err(anyhow!("Frame format has to be qoi, rgb or rle")),
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+    display::init(DisplaySettings {
+        size: Size::new(cli.width, cli.height),
+        color_depth: cli.color_depth,
+        frame_format: cli.frame_format,
+        output: cli.output,
+    });
+    auth::init(AuthSettings {
+        api_token: cli.api_token,
+        public_read_only: cli.public_read_only,
+    });
+
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, cli.port);
+    let listener = TcpListener::bind(address).await?;
+    info!("Simulated display listening on {address}");
+
+    let app = Rc::new(rest::router());
+    let config = Rc::new(
+        picoserve::Config::new(picoserve::Timeouts {
+            start_read_request: Some(Duration::from_secs(5)),
+            persistent_start_read_request: Some(Duration::from_secs(1)),
+            read_request: Some(Duration::from_secs(1)),
+            write: Some(Duration::from_secs(1)),
+        })
+        .keep_connection_alive(),
+    );
+
+    let tasks = tokio::task::LocalSet::new();
+    tasks.spawn_local(async {
+        let mut ticker = interval(RENDER_INTERVAL);
+        loop {
+            ticker.tick().await;
+            display().render();
+        }
+    });
+    tasks
+        .run_until(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("Failed to accept connection: {e}");
+                        continue;
+                    }
+                };
+                let app = app.clone();
+                let config = config.clone();
+                tokio::task::spawn_local(async move {
+                    let mut buffer = [0; 2048];
+                    if let Err(e) = picoserve::serve(&app, &config, &mut buffer, stream).await {
+                        error!("Connection from {remote} failed: {e:?}");
+                    }
+                });
+            }
+        })
+        .await
+}
@@ -0,0 +1,503 @@
+use std::sync::Mutex;
+
+use crate::auth::AuthLayer;
+use crate::display::display;
+use interface::{
+    Configuration, CrashReport, DaylightSaving, Resource, ResourceInfo, Schedule, StorageInfo,
+    StoredItem, UploadProgress,
+    embedded::{CheckedScreenConfig, ScreenBuildError},
+};
+use log::info;
+use picoserve::{
+    extract::{FromRequest, FromRequestParts, Query},
+    io::Read,
+    response::{self, Content, ErrorWithStatusCode, Json},
+    routing::{PathRouter, get, post},
+};
+use postcard::from_bytes;
+
+pub fn router() -> picoserve::Router<impl PathRouter> {
+    picoserve::Router::new()
+        .route(
+            "/",
+            get(|| async move {
+                "This is the simulated Public transport display. Please make API requests to /api/.."
+            }),
+        )
+        .route("/api/state", post(on_off_handler))
+        .route("/api/config", post(config_handler))
+        .route("/api/heartbeat", post(heartbeat_handler))
+        .route("/api/settings", post(settings_handler))
+        .route(
+            "/api/schedule",
+            get(schedule_handler).post(schedule_update_handler),
+        )
+        .route("/api/screenshot", get(screenshot_handler))
+        .route("/api/crashes", get(crashes_handler))
+        .nest("/api/storage", storage_router())
+        .layer(AuthLayer)
+}
+
+/// The storage endpoints live in their own router. A single chain of all routes
+/// nests the types deeper than the compiler can lay out the connection future
+fn storage_router() -> picoserve::Router<impl PathRouter> {
+    picoserve::Router::new()
+        .route("/format", post(format_handler))
+        .route("/upload", get(upload_offset_handler).post(upload_handler))
+        .route("/exists", post(exists_handler))
+        .route("/delete", post(delete_handler))
+        .route("/list", get(list_handler))
+        .route("/download", get(download_handler))
+}
+
+/// Size of the chunks the display stores sprites in. Uploads can only be resumed at a multiple of it
+const CHUNK_SIZE: usize = 10240;
+
+/// Largest sprite the display can store
+const MAX_STORED_SIZE: usize = 64 * CHUNK_SIZE;
+
+/// The schedule is only kept, the simulator has no power state or brightness to switch
+static SCHEDULE: Mutex<Schedule> = Mutex::new(Schedule {
+    utc_offset_minutes: 0,
+    daylight_saving: DaylightSaving::None,
+    entries: Vec::new(),
+});
+
+/// Encoding of a request or response body
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Postcard,
+    Json,
+}
+
+impl Encoding {
+    /// Postcard is used unless the header asks for JSON
+    fn from_header(request_parts: &picoserve::request::RequestParts<'_>, name: &str) -> Self {
+        let is_json = request_parts.headers().get(name).is_some_and(|value| {
+            value
+                .as_str()
+                .is_ok_and(|value| value.contains("application/json"))
+        });
+        if is_json {
+            Encoding::Json
+        } else {
+            Encoding::Postcard
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Postcard => "application/octet-stream",
+            Encoding::Json => "application/json",
+        }
+    }
+}
+
+/// Encoding the client wants the response in as given by the `Accept` header
+pub struct Accept(pub Encoding);
+
+impl<'r, State> FromRequestParts<'r, State> for Accept {
+    type Rejection = core::convert::Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &picoserve::request::RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Accept(Encoding::from_header(request_parts, "Accept")))
+    }
+}
+
+/// Response body which is already encoded in the given encoding
+pub struct Encoded(pub Encoding, pub Vec<u8>);
+
+impl Content for Encoded {
+    fn content_type(&self) -> &'static str {
+        self.0.content_type()
+    }
+
+    fn content_length(&self) -> usize {
+        self.1.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.1.as_slice().write_content(writer).await
+    }
+}
+
+/// A QOI encoded image which can be sent as a HTTP response
+pub struct QoiImage(pub Vec<u8>);
+
+impl Content for QoiImage {
+    fn content_type(&self) -> &'static str {
+        "image/qoi"
+    }
+
+    fn content_length(&self) -> usize {
+        self.0.len()
+    }
+
+    async fn write_content<W: picoserve::io::Write>(self, writer: W) -> Result<(), W::Error> {
+        self.0.as_slice().write_content(writer).await
+    }
+}
+
+/// Read the whole request body, which may be larger than the HTTP buffer
+async fn read_body<R: Read>(
+    request_body: picoserve::request::RequestBody<'_, R>,
+) -> Result<Vec<u8>, R::Error> {
+    let mut reader = request_body.reader();
+    let mut data = Vec::with_capacity(reader.content_length());
+    loop {
+        let mut buf = [0u8; 4096];
+        let read_size = reader.read(&mut buf).await?;
+        if read_size == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..read_size]);
+    }
+}
+
+/// Request body which is decoded as JSON if the `Content-Type` is `application/json`
+/// and as postcard otherwise
+pub struct Payload<T>(pub T);
+
+#[derive(Debug, thiserror::Error, ErrorWithStatusCode)]
+#[status_code(BAD_REQUEST)]
+pub enum BadPayloadRequest {
+    #[error("Read Error")]
+    #[status_code(INTERNAL_SERVER_ERROR)]
+    ReadFailed,
+    #[error("Postcard deserialize failed: {0}")]
+    Postcard(#[from] postcard::Error),
+    #[error("JSON deserialize failed: {0}")]
+    Json(serde_json::Error),
+}
+
+impl<'r, State, T: serde::de::DeserializeOwned> FromRequest<'r, State> for Payload<T> {
+    type Rejection = BadPayloadRequest;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        request_parts: picoserve::request::RequestParts<'r>,
+        request_body: picoserve::request::RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let encoding = Encoding::from_header(&request_parts, "Content-Type");
+        let data = read_body(request_body)
+            .await
+            .map_err(|_| BadPayloadRequest::ReadFailed)?;
+        match encoding {
+            Encoding::Postcard => Ok(Payload(from_bytes(&data)?)),
+            Encoding::Json => Ok(Payload(
+                serde_json::from_slice(&data).map_err(BadPayloadRequest::Json)?,
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UploadQuery {
+    key: String,
+    /// Number of bytes already stored by an interrupted upload which is resumed
+    #[serde(default)]
+    offset: u32,
+}
+
+/// A completely uploaded sprite, converted to postcard if it was sent as JSON.
+/// A resumed upload is joined with the part stored before its offset
+pub struct SpriteUpload {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error, ErrorWithStatusCode)]
+#[status_code(BAD_REQUEST)]
+pub enum BadSpriteUpload {
+    #[error("Read Error")]
+    #[status_code(INTERNAL_SERVER_ERROR)]
+    ReadError,
+    #[error("Missing key or invalid offset")]
+    BadQuery,
+    #[error("Sprite of {0} bytes exceeds the limit of {MAX_STORED_SIZE} bytes")]
+    #[status_code(PAYLOAD_TOO_LARGE)]
+    TooLarge(usize),
+    #[error("JSON deserialize failed: {0}")]
+    JsonError(serde_json::Error),
+    #[error("Offset {0} is not a multiple of the chunk size of {CHUNK_SIZE} bytes")]
+    Unaligned(u32),
+    #[error("Can not resume at offset {0}, the previous chunks are missing")]
+    CannotResume(u32),
+    #[error("Offset {0} is past the end of the sprite of {1} bytes")]
+    OffsetPastEnd(u32, usize),
+    #[error("Connection closed after {0} bytes were stored")]
+    Interrupted(u32),
+}
+
+impl<'r, State> FromRequest<'r, State> for SpriteUpload {
+    type Rejection = BadSpriteUpload;
+
+    async fn from_request<R: Read>(
+        state: &'r State,
+        request_parts: picoserve::request::RequestParts<'r>,
+        request_body: picoserve::request::RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(UploadQuery { key, offset }) =
+            Query::<UploadQuery>::from_request_parts(state, &request_parts)
+                .await
+                .map_err(|_| BadSpriteUpload::BadQuery)?;
+        if !(offset as usize).is_multiple_of(CHUNK_SIZE) {
+            return Err(BadSpriteUpload::Unaligned(offset));
+        }
+        let encoding = Encoding::from_header(&request_parts, "Content-Type");
+        let total_size = offset as usize + request_body.content_length();
+        if encoding == Encoding::Postcard && total_size > MAX_STORED_SIZE {
+            return Err(BadSpriteUpload::TooLarge(total_size));
+        }
+        let mut value = if offset == 0 {
+            Vec::new()
+        } else {
+            display()
+                .sprites
+                .unfinished
+                .get(&key)
+                .and_then(|stored| stored.get(..offset as usize))
+                .ok_or(BadSpriteUpload::CannotResume(offset))?
+                .to_vec()
+        };
+
+        match encoding {
+            Encoding::Postcard => {
+                let mut reader = request_body.reader();
+                let complete = loop {
+                    let mut buf = [0u8; 4096];
+                    match reader.read(&mut buf).await {
+                        Ok(0) => break value.len() == total_size,
+                        Ok(read_size) => value.extend_from_slice(&buf[..read_size]),
+                        Err(_) => break false,
+                    }
+                };
+                if !complete {
+                    // Keep the complete chunks, which the display has in flash by now
+                    value.truncate(value.len() - value.len() % CHUNK_SIZE);
+                    let stored = value.len() as u32;
+                    display().sprites.unfinished.insert(key, value);
+                    return Err(BadSpriteUpload::Interrupted(stored));
+                }
+            }
+            Encoding::Json => {
+                let json = read_body(request_body)
+                    .await
+                    .map_err(|_| BadSpriteUpload::ReadError)?;
+                let resource: Resource =
+                    serde_json::from_slice(&json).map_err(BadSpriteUpload::JsonError)?;
+                let data = postcard::to_allocvec(&resource).expect("Failed to serialize resource");
+                if data.len() > MAX_STORED_SIZE {
+                    return Err(BadSpriteUpload::TooLarge(data.len()));
+                }
+                // The offset of a resumed JSON upload counts the bytes of the converted sprite
+                let Some(remaining) = data.get(offset as usize..) else {
+                    return Err(BadSpriteUpload::OffsetPastEnd(offset, data.len()));
+                };
+                value.extend_from_slice(remaining);
+            }
+        }
+        Ok(SpriteUpload { key, value })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct PanelStateQuery {
+    on: bool,
+}
+
+async fn on_off_handler(on: Query<PanelStateQuery>) -> (response::StatusCode, &'static str) {
+    info!("Panel turned {}", if on.0.on { "on" } else { "off" });
+    (response::StatusCode::OK, "State updated")
+}
+
+#[derive(serde::Deserialize)]
+struct SettingsQuery {
+    brightness: u8,
+}
+
+async fn settings_handler(settings: Query<SettingsQuery>) -> (response::StatusCode, &'static str) {
+    info!("Brightness set to {}", settings.0.brightness);
+    (response::StatusCode::OK, "Settings updated")
+}
+
+async fn schedule_handler() -> Json<Schedule> {
+    Json(
+        SCHEDULE
+            .lock()
+            .expect("The schedule lock is poisoned")
+            .clone(),
+    )
+}
+
+async fn schedule_update_handler(schedule: Payload<Schedule>) -> (response::StatusCode, String) {
+    let schedule = schedule.0;
+    if let Err(e) = schedule.validate() {
+        return (response::StatusCode::BAD_REQUEST, String::from(e));
+    }
+    info!("Schedule set with {} entries", schedule.entries.len());
+    *SCHEDULE.lock().expect("The schedule lock is poisoned") = schedule;
+    (response::StatusCode::OK, String::from("Schedule updated"))
+}
+
+/// The simulator does not keep crash reports across restarts, so there are never any
+async fn crashes_handler() -> Json<Vec<CrashReport>> {
+    Json(Vec::new())
+}
+
+async fn screenshot_handler() -> QoiImage {
+    QoiImage(display().canvas.to_qoi())
+}
+
+async fn format_handler() -> (response::StatusCode, String) {
+    let mut display = display();
+    display.set_config(None);
+    display.sprites.stored.clear();
+    display.sprites.invalidate(None);
+    (
+        response::StatusCode::OK,
+        String::from("Sprites deleted and config cleared"),
+    )
+}
+
+#[derive(serde::Deserialize)]
+struct FlashKey {
+    key: String,
+}
+
+async fn upload_offset_handler(key: Query<FlashKey>) -> Json<UploadProgress> {
+    let offset = display()
+        .sprites
+        .unfinished
+        .get(&key.0.key)
+        .map_or(0, |stored| stored.len() as u32);
+    Json(UploadProgress { offset })
+}
+
+async fn upload_handler(upload: SpriteUpload) -> (response::StatusCode, String) {
+    let SpriteUpload { key, value } = upload;
+    let mut display = display();
+    display.sprites.unfinished.remove(&key);
+    // Like the display, the old sprite is replaced even if the new one turns out to be invalid
+    let result = match from_bytes::<Resource>(&value) {
+        Ok(_) => {
+            info!("Stored {key} with {} bytes", value.len());
+            display.sprites.stored.insert(key.clone(), value);
+            (response::StatusCode::OK, String::from("Item stored"))
+        }
+        Err(e) => {
+            display.sprites.stored.remove(&key);
+            (
+                response::StatusCode::BAD_REQUEST,
+                format!("Not a valid sprite: {e}"),
+            )
+        }
+    };
+    display.sprites.invalidate(Some(&key));
+    display.redraw();
+    result
+}
+
+async fn exists_handler(key: Query<FlashKey>) -> (response::StatusCode, String) {
+    if display().sprites.stored.contains_key(&key.0.key) {
+        (response::StatusCode::OK, String::from("Item exists"))
+    } else {
+        (
+            response::StatusCode::OK,
+            String::from("Item does not exist"),
+        )
+    }
+}
+
+async fn delete_handler(key: Query<FlashKey>) -> (response::StatusCode, String) {
+    let key = key.0.key;
+    let mut display = display();
+    if display.sprites.stored.remove(&key).is_some() {
+        display.sprites.invalidate(Some(&key));
+        display.redraw();
+    }
+    (response::StatusCode::OK, String::from("Item was deleted"))
+}
+
+/// Try to interpret a stored value as [`Resource`] and summarize it
+fn resource_info(value: &[u8]) -> Option<ResourceInfo> {
+    let res = from_bytes::<Resource>(value).ok()?;
+    // The QOI header holds the width and height as big endian numbers after the magic
+    let (width, height) = res
+        .frames
+        .first()
+        .filter(|frame| frame.len() >= 12 && frame.starts_with(b"qoif"))
+        .map(|frame| {
+            (
+                u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]),
+                u32::from_be_bytes([frame[8], frame[9], frame[10], frame[11]]),
+            )
+        })
+        .unwrap_or_default();
+    Some(ResourceInfo {
+        frame_count: res.frames.len() as u32,
+        frame_time_ms: res.frame_time_ms,
+        width,
+        height,
+    })
+}
+
+async fn list_handler() -> Json<StorageInfo> {
+    let display = display();
+    let items: Vec<StoredItem> = display
+        .sprites
+        .stored
+        .iter()
+        .map(|(key, data)| StoredItem {
+            key: key.clone(),
+            size: data.len() as u32,
+            resource: resource_info(data),
+        })
+        .collect();
+    let used_bytes = items.iter().map(|item| item.size).sum();
+    Json(StorageInfo {
+        items,
+        used_bytes,
+        // The simulator keeps sprites in memory without a fixed capacity
+        total_bytes: u32::MAX,
+    })
+}
+
+async fn download_handler(
+    key: Query<FlashKey>,
+    accept: Accept,
+) -> Result<Encoded, (response::StatusCode, String)> {
+    let Some(data) = display().sprites.stored.get(&key.0.key).cloned() else {
+        return Err((
+            response::StatusCode::NOT_FOUND,
+            String::from("Item does not exist"),
+        ));
+    };
+    match accept.0 {
+        Encoding::Postcard => Ok(Encoded(Encoding::Postcard, data)),
+        Encoding::Json => from_bytes::<Resource>(&data)
+            .ok()
+            .and_then(|resource| serde_json::to_vec(&resource).ok())
+            .map(|json| Encoded(Encoding::Json, json))
+            .ok_or((
+                response::StatusCode::INTERNAL_SERVER_ERROR,
+                String::from("Stored item is not a valid resource"),
+            )),
+    }
+}
+
+async fn heartbeat_handler() -> (response::StatusCode, &'static str) {
+    (response::StatusCode::OK, "Heartbeat received")
+}
+
+async fn config_handler(
+    config: Payload<Configuration>,
+) -> Result<(response::StatusCode, &'static str), ScreenBuildError> {
+    info!("Validating config update");
+    let config = CheckedScreenConfig::new(config.0)?;
+    display().set_config(Some(config));
+    Ok((response::StatusCode::OK, "Config updated"))
+}